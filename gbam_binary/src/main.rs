@@ -4,25 +4,37 @@ use byteorder::{ReadBytesExt, LittleEndian};
 use gbam_tools::{
     bam::bam_to_gbam::bam_sort_to_gbam,
     bam::gbam_to_bam::gbam_to_bam,
-    query::depth::main_depth,
-    reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord},
+    gbam::cat::cat_gbams,
+    gbam::merge::merge_gbams,
+    gbam::split::{split_gbam, SplitBy},
+    gbam::subsample::subsample_gbam,
+    gbam::sort::{materialize_index_sort, sort_gbam},
+    query::depth::{main_depth, CigarMode, DepthMode},
+    reader::{parallel::par_map_reduce, parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord},
     {bam_to_gbam, Codecs},
+    meta::SortOrder,
     query::flagstat::collect_stats,
+    query::pileup::main_pileup,
+    query::mpileup::main_mpileup,
 };
 
-use std::{path::PathBuf, convert::TryInto, io::{Read, Seek}, io::{BufWriter, Write}};
+#[cfg(feature = "parquet")]
+use gbam_tools::parquet_io::{gbam_to_parquet, parquet_to_gbam};
+use std::{path::PathBuf, io::{Read, Seek}, io::{BufWriter, Write}};
 use std::time::Instant;
 use std::fs::File;
 use structopt::StructOpt;
 use std::env;
 
-use rayon::prelude::*;
 
 #[derive(StructOpt)]
 struct Cli {
-    /// Sort BAM file before converting it to GBAM.
+    /// Sort BAM file before converting it to GBAM. Without -c sorts GBAM file by coordinate into new GBAM file (in order of --index-file, if passed).
     #[structopt(short, long)]
     sort: bool,
+    /// Sort order used with --sort: coordinate (default), name or collate (alignments of the same read adjacent, mates matched).
+    #[structopt(long)]
+    sort_order: Option<SortOrder>,
     /// Specify which kind of temporary medium to use while sorting: ram, lz4_ram, file, lz4_file
     #[structopt(long)]
     sort_temp_mode: Option<String>,
@@ -44,9 +56,53 @@ struct Cli {
     /// Collect statistic from flag field from all records in the file.
     #[structopt(short, long)]
     flagstat: bool,
+    /// Get base composition (A/C/G/T/N/deletion counts) for every position of the region passed in --query (only on sorted files).
+    #[structopt(long)]
+    pileup: bool,
+    /// Print samtools mpileup compatible text output for the region passed in --query (only on sorted files).
+    #[structopt(long)]
+    mpileup: bool,
+    /// Mpileup query. Reference FASTA file, used to print reference bases and matches.
+    #[structopt(long, parse(from_os_str))]
+    reference: Option<PathBuf>,
+    /// Mpileup query. Do not skip anomalous read pairs (paired, but not properly paired).
+    #[structopt(long)]
+    count_orphans: bool,
+    /// Mpileup query. Print deletions on reverse strand as `#` instead of `*`.
+    #[structopt(long)]
+    reverse_del: bool,
     /// The path to the BAM file to read
     #[structopt(parse(from_os_str))]
     in_path: PathBuf,
+    /// Additional input GBAM files (for --merge and --cat).
+    #[structopt(parse(from_os_str))]
+    more_in_paths: Vec<PathBuf>,
+    /// Merge coordinate sorted GBAM files (input path and additional input paths) into one sorted GBAM file.
+    #[structopt(long)]
+    merge: bool,
+    /// Concatenate GBAM files (input path and additional input paths) by copying compressed blocks. Files must have the same reference sequences.
+    #[structopt(long)]
+    cat: bool,
+    /// Split GBAM file by reference, rg (read group) or into files of N records. Output path is used as prefix of output files.
+    #[structopt(long)]
+    split: Option<SplitBy>,
+    /// Keep this fraction of reads (0..1), mates are kept together. Same selection as samtools view -s with seed 0.
+    #[structopt(long)]
+    subsample: Option<f64>,
+    /// Convert GBAM file into Parquet file (one column per field).
+    #[cfg(feature = "parquet")]
+    #[structopt(long)]
+    to_parquet: bool,
+    /// Convert Parquet file written with --to-parquet back into GBAM file.
+    #[cfg(feature = "parquet")]
+    #[structopt(long)]
+    from_parquet: bool,
+    /// Subsample. Seed of the selection.
+    #[structopt(long, default_value = "0")]
+    seed: u32,
+    /// Merge. Attach RG tag named after source file (without extension) to every read.
+    #[structopt(long)]
+    attach_rg: bool,
     /// The path to write output GBAM file
     #[structopt(short, parse(from_os_str))]
     out_path: Option<PathBuf>,
@@ -56,9 +112,21 @@ struct Cli {
     /// Depth query. Example: chr1:54, or chrX:1258
     #[structopt(short, parse(from_os_str))]
     bed_file: Option<PathBuf>,
-    /// Depth query. Filter reads with map quality lower than.
+    /// Depth and mpileup query. Filter reads with map quality lower than.
     #[structopt(long)]
     mapq: Option<u32>,
+    /// Pileup and mpileup query. Skip bases with quality lower than (mpileup default is 13).
+    #[structopt(long)]
+    min_base_qual: Option<u8>,
+    /// Depth query. What to count as coverage: reads (default), forward, reverse (strand-specific) or fragment (full insert of proper pairs).
+    #[structopt(long)]
+    depth_mode: Option<DepthMode>,
+    /// Depth query. Do not count reference skips (N CIGAR operations) as covered, e.g. for RNA-seq. Not available for fragment depth mode.
+    #[structopt(long)]
+    skip_splices: bool,
+    /// Depth query. Do not count deletions (D CIGAR operations) as covered. Implies --skip-splices.
+    #[structopt(long)]
+    skip_deletions: bool,
     /// Depth query. Number of threads to use. WARNING: each thread will attempt to allocate up to 1GB.
     #[structopt(long)]
     thread_num: Option<usize>,
@@ -74,6 +142,12 @@ struct Cli {
     /// When sorting and converting file, only sort the indices of records but not the data itself.
     #[structopt(long)]
     index_sort: bool,
+    /// When converting file, write read name filter to speed up --find-name lookups.
+    #[structopt(long)]
+    name_filter: bool,
+    /// Output all alignments with the read name in binary format, as in --view.
+    #[structopt(long)]
+    find_name: Option<String>,
     /// Index file for use in Depth.
     #[structopt(long, parse(from_os_str))]
     index_file: Option<PathBuf>,
@@ -88,6 +162,11 @@ fn main() {
     let args = Cli::from_args();
     let arguments_strings: Vec<String> = env::args().collect();
     let full_command = arguments_strings.join(" ");
+    #[cfg(feature = "parquet")]
+    if args.to_parquet || args.from_parquet {
+        parquet(args, full_command);
+        return;
+    }
     if args.convert_to_gbam {
         convert(args, full_command);
     } else if args.test {
@@ -98,12 +177,28 @@ fn main() {
         depth(args);
     } else if args.convert_to_bam {
         convert_to_bam(args);
+    } else if args.merge {
+        merge(args, full_command);
+    } else if args.cat {
+        cat(args, full_command);
+    } else if args.split.is_some() {
+        split(args, full_command);
+    } else if args.subsample.is_some() {
+        subsample(args, full_command);
+    } else if args.sort {
+        sort(args, full_command);
     } else if args.flagstat {
         flagstat(args);
+    } else if args.pileup {
+        pileup(args);
+    } else if args.mpileup {
+        mpileup(args);
     } else if args.header {
         view_header(args);
     } else if args.view {
         view_file(args);
+    } else if args.find_name.is_some() {
+        find_name(args);
     } else if args.calc_uncompressed_size {
         test_file_uncompressed_size_fetch(args);
     }
@@ -123,9 +218,110 @@ fn convert(args: Cli, full_command: String) {
         .to_str()
         .unwrap();
     if args.sort {
-        bam_sort_to_gbam(in_path, out_path, Codecs::Lz4, args.sort_temp_mode, args.temp_dir, full_command, args.index_sort);
+        bam_sort_to_gbam(in_path, out_path, Codecs::Lz4, args.sort_temp_mode, args.temp_dir, full_command, args.index_sort, args.name_filter, args.sort_order.unwrap_or(SortOrder::Coordinate));
     } else {
-        bam_to_gbam(in_path, out_path, Codecs::Lz4, full_command);
+        bam_to_gbam(in_path, out_path, Codecs::Lz4, full_command, args.name_filter);
+    }
+}
+
+fn sort(args: Cli, full_command: String) {
+    let in_path = args
+        .in_path
+        .as_path()
+        .to_str()
+        .expect("Couldn't parse input path.");
+    let out_path = args
+        .out_path
+        .as_ref()
+        .expect("Output path is mandatory for this operation.")
+        .as_path()
+        .to_str()
+        .unwrap();
+    match args.index_file.and_then(read_index) {
+        Some(index) => materialize_index_sort(in_path, &index, out_path, full_command),
+        None => sort_gbam(in_path, out_path, full_command),
+    }
+}
+
+fn input_paths(args: &Cli) -> Vec<&str> {
+    std::iter::once(&args.in_path)
+        .chain(args.more_in_paths.iter())
+        .map(|path| path.as_path().to_str().expect("Couldn't parse input path."))
+        .collect()
+}
+
+fn merge(args: Cli, full_command: String) {
+    let out_path = args
+        .out_path
+        .as_ref()
+        .expect("Output path is mandatory for this operation.")
+        .as_path()
+        .to_str()
+        .unwrap();
+    merge_gbams(&input_paths(&args), out_path, args.attach_rg, full_command);
+}
+
+fn cat(args: Cli, full_command: String) {
+    let out_path = args
+        .out_path
+        .as_ref()
+        .expect("Output path is mandatory for this operation.")
+        .as_path()
+        .to_str()
+        .unwrap();
+    cat_gbams(&input_paths(&args), out_path, full_command);
+}
+
+fn split(args: Cli, full_command: String) {
+    let in_path = args
+        .in_path
+        .as_path()
+        .to_str()
+        .expect("Couldn't parse input path.");
+    let out_prefix = args
+        .out_path
+        .as_ref()
+        .expect("Output path is mandatory for this operation.")
+        .as_path()
+        .to_str()
+        .unwrap();
+    split_gbam(in_path, out_prefix, args.split.unwrap(), full_command);
+}
+
+fn subsample(args: Cli, full_command: String) {
+    let in_path = args
+        .in_path
+        .as_path()
+        .to_str()
+        .expect("Couldn't parse input path.");
+    let out_path = args
+        .out_path
+        .as_ref()
+        .expect("Output path is mandatory for this operation.")
+        .as_path()
+        .to_str()
+        .unwrap();
+    subsample_gbam(in_path, out_path, args.subsample.unwrap(), args.seed, full_command);
+}
+
+#[cfg(feature = "parquet")]
+fn parquet(args: Cli, full_command: String) {
+    let in_path = args
+        .in_path
+        .as_path()
+        .to_str()
+        .expect("Couldn't parse input path.");
+    let out_path = args
+        .out_path
+        .as_ref()
+        .expect("Output path is mandatory for this operation.")
+        .as_path()
+        .to_str()
+        .unwrap();
+    if args.to_parquet {
+        gbam_to_parquet(in_path, out_path);
+    } else {
+        parquet_to_gbam(in_path, out_path, full_command);
     }
 }
 
@@ -181,25 +377,17 @@ fn test(args: Cli) {
 
 fn test_parallel_cigar_fetch(args: Cli) {
     let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
-    let temp_reader = Reader::new(file.try_clone().unwrap(), ParsingTemplate::new()).unwrap();
-    let file_meta = temp_reader.file_meta;
-    let total_records = temp_reader.amount;
     let now = Instant::now();
-    
-    (0..total_records).into_par_iter().chunks(500_000).for_each(|records_range| {
-        let mut rec =  GbamRecord::default();
-        let mut tmplt = ParsingTemplate::new();
-        tmplt.set(&Fields::RawCigar, true);
-    
-        let mut reader = Reader::new_with_meta(file.try_clone().unwrap(), tmplt, &file_meta, None).unwrap();
 
+    par_map_reduce(&file, ParsingTemplate::new_with(&[Fields::RawCigar]), |reader, records_range| {
+        let mut rec =  GbamRecord::default();
         let mut collector = Vec::with_capacity(records_range.len());
 
         for rec_num in records_range {
             reader.fill_record(rec_num, &mut rec);
             collector.push(rec.cigar.as_ref().unwrap().base_coverage());
         }
-    });
+    }, |_, _| ());
 
     println!(
         "Fetching CIGAR in parallel took: {}",
@@ -245,22 +433,38 @@ fn test_file_uncompressed_size_fetch(args: Cli) {
 }
 
 fn read_index(index: PathBuf) -> Option<std::sync::Arc<Vec<u32>>> {
-    let file = File::open(index).unwrap();
-    let size = file.metadata().unwrap().len();
-    let mut f = std::io::BufReader::new(file);
-    let mut res = vec![0 as u32; (size/(std::mem::size_of::<u32>() as u64)).try_into().unwrap()];
+    Some(gbam_tools::reader::reader::read_index(File::open(index).unwrap()))
+}
 
-    for slot in &mut res {
-        *slot = f.read_u32::<LittleEndian>().unwrap();
-    }
+fn depth(args: Cli) {
+    assert!(
+        args.depth_mode != Some(DepthMode::Fragment) || !(args.skip_splices || args.skip_deletions),
+        "--skip-splices and --skip-deletions can't be used with --depth-mode fragment, fragments cover the whole insert."
+    );
+    let cigar_mode = if args.skip_deletions {
+        CigarMode::SkipSplicesAndDeletions
+    } else if args.skip_splices {
+        CigarMode::SkipSplices
+    } else {
+        CigarMode::Span
+    };
+    let in_path = args.in_path.as_path().to_str().unwrap();
+    let gbam_file = File::open(in_path).unwrap();
+    main_depth(gbam_file, args.bed_file.as_ref(), args.index_file.and_then(read_index), args.query, args.mapq, args.out_path, args.thread_num, args.depth_mode.unwrap_or(DepthMode::Reads), cigar_mode);
+}
 
-    Some(std::sync::Arc::new(res))
+fn pileup(args: Cli) {
+    let in_path = args.in_path.as_path().to_str().unwrap();
+    let gbam_file = File::open(in_path).unwrap();
+    let region = args.query.expect("Region query is mandatory for this operation. Example: chr1:1000-1010");
+    main_pileup(gbam_file, args.index_file.and_then(read_index), &region, args.min_base_qual);
 }
 
-fn depth(args: Cli) {
+fn mpileup(args: Cli) {
     let in_path = args.in_path.as_path().to_str().unwrap();
     let gbam_file = File::open(in_path).unwrap();
-    main_depth(gbam_file, args.bed_file.as_ref(), args.index_file.and_then(read_index), args.query, args.mapq, args.out_path, args.thread_num);
+    let region = args.query.expect("Region query is mandatory for this operation. Example: chr1:1000-1010");
+    main_mpileup(gbam_file, args.index_file.and_then(read_index), &region, args.reference.as_ref(), args.min_base_qual, args.mapq, args.count_orphans, args.reverse_del);
 }
 
 fn view_header(args: Cli){
@@ -298,4 +502,29 @@ fn view_file(args: Cli){
             break;
         }
     }
-}
\ No newline at end of file
+}
+
+fn find_name(args: Cli){
+    let file = File::open(args.in_path.as_path().to_str().unwrap()).unwrap();
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+
+    let mut reader = Reader::new(file, template).unwrap();
+    let records = reader.find_by_name(args.find_name.as_ref().unwrap().as_bytes());
+
+    let st = std::io::stdout();
+    let lock = st.lock();
+    let mut stdout = BufWriter::with_capacity(64 * 1024, lock);
+
+    const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+    stdout.write_all(BAM_MAGIC).unwrap();
+    stdout.write_all(reader.file_meta.get_sam_header()).unwrap();
+
+    let mut buf = Vec::new();
+    for rec in records {
+        rec.convert_to_bytes(&mut buf);
+        if stdout.write_all(&buf).is_err() {
+            break;
+        }
+    }
+}
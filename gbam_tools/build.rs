@@ -0,0 +1,27 @@
+fn main() {
+    #[cfg(feature = "c-api")]
+    generate_c_header();
+}
+
+/// Writes C header of `capi` module into `OUT_DIR`. The header in
+/// include/gbam.h is committed, it is replaced only if `GBAM_UPDATE_HEADER`
+/// environment variable is set, so that builds don't modify source tree.
+#[cfg(feature = "c-api")]
+fn generate_c_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/capi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=GBAM_UPDATE_HEADER");
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap();
+    let header_path = format!("{}/gbam.h", out_dir);
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(format!("{}/src/capi.rs", crate_dir))
+        .generate()
+        .expect("Unable to generate C header.")
+        .write_to_file(&header_path);
+    if std::env::var_os("GBAM_UPDATE_HEADER").is_some() {
+        std::fs::copy(&header_path, format!("{}/include/gbam.h", crate_dir)).expect("Unable to copy C header into include directory.");
+    }
+}
@@ -0,0 +1,47 @@
+/*
+ * Prints read name, flag, reference, 1-based position and CIGAR of records of
+ * GBAM file (of region, if passed), tab separated.
+ *
+ * cargo build --release -p gbam_tools --features c-api
+ * cc gbam_view.c -I../../include -L../../../target/release -lgbam_tools -o gbam_view
+ * ./gbam_view file.gbam [region] [index file]
+ */
+#include <stdio.h>
+#include "gbam.h"
+
+static const char CIGAR_OPS[] = "MIDNSHP=X";
+
+int main(int argc, char **argv) {
+    if (argc < 2) {
+        fprintf(stderr, "Usage: %s file.gbam [region] [index file]\n", argv[0]);
+        return 1;
+    }
+    GbamReader *reader = gbam_reader_open(argv[1], argc > 3 ? argv[3] : NULL);
+    if (reader == NULL) {
+        fprintf(stderr, "%s\n", gbam_last_error());
+        return 1;
+    }
+    int fields[] = {GBAM_FIELD_READ_NAME, GBAM_FIELD_FLAG, GBAM_FIELD_REF_ID, GBAM_FIELD_POS, GBAM_FIELD_CIGAR};
+    if (gbam_reader_set_fields(reader, fields, sizeof(fields) / sizeof(fields[0])) < 0 ||
+        (argc > 2 && gbam_reader_query(reader, argv[2]) < 0)) {
+        fprintf(stderr, "%s\n", gbam_last_error());
+        gbam_reader_close(reader);
+        return 1;
+    }
+
+    GbamRecordView rec;
+    int res;
+    while ((res = gbam_reader_next(reader, &rec)) > 0) {
+        const char *ref_name = rec.refid < 0 ? "*" : gbam_reader_ref_name(reader, rec.refid);
+        printf("%s\t%u\t%s\t%d\t", rec.read_name, rec.flag, ref_name, rec.pos + 1);
+        for (size_t i = 0; i < rec.n_cigar_op; i++) {
+            printf("%u%c", rec.cigar[i] >> 4, CIGAR_OPS[rec.cigar[i] & 0xf]);
+        }
+        printf("%s\n", rec.n_cigar_op == 0 ? "*" : "");
+    }
+    if (res < 0) {
+        fprintf(stderr, "%s\n", gbam_last_error());
+    }
+    gbam_reader_close(reader);
+    return res < 0;
+}
@@ -0,0 +1 @@
+from .gbam_tools import *  # noqa: F401,F403
@@ -0,0 +1,197 @@
+#ifndef GBAM_H
+#define GBAM_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Fields of GBAM record, named as in SAM specification. Functions take them
+ * as `int`, so that values from C are validated.
+ */
+typedef enum GbamField {
+  GBAM_FIELD_REF_ID,
+  GBAM_FIELD_POS,
+  GBAM_FIELD_MAPQ,
+  GBAM_FIELD_BIN,
+  GBAM_FIELD_FLAG,
+  GBAM_FIELD_NEXT_REF_ID,
+  GBAM_FIELD_NEXT_POS,
+  GBAM_FIELD_TLEN,
+  GBAM_FIELD_READ_NAME,
+  GBAM_FIELD_CIGAR,
+  GBAM_FIELD_SEQ,
+  GBAM_FIELD_QUAL,
+  GBAM_FIELD_TAGS,
+} GbamField;
+
+/**
+ * Opaque GBAM reader.
+ */
+typedef struct GbamReader GbamReader;
+
+/**
+ * Record filled according to reader fields. Fields which are not fetched
+ * are zero, their pointers are NULL.
+ */
+typedef struct GbamRecordView {
+  int32_t refid;
+  int32_t pos;
+  uint8_t mapq;
+  uint16_t bin;
+  uint16_t flag;
+  int32_t next_ref_id;
+  int32_t next_pos;
+  int32_t tlen;
+  /**
+   * NUL terminated read name.
+   */
+  const char *read_name;
+  /**
+   * BAM encoded CIGAR operations (`len << 4 | op`).
+   */
+  const uint32_t *cigar;
+  size_t n_cigar_op;
+  /**
+   * Sequence as letters, not NUL terminated.
+   */
+  const char *seq;
+  size_t seq_len;
+  /**
+   * Phred-scaled base qualities.
+   */
+  const uint8_t *qual;
+  size_t qual_len;
+  /**
+   * BAM encoded auxiliary data.
+   */
+  const uint8_t *tags;
+  size_t tags_len;
+} GbamRecordView;
+
+/**
+ * Returns message of the last error happened in this thread, or NULL. The
+ * string is valid until the next failing call.
+ */
+const char *gbam_last_error(void);
+
+/**
+ * Opens GBAM file with all fields fetched. `index_path` is index file of
+ * index sorted file or NULL; with it records are numbered in sorted order.
+ * Returns NULL on error.
+ *
+ * # Safety
+ * `path` and `index_path` (if not NULL) must be NUL terminated strings.
+ */
+struct GbamReader *gbam_reader_open(const char *path, const char *index_path);
+
+/**
+ * Closes reader.
+ *
+ * # Safety
+ * `reader` must be returned by `gbam_reader_open` (or NULL) and not used after.
+ */
+void gbam_reader_close(struct GbamReader *reader);
+
+/**
+ * Sets fields (`GbamField` values) fetched for records. Returns 0 on success.
+ *
+ * # Safety
+ * `reader` must be open reader, `fields` must point to `n` values.
+ */
+int gbam_reader_set_fields(struct GbamReader *reader, const int *fields, size_t n);
+
+/**
+ * Amount of records, negative value on error.
+ *
+ * # Safety
+ * `reader` must be open reader.
+ */
+int64_t gbam_reader_len(const struct GbamReader *reader);
+
+/**
+ * Amount of reference sequences, negative value on error.
+ *
+ * # Safety
+ * `reader` must be open reader.
+ */
+int64_t gbam_reader_n_refs(const struct GbamReader *reader);
+
+/**
+ * Name of reference sequence, NULL on error (e.g. `ref_id` is out of range).
+ *
+ * # Safety
+ * `reader` must be open reader.
+ */
+const char *gbam_reader_ref_name(const struct GbamReader *reader, size_t ref_id);
+
+/**
+ * Length of reference sequence, negative value on error (e.g. `ref_id` is
+ * out of range).
+ *
+ * # Safety
+ * `reader` must be open reader.
+ */
+int64_t gbam_reader_ref_len(const struct GbamReader *reader, size_t ref_id);
+
+/**
+ * Reads record `rec_num` into `out`. Returns 0 on success.
+ *
+ * # Safety
+ * `reader` must be open reader, `out` must be valid pointer.
+ */
+int gbam_reader_read(struct GbamReader *reader, size_t rec_num, struct GbamRecordView *out);
+
+/**
+ * Makes `gbam_reader_next` iterate over all records from the first one.
+ * Returns 0 on success.
+ *
+ * # Safety
+ * `reader` must be open reader.
+ */
+int gbam_reader_rewind(struct GbamReader *reader);
+
+/**
+ * Reads next record of iteration (all records after open or rewind, region
+ * records after query) into `out`. Returns 1 if record was read, 0 at the
+ * end, negative value on error.
+ *
+ * # Safety
+ * `reader` must be open reader, `out` must be valid pointer.
+ */
+int gbam_reader_next(struct GbamReader *reader, struct GbamRecordView *out);
+
+/**
+ * Makes `gbam_reader_next` iterate over records which overlap region
+ * (chr1:1000-2000, 1-based, inclusive, or chr1). The file has to be sorted by
+ * coordinate (pass index file for index sorted files). Returns amount of
+ * records in region, negative value on error.
+ *
+ * # Safety
+ * `reader` must be open reader, `region` must be NUL terminated string.
+ */
+int64_t gbam_reader_query(struct GbamReader *reader, const char *region);
+
+/**
+ * Size in bytes of value of fixed sized field (1 for mapq, 2 for bin and
+ * flag, 4 for the rest), 0 for variable sized fields, negative value for
+ * unknown field.
+ */
+int gbam_field_size(int field);
+
+/**
+ * Copies values of fixed sized field of all records into `out` (in order of
+ * index file, if passed), which must hold `gbam_reader_len` values of
+ * `gbam_field_size` bytes. Only blocks of this field are decompressed.
+ * Returns 0 on success.
+ *
+ * # Safety
+ * `reader` must be open reader, `out` must point to `len` values.
+ */
+int gbam_reader_fetch_column(struct GbamReader *reader, int field, void *out, size_t len);
+
+#endif  /* GBAM_H */
@@ -0,0 +1,163 @@
+//! Conversion of GBAM columns into Apache Arrow record batches.
+use crate::name_filter::strip_nul;
+use crate::reader::batch::{Batch, BatchColumn};
+use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader};
+pub use crate::reader::parse_tmplt::field_name as column_name;
+use arrow::array::{ArrayRef, BinaryArray, Int32Array, ListArray, StringArray, StringBuilder, UInt16Array, UInt32Array, UInt8Array};
+use arrow::buffer::{Buffer, OffsetBuffer, ScalarBuffer};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::record_batch::RecordBatch;
+use bam_tools::record::bamrawrecord::decode_seq;
+use bam_tools::record::fields::Fields;
+use std::sync::Arc;
+
+fn cigar_item() -> Arc<Field> {
+    Arc::new(Field::new("item", DataType::UInt32, false))
+}
+
+/// Arrow type of data field. CIGAR is a list of BAM encoded operations
+/// (`len << 4 | op`), tags are BAM encoded auxiliary data.
+pub fn column_type(field: &Fields) -> DataType {
+    match field {
+        Fields::Mapq => DataType::UInt8,
+        Fields::Bin | Fields::Flags => DataType::UInt16,
+        Fields::ReadName | Fields::RawSequence => DataType::Utf8,
+        Fields::RawCigar => DataType::List(cigar_item()),
+        Fields::RawQual | Fields::RawTags => DataType::Binary,
+        _ => DataType::Int32,
+    }
+}
+
+/// Schema of record batches for data fields active in parsing template.
+pub fn arrow_schema(template: &ParsingTemplate) -> SchemaRef {
+    Arc::new(Schema::new(
+        template
+            .get_active_data_fields_iter()
+            .map(|field| Field::new(column_name(field).unwrap(), column_type(field), false))
+            .collect::<Vec<Field>>(),
+    ))
+}
+
+fn offset_buffer(offsets: Vec<u32>) -> OffsetBuffer<i32> {
+    OffsetBuffer::new(ScalarBuffer::from(offsets.into_iter().map(|offset| offset as i32).collect::<Vec<i32>>()))
+}
+
+fn to_array(field: &Fields, column: BatchColumn) -> ArrayRef {
+    match column {
+        BatchColumn::U8(items) => Arc::new(UInt8Array::new(ScalarBuffer::from(items), None)),
+        BatchColumn::U16(items) => Arc::new(UInt16Array::new(ScalarBuffer::from(items), None)),
+        BatchColumn::I32(items) => Arc::new(Int32Array::new(ScalarBuffer::from(items), None)),
+        BatchColumn::Variable(items) => match field {
+            Fields::ReadName => Arc::new(StringArray::from_iter_values(
+                (0..items.len()).map(|i| std::str::from_utf8(strip_nul(items.get(i))).expect("Read name is not valid UTF-8.")),
+            )),
+            Fields::RawSequence => {
+                let mut builder = StringBuilder::with_capacity(items.len(), items.data().len() * 2);
+                let mut seq = String::new();
+                for i in 0..items.len() {
+                    decode_seq(items.get(i), &mut seq);
+                    builder.append_value(&seq);
+                }
+                Arc::new(builder.finish())
+            }
+            Fields::RawCigar => {
+                let (offsets, data) = items.into_parts();
+                let ops: Vec<u32> = data.chunks_exact(4).map(|op| u32::from_le_bytes([op[0], op[1], op[2], op[3]])).collect();
+                let offsets = offsets.into_iter().map(|offset| offset / 4).collect();
+                Arc::new(ListArray::new(cigar_item(), offset_buffer(offsets), Arc::new(UInt32Array::new(ScalarBuffer::from(ops), None)), None))
+            }
+            _ => {
+                let (offsets, data) = items.into_parts();
+                Arc::new(BinaryArray::new(offset_buffer(offsets), Buffer::from_vec(data), None))
+            }
+        },
+    }
+}
+
+/// Converts batch of decoded columns into Arrow record batch. Fixed sized
+/// columns, qualities and tags are moved without copying.
+pub fn to_record_batch(batch: Batch) -> RecordBatch {
+    let (fields, arrays): (Vec<Field>, Vec<ArrayRef>) = batch
+        .into_columns()
+        .into_iter()
+        .map(|(field, column)| (Field::new(column_name(&field).unwrap(), column_type(&field), false), to_array(&field, column)))
+        .unzip();
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays).unwrap()
+}
+
+/// Iterator over Arrow record batches of about `batch_size` records, with
+/// columns of fields active in reader parsing template (see `arrow_schema`).
+/// Records are in physical order.
+pub fn record_batches(reader: &Reader, batch_size: usize) -> impl Iterator<Item = RecordBatch> + '_ {
+    reader.batches(batch_size).map(to_record_batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::{Cigar, Op};
+    use crate::reader::reader::{reader_from_records, test_record};
+    use crate::reader::record::GbamRecord;
+    use crate::SIZE_LIMIT;
+    use arrow::array::AsArray;
+    use arrow::datatypes::{Int32Type, UInt16Type, UInt32Type};
+
+    #[test]
+    fn test_arrow_schema() {
+        let schema = arrow_schema(&ParsingTemplate::new_with(&[Fields::Pos, Fields::Flags, Fields::RawCigar]));
+        let columns: Vec<(&str, &DataType)> = schema.fields().iter().map(|f| (f.name().as_str(), f.data_type())).collect();
+        assert_eq!(
+            columns,
+            vec![("pos", &DataType::Int32), ("flag", &DataType::UInt16), ("cigar", &DataType::List(cigar_item()))]
+        );
+    }
+
+    #[test]
+    fn test_record_batches() {
+        // Tags take more than a third of block, so they span three blocks.
+        let records: Vec<GbamRecord> = (0..5)
+            .map(|i| {
+                let len = 2 * (i % 3 + 1);
+                GbamRecord {
+                    flag: Some(i as u16),
+                    read_name: Some(format!("read{}\0", i).into_bytes()),
+                    cigar: Some(Cigar::new(vec![Op::new((len as u32) << 4), Op::new((i as u32 + 1) << 4 | 4)])),
+                    seq: Some(String::from(&"ACGTAC"[..len])),
+                    qual: Some(vec![30 + i as u8; len]),
+                    tags: Some(vec![i as u8; SIZE_LIMIT / 3 + i]),
+                    ..test_record(0, 10 * i as i32)
+                }
+            })
+            .collect();
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let reader = reader_from_records(vec![(String::from("chr1"), 100)], &records, template);
+        assert_eq!(reader.file_meta.view_blocks(&Fields::RawTags).len(), 3);
+
+        let mut rec_num = 0;
+        for batch in record_batches(&reader, 3) {
+            let column = |field: &Fields| batch.column_by_name(column_name(field).unwrap()).unwrap().clone();
+            let pos = column(&Fields::Pos);
+            let flag = column(&Fields::Flags);
+            let read_name = column(&Fields::ReadName);
+            let cigar = column(&Fields::RawCigar);
+            let seq = column(&Fields::RawSequence);
+            let qual = column(&Fields::RawQual);
+            let tags = column(&Fields::RawTags);
+            for row in 0..batch.num_rows() {
+                let expected = &records[rec_num + row];
+                assert_eq!(pos.as_primitive::<Int32Type>().value(row), expected.pos.unwrap());
+                assert_eq!(flag.as_primitive::<UInt16Type>().value(row), expected.flag.unwrap());
+                assert_eq!(read_name.as_string::<i32>().value(row).as_bytes(), strip_nul(expected.read_name.as_ref().unwrap()));
+                let ops = cigar.as_list::<i32>().value(row);
+                let expected_ops: Vec<u32> = expected.cigar.as_ref().unwrap().ops().map(|op| op.0).collect();
+                assert_eq!(ops.as_primitive::<UInt32Type>().values().to_vec(), expected_ops);
+                assert_eq!(seq.as_string::<i32>().value(row), expected.seq.as_ref().unwrap());
+                assert_eq!(qual.as_binary::<i32>().value(row), &expected.qual.as_ref().unwrap()[..]);
+                assert_eq!(tags.as_binary::<i32>().value(row), &expected.tags.as_ref().unwrap()[..]);
+            }
+            rec_num += batch.num_rows();
+        }
+        assert_eq!(rec_num, records.len());
+    }
+}
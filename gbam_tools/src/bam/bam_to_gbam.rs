@@ -1,4 +1,5 @@
-use crate::MEGA_BYTE_SIZE;
+use crate::meta::SortOrder;
+use crate::{MEGA_BYTE_SIZE, U32_SIZE};
 use crate::{Codecs, Writer};
 use bam_tools::parse_reference_sequences;
 use bam_tools::record::bamrawrecord::BAMRawRecord;
@@ -6,6 +7,7 @@ use bam_tools::record::fields::{Fields, FIELDS_NUM};
 use bam_tools::sorting::sort;
 use bam_tools::sorting::sort::TempFilesMode;
 use bam_tools::Reader;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::borrow::Cow;
 use std::fs::File;
 use std::io::{BufReader, BufWriter};
@@ -16,9 +18,23 @@ use tempdir::TempDir;
 
 const MEM_LIMIT: usize = 2000 * MEGA_BYTE_SIZE;
 
-/// Converts BAM file to GBAM file. This uses the `bam_parallel` reader.
-pub fn bam_to_gbam(in_path: &str, out_path: &str, codec: Codecs, full_command: String) {
+/// Fields for which block stats are collected. RawQual stats hold read lengths.
+pub(crate) const STATS_FIELDS: [Fields; 6] = [
+    Fields::RefID,
+    Fields::Mapq,
+    Fields::Flags,
+    Fields::NextRefID,
+    Fields::TemplateLength,
+    Fields::RawQual,
+];
+
+/// Converts BAM file to GBAM file. This uses the `bam_parallel` reader. If
+/// `name_filter` is set, read name filter is written for lookups by read name.
+pub fn bam_to_gbam(in_path: &str, out_path: &str, codec: Codecs, full_command: String, name_filter: bool) {
     let (mut bam_reader, mut writer) = get_bam_reader_gbam_writer(in_path, out_path, codec, full_command);
+    if name_filter {
+        writer.enable_name_filter();
+    }
 
     let mut records = bam_reader.records();
     while let Some(Ok(rec)) = records.next_rec() {
@@ -29,13 +45,23 @@ pub fn bam_to_gbam(in_path: &str, out_path: &str, codec: Codecs, full_command: S
     writer.finish().unwrap();
 }
 
-/// Converts BAM file to GBAM file. Sorts BAM file in process. This uses the `bam_parallel` reader.
-pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_temp_mode: Option<String>, temp_dir: Option<PathBuf>, full_command: String, index_sort: bool) {
+/// Converts BAM file to GBAM file. Sorts BAM file in process in `sort_order`
+/// (coordinate, query name or collate). This uses the `bam_parallel` reader.
+#[allow(clippy::too_many_arguments)]
+pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_temp_mode: Option<String>, temp_dir: Option<PathBuf>, full_command: String, index_sort: bool, name_filter: bool, sort_order: SortOrder) {
+    let sort_by = match sort_order {
+        SortOrder::Coordinate => sort::SortBy::CoordinatesAndStrand,
+        SortOrder::QueryName => sort::SortBy::Name,
+        SortOrder::Collate => sort::SortBy::NameAndMatchMates,
+        SortOrder::Unsorted => panic!("Sort order is required for sorting."),
+    };
+
     let fin_for_ref_seqs = File::open(in_path).expect("failed");
     
     let mut reader_for_header_only = Reader::new(fin_for_ref_seqs, 1, None);
     let (sam_header, ref_seqs, _) =
         read_sam_header_and_ref_seqs(&mut reader_for_header_only);
+    let sam_header = set_header_sort_order(&sam_header, sort_order);
 
 
     let fin = File::open(in_path).expect("failed");
@@ -46,16 +72,29 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
     let buf_reader = BufReader::new(fin);
     let buf_writer = BufWriter::new(fout);
 
+    // Records are physically sorted unless the order is kept in index file.
+    // Pos stats of sorted files hold (RefID, Pos) bounds of blocks.
+    let mut stats_fields = STATS_FIELDS.to_vec();
+    if sort_order == SortOrder::Coordinate && !index_sort {
+        stats_fields.push(Fields::Pos);
+    }
+
     let mut writer = Writer::new(
         buf_writer,
         vec![codec; FIELDS_NUM],
         8,
-        vec![Fields::RefID],
+        stats_fields,
         ref_seqs,
         sam_header,
         full_command,
-        true
+        sort_order
     );
+    if name_filter {
+        writer.enable_name_filter();
+    }
+    if index_sort {
+        writer.set_index_sorted();
+    }
 
     let tmp_dir_path = temp_dir.map_or(std::env::temp_dir(), |path| path);
     if sort_temp_mode.is_none() {
@@ -85,7 +124,7 @@ pub fn bam_sort_to_gbam(in_path: &str, out_path: &str, codec: Codecs, mut sort_t
         8,
         tmp_medium_mode,
         index_file,
-        sort::SortBy::CoordinatesAndStrand,
+        sort_by,
         Some(file_size)
     )
     .unwrap();
@@ -110,6 +149,58 @@ fn read_sam_header_and_ref_seqs(reader: &mut Reader) -> (Vec<u8>, Vec<(String, u
     (bytes_of_header, sequences, ref_sequences_offset)
 }
 
+/// Sets SO (and GO) tags of @HD line of SAM header, adding the line if it is
+/// missing. Header bytes are laid out as in BAM, without magic: l_text, text,
+/// reference sequences.
+pub(crate) fn set_header_sort_order(sam_header: &[u8], sort_order: SortOrder) -> Vec<u8> {
+    let l_text = (&sam_header[..U32_SIZE]).read_u32::<LittleEndian>().unwrap() as usize;
+    let text = sam_header_text(sam_header);
+
+    let (hd_line, rest) = match text.strip_prefix("@HD") {
+        Some(_) => text.split_once('\n').unwrap_or((&text, "")),
+        None => ("@HD\tVN:1.6", &text[..]),
+    };
+    let mut new_text: String = hd_line
+        .split('\t')
+        .filter(|tag| !tag.starts_with("SO:") && !tag.starts_with("GO:") && !tag.starts_with("SS:"))
+        .collect::<Vec<&str>>()
+        .join("\t");
+    new_text.push('\t');
+    new_text.push_str(sort_order.header_tags());
+    new_text.push('\n');
+    new_text.push_str(rest);
+
+    let mut res = Vec::with_capacity(sam_header.len() + new_text.len());
+    res.write_u32::<LittleEndian>(new_text.len() as u32).unwrap();
+    res.extend_from_slice(new_text.as_bytes());
+    res.extend_from_slice(&sam_header[U32_SIZE + l_text..]);
+    res
+}
+
+/// Returns text of SAM header. Header bytes are laid out as in BAM, without magic.
+pub(crate) fn sam_header_text(sam_header: &[u8]) -> String {
+    let l_text = (&sam_header[..U32_SIZE]).read_u32::<LittleEndian>().unwrap() as usize;
+    let text = &sam_header[U32_SIZE..U32_SIZE + l_text];
+    // Text may be padded with NULs.
+    String::from_utf8_lossy(text).trim_end_matches('\0').to_owned()
+}
+
+/// Builds SAM header bytes as in BAM (without magic) out of text and reference
+/// sequences.
+pub(crate) fn build_sam_header(text: &str, ref_seqs: &[(String, u32)]) -> Vec<u8> {
+    let mut res = Vec::new();
+    res.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+    res.extend_from_slice(text.as_bytes());
+    res.write_u32::<LittleEndian>(ref_seqs.len() as u32).unwrap();
+    for (name, len) in ref_seqs {
+        res.write_u32::<LittleEndian>(name.len() as u32 + 1).unwrap();
+        res.extend_from_slice(name.as_bytes());
+        res.push(0);
+        res.write_u32::<LittleEndian>(*len).unwrap();
+    }
+    res
+}
+
 fn get_bam_reader_gbam_writer(
     in_path: &str,
     out_path: &str,
@@ -132,12 +223,37 @@ fn get_bam_reader_gbam_writer(
         buf_writer,
         vec![codec; FIELDS_NUM],
         8,
-        vec![Fields::RefID],
+        STATS_FIELDS.to_vec(),
         ref_seqs,
         sam_header,
         full_command,
-        false,
+        SortOrder::Unsorted,
     );
 
     (bgzf_reader, writer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(text: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(text.len() as u32).unwrap();
+        bytes.extend_from_slice(text.as_bytes());
+        // n_ref
+        bytes.write_u32::<LittleEndian>(0).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_set_header_sort_order() {
+        let header = header_bytes("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:10\n\0");
+        let res = set_header_sort_order(&header, SortOrder::QueryName);
+        assert_eq!(res, header_bytes("@HD\tVN:1.6\tSO:queryname\n@SQ\tSN:chr1\tLN:10\n"));
+
+        let header = header_bytes("@SQ\tSN:chr1\tLN:10\n");
+        let res = set_header_sort_order(&header, SortOrder::Collate);
+        assert_eq!(res, header_bytes("@HD\tVN:1.6\tSO:unsorted\tGO:query\n@SQ\tSN:chr1\tLN:10\n"));
+    }
+}
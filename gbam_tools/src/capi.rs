@@ -0,0 +1,533 @@
+//! C API for reading GBAM files. Header `include/gbam.h` is generated by
+//! cbindgen when the crate is built with `c-api` feature and
+//! `GBAM_UPDATE_HEADER` environment variable set.
+//!
+//! Functions which may fail return negative value (or NULL) and store the
+//! reason, which is available through `gbam_last_error`. Records are returned
+//! as views into buffers owned by reader, valid until the next call on it.
+use crate::query::pileup::{overlapping_records, parse_region};
+use crate::reader::batch::{BatchColumn, BatchReader};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::{read_index, Reader};
+use crate::reader::record::GbamRecord;
+use bam_tools::record::fields::{field_type, FieldType, Fields};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::ops::Range;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Arc;
+
+/// Fields of GBAM record, named as in SAM specification. Functions take them
+/// as `int`, so that values from C are validated.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum GbamField {
+    RefId,
+    Pos,
+    Mapq,
+    Bin,
+    Flag,
+    NextRefId,
+    NextPos,
+    Tlen,
+    ReadName,
+    Cigar,
+    Seq,
+    Qual,
+    Tags,
+}
+
+const GBAM_FIELDS: [GbamField; 13] = [
+    GbamField::RefId,
+    GbamField::Pos,
+    GbamField::Mapq,
+    GbamField::Bin,
+    GbamField::Flag,
+    GbamField::NextRefId,
+    GbamField::NextPos,
+    GbamField::Tlen,
+    GbamField::ReadName,
+    GbamField::Cigar,
+    GbamField::Seq,
+    GbamField::Qual,
+    GbamField::Tags,
+];
+
+impl TryFrom<c_int> for GbamField {
+    type Error = String;
+
+    fn try_from(value: c_int) -> Result<Self, Self::Error> {
+        usize::try_from(value)
+            .ok()
+            .and_then(|idx| GBAM_FIELDS.get(idx).copied())
+            .ok_or_else(|| format!("Unknown field {}.", value))
+    }
+}
+
+impl From<GbamField> for Fields {
+    fn from(field: GbamField) -> Self {
+        match field {
+            GbamField::RefId => Fields::RefID,
+            GbamField::Pos => Fields::Pos,
+            GbamField::Mapq => Fields::Mapq,
+            GbamField::Bin => Fields::Bin,
+            GbamField::Flag => Fields::Flags,
+            GbamField::NextRefId => Fields::NextRefID,
+            GbamField::NextPos => Fields::NextPos,
+            GbamField::Tlen => Fields::TemplateLength,
+            GbamField::ReadName => Fields::ReadName,
+            GbamField::Cigar => Fields::RawCigar,
+            GbamField::Seq => Fields::RawSequence,
+            GbamField::Qual => Fields::RawQual,
+            GbamField::Tags => Fields::RawTags,
+        }
+    }
+}
+
+/// Record filled according to reader fields. Fields which are not fetched
+/// are zero, their pointers are NULL.
+#[repr(C)]
+pub struct GbamRecordView {
+    pub refid: i32,
+    pub pos: i32,
+    pub mapq: u8,
+    pub bin: u16,
+    pub flag: u16,
+    pub next_ref_id: i32,
+    pub next_pos: i32,
+    pub tlen: i32,
+    /// NUL terminated read name.
+    pub read_name: *const c_char,
+    /// BAM encoded CIGAR operations (`len << 4 | op`).
+    pub cigar: *const u32,
+    pub n_cigar_op: usize,
+    /// Sequence as letters, not NUL terminated.
+    pub seq: *const c_char,
+    pub seq_len: usize,
+    /// Phred-scaled base qualities.
+    pub qual: *const u8,
+    pub qual_len: usize,
+    /// BAM encoded auxiliary data.
+    pub tags: *const u8,
+    pub tags_len: usize,
+}
+
+/// Records returned by `gbam_reader_next`.
+enum Selection {
+    Range(Range<usize>),
+    List(std::vec::IntoIter<usize>),
+}
+
+impl Iterator for Selection {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        match self {
+            Selection::Range(range) => range.next(),
+            Selection::List(rec_nums) => rec_nums.next(),
+        }
+    }
+}
+
+/// Opaque GBAM reader.
+pub struct GbamReader {
+    reader: Reader,
+    index: Option<Arc<Vec<u32>>>,
+    ref_names: Vec<CString>,
+    rec: GbamRecord,
+    cigar: Vec<u32>,
+    selection: Selection,
+}
+
+impl GbamReader {
+    fn new(reader: Reader, index: Option<Arc<Vec<u32>>>) -> Self {
+        let ref_names = reader
+            .file_meta
+            .get_ref_seqs()
+            .iter()
+            .map(|(name, _)| CString::new(name.as_str()).unwrap())
+            .collect();
+        let selection = Selection::Range(0..reader.amount);
+        GbamReader { reader, index, ref_names, rec: GbamRecord::default(), cigar: Vec::new(), selection }
+    }
+
+    /// Reader of the same file and index with another set of fields.
+    fn sub_reader(&self, fields: &[Fields]) -> Reader {
+        Reader::from_source_with_meta(self.reader.source.clone(), ParsingTemplate::new_with(fields), &self.reader.file_meta, self.index.clone()).unwrap()
+    }
+
+    fn view(&mut self, rec_num: usize) -> GbamRecordView {
+        self.reader.fill_record(rec_num, &mut self.rec);
+        let rec = &self.rec;
+        self.cigar.clear();
+        if let Some(cigar) = &rec.cigar {
+            self.cigar.extend(cigar.ops().map(|op| op.0));
+        }
+        let (seq, seq_len) = rec.seq.as_ref().map_or((ptr::null(), 0), |seq| (seq.as_ptr() as *const c_char, seq.len()));
+        GbamRecordView {
+            refid: rec.refid.unwrap_or(0),
+            pos: rec.pos.unwrap_or(0),
+            mapq: rec.mapq.unwrap_or(0),
+            bin: rec.bin.unwrap_or(0),
+            flag: rec.flag.unwrap_or(0),
+            next_ref_id: rec.next_ref_id.unwrap_or(0),
+            next_pos: rec.next_pos.unwrap_or(0),
+            tlen: rec.tlen.unwrap_or(0),
+            read_name: rec.read_name.as_ref().map_or(ptr::null(), |name| name.as_ptr() as *const c_char),
+            cigar: rec.cigar.as_ref().map_or(ptr::null(), |_| self.cigar.as_ptr()),
+            n_cigar_op: self.cigar.len(),
+            seq,
+            seq_len,
+            qual: rec.qual.as_ref().map_or(ptr::null(), |qual| qual.as_ptr()),
+            qual_len: rec.qual.as_ref().map_or(0, |qual| qual.len()),
+            tags: rec.tags.as_ref().map_or(ptr::null(), |tags| tags.as_ptr()),
+            tags_len: rec.tags.as_ref().map_or(0, |tags| tags.len()),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(CString::new(msg.replace('\0', " ")).unwrap()));
+}
+
+/// Runs `f`, catching panics, since they must not cross FFI boundary. Returns
+/// `fail` on error.
+fn guard<T, F: FnOnce() -> Result<T, String>>(fail: T, f: F) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(val)) => val,
+        Ok(Err(msg)) => {
+            set_last_error(msg);
+            fail
+        }
+        Err(payload) => {
+            let msg = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+                (Some(msg), _) => msg.to_string(),
+                (_, Some(msg)) => msg.clone(),
+                _ => String::from("Unknown error."),
+            };
+            set_last_error(msg);
+            fail
+        }
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(String::from("String argument is NULL."));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| String::from("String argument is not valid UTF-8."))
+}
+
+unsafe fn reader_mut<'a>(reader: *mut GbamReader) -> Result<&'a mut GbamReader, String> {
+    reader.as_mut().ok_or_else(|| String::from("Reader is NULL."))
+}
+
+unsafe fn reader_ref<'a>(reader: *const GbamReader) -> Result<&'a GbamReader, String> {
+    reader.as_ref().ok_or_else(|| String::from("Reader is NULL."))
+}
+
+unsafe fn record_out<'a>(out: *mut GbamRecordView) -> Result<&'a mut GbamRecordView, String> {
+    out.as_mut().ok_or_else(|| String::from("Output record is NULL."))
+}
+
+/// Returns message of the last error happened in this thread, or NULL. The
+/// string is valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn gbam_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
+
+/// Opens GBAM file with all fields fetched. `index_path` is index file of
+/// index sorted file or NULL; with it records are numbered in sorted order.
+/// Returns NULL on error.
+///
+/// # Safety
+/// `path` and `index_path` (if not NULL) must be NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_open(path: *const c_char, index_path: *const c_char) -> *mut GbamReader {
+    guard(ptr::null_mut(), || {
+        let path = to_str(path)?;
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let index = match index_path.is_null() {
+            true => None,
+            false => {
+                let index_path = to_str(index_path)?;
+                Some(read_index(File::open(index_path).map_err(|e| format!("{}: {}", index_path, e))?))
+            }
+        };
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let reader = Reader::new_with_index(file, template, index.clone()).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(GbamReader::new(reader, index))))
+    })
+}
+
+/// Closes reader.
+///
+/// # Safety
+/// `reader` must be returned by `gbam_reader_open` (or NULL) and not used after.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_close(reader: *mut GbamReader) {
+    if !reader.is_null() {
+        drop(Box::from_raw(reader));
+    }
+}
+
+/// Sets fields (`GbamField` values) fetched for records. Returns 0 on success.
+///
+/// # Safety
+/// `reader` must be open reader, `fields` must point to `n` values.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_set_fields(reader: *mut GbamReader, fields: *const c_int, n: usize) -> c_int {
+    guard(-1, || {
+        let reader = reader_mut(reader)?;
+        if fields.is_null() && n != 0 {
+            return Err(String::from("Fields are NULL."));
+        }
+        let fields: Vec<Fields> = match n {
+            0 => Vec::new(),
+            _ => std::slice::from_raw_parts(fields, n)
+                .iter()
+                .map(|&field| GbamField::try_from(field).map(Fields::from))
+                .collect::<Result<_, _>>()?,
+        };
+        reader.reader = reader.sub_reader(&fields);
+        reader.rec = GbamRecord::default();
+        Ok(0)
+    })
+}
+
+/// Amount of records, negative value on error.
+///
+/// # Safety
+/// `reader` must be open reader.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_len(reader: *const GbamReader) -> i64 {
+    guard(-1, || Ok(reader_ref(reader)?.reader.amount as i64))
+}
+
+/// Amount of reference sequences, negative value on error.
+///
+/// # Safety
+/// `reader` must be open reader.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_n_refs(reader: *const GbamReader) -> i64 {
+    guard(-1, || Ok(reader_ref(reader)?.ref_names.len() as i64))
+}
+
+/// Name of reference sequence, NULL on error (e.g. `ref_id` is out of range).
+///
+/// # Safety
+/// `reader` must be open reader.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_ref_name(reader: *const GbamReader, ref_id: usize) -> *const c_char {
+    guard(ptr::null(), || {
+        let name = reader_ref(reader)?.ref_names.get(ref_id).ok_or_else(|| format!("Reference id {} is out of range.", ref_id))?;
+        Ok(name.as_ptr())
+    })
+}
+
+/// Length of reference sequence, negative value on error (e.g. `ref_id` is
+/// out of range).
+///
+/// # Safety
+/// `reader` must be open reader.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_ref_len(reader: *const GbamReader, ref_id: usize) -> i64 {
+    guard(-1, || {
+        let ref_seqs = reader_ref(reader)?.reader.file_meta.get_ref_seqs();
+        let (_, len) = ref_seqs.get(ref_id).ok_or_else(|| format!("Reference id {} is out of range.", ref_id))?;
+        Ok(*len as i64)
+    })
+}
+
+/// Reads record `rec_num` into `out`. Returns 0 on success.
+///
+/// # Safety
+/// `reader` must be open reader, `out` must be valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_read(reader: *mut GbamReader, rec_num: usize, out: *mut GbamRecordView) -> c_int {
+    guard(-1, || {
+        let reader = reader_mut(reader)?;
+        let out = record_out(out)?;
+        if rec_num >= reader.reader.amount {
+            return Err(format!("Record number {} is out of range.", rec_num));
+        }
+        *out = reader.view(rec_num);
+        Ok(0)
+    })
+}
+
+/// Makes `gbam_reader_next` iterate over all records from the first one.
+/// Returns 0 on success.
+///
+/// # Safety
+/// `reader` must be open reader.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_rewind(reader: *mut GbamReader) -> c_int {
+    guard(-1, || {
+        let reader = reader_mut(reader)?;
+        reader.selection = Selection::Range(0..reader.reader.amount);
+        Ok(0)
+    })
+}
+
+/// Reads next record of iteration (all records after open or rewind, region
+/// records after query) into `out`. Returns 1 if record was read, 0 at the
+/// end, negative value on error.
+///
+/// # Safety
+/// `reader` must be open reader, `out` must be valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_next(reader: *mut GbamReader, out: *mut GbamRecordView) -> c_int {
+    guard(-1, || {
+        let reader = reader_mut(reader)?;
+        let out = record_out(out)?;
+        match reader.selection.next() {
+            Some(rec_num) => {
+                *out = reader.view(rec_num);
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    })
+}
+
+/// Makes `gbam_reader_next` iterate over records which overlap region
+/// (chr1:1000-2000, 1-based, inclusive, or chr1). The file has to be sorted by
+/// coordinate (pass index file for index sorted files). Returns amount of
+/// records in region, negative value on error.
+///
+/// # Safety
+/// `reader` must be open reader, `region` must be NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_query(reader: *mut GbamReader, region: *const c_char) -> i64 {
+    guard(-1, || {
+        let reader = reader_mut(reader)?;
+        let (ref_id, start, end) = parse_region(reader.reader.file_meta.get_ref_seqs(), to_str(region)?)?;
+        if !reader.reader.is_coordinate_sorted() {
+            return Err(String::from("File has to be sorted by coordinate (pass index file for index sorted files)."));
+        }
+        let mut locator = reader.sub_reader(&[Fields::RefID, Fields::Pos, Fields::RawCigar]);
+        let rec_nums = overlapping_records(&mut locator, ref_id, start, end);
+        let amount = rec_nums.len() as i64;
+        reader.selection = Selection::List(rec_nums.into_iter());
+        Ok(amount)
+    })
+}
+
+fn field_size(field: GbamField) -> c_int {
+    let field = Fields::from(field);
+    match field_type(&field) {
+        FieldType::FixedSized => match field {
+            Fields::Mapq => 1,
+            Fields::Bin | Fields::Flags => 2,
+            _ => 4,
+        },
+        FieldType::VariableSized => 0,
+    }
+}
+
+/// Size in bytes of value of fixed sized field (1 for mapq, 2 for bin and
+/// flag, 4 for the rest), 0 for variable sized fields, negative value for
+/// unknown field.
+#[no_mangle]
+pub extern "C" fn gbam_field_size(field: c_int) -> c_int {
+    guard(-1, || Ok(field_size(GbamField::try_from(field)?)))
+}
+
+/// Copies values of fixed sized field of all records into `out` (in order of
+/// index file, if passed), which must hold `gbam_reader_len` values of
+/// `gbam_field_size` bytes. Only blocks of this field are decompressed.
+/// Returns 0 on success.
+///
+/// # Safety
+/// `reader` must be open reader, `out` must point to `len` values.
+#[no_mangle]
+pub unsafe extern "C" fn gbam_reader_fetch_column(reader: *mut GbamReader, field: c_int, out: *mut c_void, len: usize) -> c_int {
+    guard(-1, || {
+        let reader = reader_mut(reader)?;
+        let field = GbamField::try_from(field)?;
+        if field_size(field) == 0 {
+            return Err(format!("Field {:?} is not fixed sized.", field));
+        }
+        if len != reader.reader.amount || out.is_null() {
+            return Err(format!("Output buffer has to hold {} values.", reader.reader.amount));
+        }
+        let column_reader = reader.sub_reader(&[field.into()]);
+        let column = BatchReader::new(&column_reader).read(0..column_reader.amount).into_columns().pop().unwrap().1;
+        let index = reader.index.as_deref();
+        match column {
+            BatchColumn::U8(items) => copy_column(&items, index, out as *mut u8),
+            BatchColumn::U16(items) => copy_column(&items, index, out as *mut u16),
+            BatchColumn::I32(items) => copy_column(&items, index, out as *mut i32),
+            BatchColumn::Variable(_) => unreachable!(),
+        }
+        Ok(0)
+    })
+}
+
+/// Copies column into `out`, reordering values by index mapping.
+unsafe fn copy_column<T: Copy>(items: &[T], index: Option<&Vec<u32>>, out: *mut T) {
+    let out = std::slice::from_raw_parts_mut(out, items.len());
+    match index {
+        Some(index) => out.iter_mut().zip(index).for_each(|(slot, &rec_num)| *slot = items[rec_num as usize]),
+        None => out.copy_from_slice(items),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::reader::{reader_from_records, test_record};
+    use std::mem::MaybeUninit;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(gbam_last_error()) }.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_invalid_arguments() {
+        assert_eq!(gbam_field_size(GbamField::Flag as c_int), 2);
+        assert_eq!(gbam_field_size(GbamField::Seq as c_int), 0);
+        assert_eq!(gbam_field_size(13), -1);
+        assert_eq!(last_error(), "Unknown field 13.");
+        assert_eq!(gbam_field_size(-1), -1);
+
+        unsafe {
+            assert_eq!(gbam_reader_len(ptr::null()), -1);
+            assert_eq!(last_error(), "Reader is NULL.");
+            assert_eq!(gbam_reader_n_refs(ptr::null()), -1);
+            assert!(gbam_reader_ref_name(ptr::null(), 0).is_null());
+            assert_eq!(gbam_reader_ref_len(ptr::null(), 0), -1);
+            assert_eq!(gbam_reader_rewind(ptr::null_mut()), -1);
+            assert_eq!(gbam_reader_set_fields(ptr::null_mut(), ptr::null(), 0), -1);
+        }
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let reader = reader_from_records(vec![(String::from("chr1"), 100)], &[test_record(0, 10)], template);
+        let reader = Box::into_raw(Box::new(GbamReader::new(reader, None)));
+        unsafe {
+            assert_eq!(gbam_reader_read(reader, 0, ptr::null_mut()), -1);
+            assert_eq!(last_error(), "Output record is NULL.");
+            assert_eq!(gbam_reader_next(reader, ptr::null_mut()), -1);
+            assert_eq!(last_error(), "Output record is NULL.");
+            // Failed call does not advance iteration.
+            let mut view = MaybeUninit::<GbamRecordView>::uninit();
+            assert_eq!(gbam_reader_next(reader, view.as_mut_ptr()), 1);
+            assert_eq!(view.assume_init_ref().pos, 10);
+            assert_eq!(gbam_reader_read(reader, 1, view.as_mut_ptr()), -1);
+            assert_eq!(last_error(), "Record number 1 is out of range.");
+            gbam_reader_close(reader);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Python bindings. Module `gbam_tools` exposes `Reader` (reading, region
+//! queries, flagstat, depth, columns as NumPy arrays) and BAM <-> GBAM
+//! conversion functions.
+use crate::bam::bam_to_gbam::sam_header_text;
+use crate::bam::gbam_to_bam::gbam_to_bam as gbam_to_bam_impl;
+use crate::meta::SortOrder;
+use crate::name_filter::strip_nul;
+use crate::query::depth::{depth_fields, region_depth, CigarMode, DepthMode};
+use crate::query::flagstat::{flagstat, Stats};
+use crate::query::pileup::{overlapping_records, parse_region};
+use crate::reader::batch::{BatchColumn, BatchReader};
+use crate::reader::parse_tmplt::{field_by_name, ParsingTemplate};
+use crate::reader::reader::{read_index, Reader};
+use crate::reader::record;
+use crate::{bam_sort_to_gbam, bam_to_gbam as bam_to_gbam_impl, Codecs, Fields};
+use bam_tools::record::fields::{field_type, is_data_field, FieldType};
+use numpy::{Element, PyArray1};
+use pyo3::exceptions::{PyIndexError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::fs::File;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Record as seen from Python. Fields which were not fetched are `None`.
+#[pyclass(name = "GbamRecord", get_all)]
+#[derive(Clone, Debug)]
+pub struct GbamRecord {
+    pub refid: Option<i32>,
+    pub pos: Option<i32>,
+    pub mapq: Option<u8>,
+    pub bin: Option<u16>,
+    pub flag: Option<u16>,
+    pub next_ref_id: Option<i32>,
+    pub next_pos: Option<i32>,
+    pub tlen: Option<i32>,
+    pub read_name: Option<String>,
+    pub cigar: Option<String>,
+    pub seq: Option<String>,
+    pub qual: Option<Vec<u8>>,
+    pub tags: Option<Vec<u8>>,
+}
+
+impl From<&record::GbamRecord> for GbamRecord {
+    fn from(rec: &record::GbamRecord) -> Self {
+        Self {
+            refid: rec.refid,
+            pos: rec.pos,
+            mapq: rec.mapq,
+            bin: rec.bin,
+            flag: rec.flag,
+            next_ref_id: rec.next_ref_id,
+            next_pos: rec.next_pos,
+            tlen: rec.tlen,
+            read_name: rec.read_name.as_ref().map(|name| String::from_utf8_lossy(strip_nul(name)).into_owned()),
+            cigar: rec.cigar.as_ref().map(|cigar| cigar.to_string()),
+            seq: rec.seq.clone(),
+            qual: rec.qual.clone(),
+            tags: rec.tags.clone(),
+        }
+    }
+}
+
+#[pymethods]
+impl GbamRecord {
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// Reads records of GBAM file. Only `fields` (SAM names: ref_id, pos, mapq,
+/// bin, flag, next_ref_id, next_pos, tlen, read_name, cigar, seq, qual, tags)
+/// are fetched, all of them by default. With `index_file` of index sorted
+/// file records are numbered in sorted order.
+#[pyclass(name = "Reader", unsendable)]
+pub struct PyReader {
+    reader: Reader,
+    file: File,
+    index: Option<Arc<Vec<u32>>>,
+    buf: record::GbamRecord,
+}
+
+impl PyReader {
+    fn record(&mut self, rec_num: usize) -> GbamRecord {
+        self.reader.fill_record(rec_num, &mut self.buf);
+        GbamRecord::from(&self.buf)
+    }
+
+    /// Reader of the same file and index with another set of fields.
+    fn sub_reader(&self, fields: &[Fields]) -> Reader {
+        Reader::from_source_with_meta(self.reader.source.clone(), ParsingTemplate::new_with(fields), &self.reader.file_meta, self.index.clone()).unwrap()
+    }
+
+    fn fixed_field(name: &str) -> PyResult<Fields> {
+        match field_by_name(name) {
+            Some(field) if is_data_field(&field) && matches!(field_type(&field), FieldType::FixedSized) => Ok(field),
+            _ => Err(PyValueError::new_err(format!("{} is not a fixed sized field (ref_id, pos, mapq, bin, flag, next_ref_id, next_pos, tlen).", name))),
+        }
+    }
+
+    fn parse_region(&self, region: &str) -> PyResult<(i32, u32, u32)> {
+        parse_region(self.reader.file_meta.get_ref_seqs(), region).map_err(PyValueError::new_err)
+    }
+
+    fn check_coordinate_sorted(&self) -> PyResult<()> {
+        match self.reader.is_coordinate_sorted() {
+            true => Ok(()),
+            false => Err(PyValueError::new_err("File has to be sorted by coordinate (pass index_file for index sorted files).")),
+        }
+    }
+}
+
+#[pymethods]
+impl PyReader {
+    #[new]
+    #[pyo3(signature = (path, fields=None, index_file=None))]
+    fn new(path: PathBuf, fields: Option<Vec<String>>, index_file: Option<PathBuf>) -> PyResult<Self> {
+        let template = match fields {
+            Some(names) => {
+                let mut fields = Vec::with_capacity(names.len());
+                for name in names {
+                    fields.push(field_by_name(&name).ok_or_else(|| PyValueError::new_err(format!("Unknown field {}.", name)))?);
+                }
+                ParsingTemplate::new_with(&fields)
+            }
+            None => {
+                let mut template = ParsingTemplate::new();
+                template.set_all();
+                template
+            }
+        };
+        let file = File::open(path)?;
+        let index = index_file.map(|path| File::open(path).map(read_index)).transpose()?;
+        let reader = Reader::new_with_index(file.try_clone()?, template, index.clone())?;
+        Ok(Self { reader, file, index, buf: record::GbamRecord::default() })
+    }
+
+    fn __len__(&self) -> usize {
+        self.reader.amount
+    }
+
+    fn __getitem__(&mut self, rec_num: isize) -> PyResult<GbamRecord> {
+        let amount = self.reader.amount as isize;
+        let rec_num = if rec_num < 0 { rec_num + amount } else { rec_num };
+        if !(0..amount).contains(&rec_num) {
+            return Err(PyIndexError::new_err("Record number is out of range."));
+        }
+        Ok(self.record(rec_num as usize))
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> RecordIterator {
+        let range = 0..slf.reader.amount;
+        RecordIterator { reader: slf.into(), range }
+    }
+
+    /// Iterator over records with numbers in [start, end).
+    #[pyo3(signature = (start=0, end=None))]
+    fn records(slf: PyRef<'_, Self>, start: usize, end: Option<usize>) -> PyResult<RecordIterator> {
+        let end = end.unwrap_or(slf.reader.amount);
+        if start > end || end > slf.reader.amount {
+            return Err(PyIndexError::new_err("Records range is out of bounds."));
+        }
+        Ok(RecordIterator { reader: slf.into(), range: start..end })
+    }
+
+    /// Records which overlap region (chr1:1000-2000, 1-based, inclusive, or
+    /// chr1). The file has to be sorted by coordinate.
+    fn query(&mut self, region: &str) -> PyResult<Vec<GbamRecord>> {
+        let (ref_id, start, end) = self.parse_region(region)?;
+        self.check_coordinate_sorted()?;
+        // Positions and CIGARs are read first, other fields only for overlapping records.
+        let mut locator = self.sub_reader(&[Fields::RefID, Fields::Pos, Fields::RawCigar]);
+        let rec_nums = overlapping_records(&mut locator, ref_id, start, end);
+        Ok(rec_nums.into_iter().map(|rec_num| self.record(rec_num)).collect())
+    }
+
+    /// All alignments with read name `name`.
+    fn find_by_name(&mut self, name: &str) -> Vec<GbamRecord> {
+        self.reader.find_by_name(name.as_bytes()).iter().map(GbamRecord::from).collect()
+    }
+
+    /// Depth of every position of region (see `query`). `mode` is one of
+    /// reads, forward, reverse, fragment.
+    #[pyo3(signature = (region, mode="reads", skip_splices=false, skip_deletions=false))]
+    fn depth(&self, py: Python<'_>, region: &str, mode: &str, skip_splices: bool, skip_deletions: bool) -> PyResult<Vec<i32>> {
+        let (ref_id, start, end) = self.parse_region(region)?;
+        self.check_coordinate_sorted()?;
+        let mode = DepthMode::from_str(mode).map_err(PyValueError::new_err)?;
+        if mode == DepthMode::Fragment && (skip_splices || skip_deletions) {
+            return Err(PyValueError::new_err("skip_splices and skip_deletions can't be used with fragment mode."));
+        }
+        let cigar_mode = if skip_deletions {
+            CigarMode::SkipSplicesAndDeletions
+        } else if skip_splices {
+            CigarMode::SkipSplices
+        } else {
+            CigarMode::Span
+        };
+        let mut reader = self.sub_reader(&depth_fields(mode));
+        Ok(py.detach(|| region_depth(&mut reader, ref_id, start, end, mode, cigar_mode)))
+    }
+
+    /// Flag statistics, as in `samtools flagstat`. Every value is a pair of
+    /// QC-passed and QC-failed reads counts.
+    fn flagstat<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let file = &self.file;
+        let stats = py.detach(|| flagstat(file));
+        stats_to_dict(py, &stats)
+    }
+
+    /// Values of fixed sized field (ref_id, pos, mapq, bin, flag, next_ref_id,
+    /// next_pos, tlen) of all records as NumPy array (uint8 for mapq, uint16
+    /// for bin and flag, int32 for the rest), in order of index file if
+    /// passed. Only blocks of this field are decompressed.
+    fn column<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+        let field = Self::fixed_field(name)?;
+        let reader = self.sub_reader(&[field]);
+        let index = self.index.as_deref();
+        let column = py.detach(move || BatchReader::new(&reader).read(0..reader.amount).into_columns().pop().unwrap().1);
+        Ok(match column {
+            BatchColumn::U8(items) => to_array(py, items, index).into_any(),
+            BatchColumn::U16(items) => to_array(py, items, index).into_any(),
+            BatchColumn::I32(items) => to_array(py, items, index).into_any(),
+            BatchColumn::Variable(_) => unreachable!(),
+        })
+    }
+
+    /// Reference sequences names and lengths.
+    #[getter]
+    fn ref_seqs(&self) -> Vec<(String, u32)> {
+        self.reader.file_meta.get_ref_seqs().clone()
+    }
+
+    /// SAM header text.
+    #[getter]
+    fn sam_header(&self) -> String {
+        sam_header_text(self.reader.file_meta.get_sam_header())
+    }
+}
+
+/// Iterator over records of `Reader`.
+#[pyclass(unsendable)]
+pub struct RecordIterator {
+    reader: Py<PyReader>,
+    range: Range<usize>,
+}
+
+#[pymethods]
+impl RecordIterator {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> Option<GbamRecord> {
+        let rec_num = self.range.next()?;
+        Some(self.reader.borrow_mut(py).record(rec_num))
+    }
+
+    fn __len__(&self) -> usize {
+        self.range.len()
+    }
+}
+
+/// Converts column into NumPy array, reordering values by index mapping.
+fn to_array<'py, T: Element + Copy>(py: Python<'py>, items: Vec<T>, index: Option<&Vec<u32>>) -> Bound<'py, PyArray1<T>> {
+    match index {
+        Some(index) => PyArray1::from_iter(py, index.iter().map(|&rec_num| items[rec_num as usize])),
+        None => PyArray1::from_vec(py, items),
+    }
+}
+
+fn stats_to_dict<'py>(py: Python<'py>, stats: &Stats) -> PyResult<Bound<'py, PyDict>> {
+    // Keys are the same as in `samtools flagstat -O json`.
+    let dict = PyDict::new(py);
+    for (key, value) in [
+        ("total", stats.n_reads),
+        ("primary", stats.n_primary),
+        ("secondary", stats.n_secondary),
+        ("supplementary", stats.n_supp),
+        ("duplicates", stats.n_dup),
+        ("primary duplicates", stats.n_pdup),
+        ("mapped", stats.n_mapped),
+        ("primary mapped", stats.n_pmapped),
+        ("paired in sequencing", stats.n_pair_all),
+        ("read1", stats.n_read1),
+        ("read2", stats.n_read2),
+        ("properly paired", stats.n_pair_good),
+        ("with itself and mate mapped", stats.n_pair_map),
+        ("singletons", stats.n_sgltn),
+        ("with mate mapped to a different chr", stats.n_diffchr),
+        ("with mate mapped to a different chr (mapQ >= 5)", stats.n_diffhigh),
+    ] {
+        dict.set_item(key, (value[0], value[1]))?;
+    }
+    Ok(dict)
+}
+
+fn parse_codec(codec: &str) -> PyResult<Codecs> {
+    match codec {
+        "gzip" => Ok(Codecs::Gzip),
+        "lz4" => Ok(Codecs::Lz4),
+        _ => Err(PyValueError::new_err(format!("Codec <{}> is not supported.", codec))),
+    }
+}
+
+/// Converts BAM file into GBAM file. With `sort` records are sorted in
+/// `sort_order` (coordinate, name, collate); `index_sort` keeps the order in
+/// index file instead of rearranging records.
+#[pyfunction]
+#[pyo3(signature = (in_path, out_path, codec="lz4", sort=false, sort_order="coordinate", index_sort=false, name_filter=false, temp_dir=None))]
+#[allow(clippy::too_many_arguments)]
+fn bam_to_gbam(
+    py: Python<'_>,
+    in_path: &str,
+    out_path: &str,
+    codec: &str,
+    sort: bool,
+    sort_order: &str,
+    index_sort: bool,
+    name_filter: bool,
+    temp_dir: Option<PathBuf>,
+) -> PyResult<()> {
+    let codec = parse_codec(codec)?;
+    let sort_order = SortOrder::from_str(sort_order).map_err(PyValueError::new_err)?;
+    let full_command = format!("gbam_tools.bam_to_gbam({}, {})", in_path, out_path);
+    py.detach(|| match sort {
+        true => bam_sort_to_gbam(in_path, out_path, codec, None, temp_dir, full_command, index_sort, name_filter, sort_order),
+        false => bam_to_gbam_impl(in_path, out_path, codec, full_command, name_filter),
+    });
+    Ok(())
+}
+
+/// Converts GBAM file into BAM file.
+#[pyfunction]
+fn gbam_to_bam(py: Python<'_>, in_path: &str, out_path: &str) {
+    py.detach(|| gbam_to_bam_impl(in_path, out_path));
+}
+
+#[pymodule]
+fn gbam_tools(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyReader>()?;
+    m.add_class::<RecordIterator>()?;
+    m.add_class::<GbamRecord>()?;
+    m.add_function(wrap_pyfunction!(bam_to_gbam, m)?)?;
+    m.add_function(wrap_pyfunction!(gbam_to_bam, m)?)?;
+    Ok(())
+}
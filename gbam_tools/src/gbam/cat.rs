@@ -0,0 +1,153 @@
+//! Concatenation of GBAM files by copying compressed blocks.
+use crate::bam::bam_to_gbam::set_header_sort_order;
+use crate::meta::{position_key, FileInfo, FileMeta, SortOrder, FILE_INFO_SIZE};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::reader::Reader;
+use crate::writer::write_meta;
+use bam_tools::record::fields::Fields;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+
+/// Returns (RefID, Pos) of the first and the last record of file, if it has
+/// position keys.
+fn key_bounds(file_meta: &FileMeta) -> Option<((i32, i32), (i32, i32))> {
+    let mut blocks = file_meta.view_blocks(&Fields::Pos).iter().filter(|block| block.numitems > 0);
+    let first = blocks.clone().next()?.stats.as_ref()?.start_key?;
+    let last = blocks.next_back()?.stats.as_ref()?.end_key?;
+    Some((first, last))
+}
+
+/// Checks whether concatenation of files is sorted by coordinate: every file
+/// is sorted and does not overlap with the next one.
+fn is_sorted_sequence(readers: &[Reader]) -> bool {
+    let mut prev_last: Option<(u32, u32)> = None;
+    for reader in readers.iter().filter(|reader| reader.amount > 0) {
+        if reader.sort_order() != SortOrder::Coordinate {
+            return false;
+        }
+        let Some((first, last)) = key_bounds(&reader.file_meta) else {
+            return false;
+        };
+        if prev_last.is_some_and(|prev_last| prev_last > position_key(first)) {
+            return false;
+        }
+        prev_last = Some(position_key(last));
+    }
+    true
+}
+
+/// Concatenates GBAM files by copying compressed blocks verbatim. Files must
+/// have the same reference sequences and codecs. SAM header of the first file
+/// is used. The result is sorted by coordinate only if inputs are sorted and do
+/// not overlap, otherwise it is unsorted.
+pub fn cat_gbams(in_paths: &[&str], out_path: &str, full_command: String) {
+    if in_paths.is_empty() {
+        panic!("No input files to concatenate.");
+    }
+    let readers: Vec<Reader> = in_paths
+        .iter()
+        .map(|path| Reader::new(File::open(path).unwrap(), ParsingTemplate::new()).unwrap())
+        .collect();
+    let mut out = BufWriter::new(File::create(out_path).expect("failed"));
+    cat_readers(&readers, in_paths, &mut out, full_command);
+}
+
+/// Concatenates files of `readers` (named `in_paths` in messages) into `out`.
+fn cat_readers<W: Write + Seek>(readers: &[Reader], in_paths: &[&str], out: &mut W, full_command: String) {
+    let first_meta = &readers[0].file_meta;
+    for (reader, path) in readers.iter().zip(in_paths).skip(1) {
+        if reader.file_meta.get_ref_seqs() != first_meta.get_ref_seqs() {
+            panic!("Reference sequences of {} differ from {}.", path, in_paths[0]);
+        }
+        if Fields::iterator().any(|field| reader.file_meta.get_field_codec(field) != first_meta.get_field_codec(field)) {
+            panic!("Codecs of {} differ from {}.", path, in_paths[0]);
+        }
+    }
+
+    let sort_order = match is_sorted_sequence(readers) {
+        true => SortOrder::Coordinate,
+        false => SortOrder::Unsorted,
+    };
+    let mut file_meta = FileMeta::new(
+        *first_meta.get_field_codec(&Fields::RefID),
+        first_meta.get_ref_seqs().clone(),
+        set_header_sort_order(first_meta.get_sam_header(), sort_order),
+    );
+
+    out.seek(SeekFrom::Start(FILE_INFO_SIZE as u64)).unwrap();
+    for reader in readers {
+        for field in Fields::iterator() {
+            for block in reader.file_meta.view_blocks(field) {
+                let mut block = block.clone();
+                let start = block.seekpos as usize;
+                block.seekpos = out.stream_position().unwrap();
+                out.write_all(&reader.source.read_at(start, block.block_size as usize).unwrap()).unwrap();
+                if let Some(filter) = block.name_filter.as_mut() {
+                    let start = filter.seekpos as usize;
+                    filter.seekpos = out.stream_position().unwrap();
+                    out.write_all(&reader.source.read_at(start, filter.size as usize).unwrap()).unwrap();
+                }
+                file_meta.get_blocks(field).push(block);
+            }
+        }
+    }
+
+    let mut file_info = FileInfo::new([1, 0], 0, 0, full_command, sort_order);
+    write_meta(out, &file_meta, &mut file_info).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::reader::{test_record, write_to_memory, write_to_memory_with_name_filter};
+    use crate::reader::record::GbamRecord;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn named_records(names: &[&str], first_pos: i32) -> Vec<GbamRecord> {
+        (first_pos..)
+            .zip(names)
+            .map(|(pos, name)| GbamRecord {
+                read_name: Some(format!("{}\0", name).into_bytes()),
+                ..test_record(0, pos)
+            })
+            .collect()
+    }
+
+    fn reader_from_bytes(bytes: Vec<u8>) -> Reader {
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        Reader::from_source(Arc::new(Cursor::new(bytes)), template, None).unwrap()
+    }
+
+    #[test]
+    fn test_cat_readers() {
+        let ref_seqs = vec![(String::from("chr1"), 100)];
+        let first = named_records(&["a", "b", "c"], 10);
+        let second = named_records(&["d", "a"], 5);
+        let readers = [
+            reader_from_bytes(write_to_memory_with_name_filter(ref_seqs.clone(), &first)),
+            reader_from_bytes(write_to_memory(ref_seqs.clone(), &second)),
+        ];
+
+        let mut out = Cursor::new(Vec::new());
+        cat_readers(&readers, &["first", "second"], &mut out, String::new());
+        let mut reader = reader_from_bytes(out.into_inner());
+        assert_eq!(reader.file_meta.get_ref_seqs(), &ref_seqs);
+        // Files overlap, so the result is not sorted.
+        assert_eq!(reader.sort_order(), SortOrder::Unsorted);
+        assert_eq!(reader.amount, first.len() + second.len());
+        for (expected, rec) in first.iter().chain(&second).zip(reader.records()) {
+            assert_eq!(rec.pos, expected.pos);
+            assert_eq!(rec.read_name, expected.read_name);
+        }
+        let positions: Vec<i32> = reader.find_by_name(b"a").iter().map(|rec| rec.pos.unwrap()).collect();
+        assert_eq!(positions, vec![10, 6]);
+    }
+
+    #[test]
+    #[should_panic(expected = "No input files to concatenate.")]
+    fn test_cat_no_inputs() {
+        cat_gbams(&[], "unused.gbam", String::new());
+    }
+}
@@ -0,0 +1,394 @@
+//! K-way merge of coordinate sorted GBAM files, like `samtools merge`.
+use crate::bam::bam_to_gbam::{build_sam_header, sam_header_text, set_header_sort_order, STATS_FIELDS};
+use crate::meta::SortOrder;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Merge key: (RefID, Pos, reverse strand, source index), same order as in
+/// BAM sort by coordinates and strand. Unmapped reads (-1) are located at the
+/// end. Records with equal positions are taken in order of input files.
+type MergeKey = (u32, u32, bool, usize);
+
+struct Source {
+    reader: Reader,
+    next_rec: usize,
+    rec: GbamRecord,
+    /// RefID in this file -> RefID in merged file.
+    ref_map: Vec<i32>,
+    read_group: Option<Vec<u8>>,
+    renamed: RenamedIds,
+}
+
+impl Source {
+    /// Reads the next record and remaps its reference ids into merged
+    /// dictionary, returns its merge key.
+    fn advance(&mut self, source_idx: usize) -> Option<MergeKey> {
+        if self.next_rec == self.reader.amount {
+            return None;
+        }
+        self.reader.fill_record(self.next_rec, &mut self.rec);
+        self.next_rec += 1;
+        let ref_map = &self.ref_map;
+        let remap = |ref_id: i32| if ref_id < 0 { ref_id } else { ref_map[ref_id as usize] };
+        let rec = &mut self.rec;
+        rec.refid = rec.refid.map(remap);
+        rec.next_ref_id = rec.next_ref_id.map(remap);
+        // Unmapped reads (-1) become u32::MAX, so they are located at the end.
+        Some((rec.refid.unwrap() as u32, rec.pos.unwrap() as u32, rec.is_reverse_complemented(), source_idx))
+    }
+
+    /// Attaches read group to current record, or renames its RG tag if the
+    /// read group clashed with another file. PG tag is renamed likewise.
+    fn prepare_record(&mut self) -> &GbamRecord {
+        let rec = &mut self.rec;
+        match &self.read_group {
+            Some(read_group) => rec.set_string_tag(b"RG", read_group),
+            None => rename_tag(rec, b"RG", &self.renamed.read_groups),
+        }
+        rename_tag(rec, b"PG", &self.renamed.programs);
+        &self.rec
+    }
+}
+
+/// New IDs of @RG and @PG lines of a file which clashed with different lines
+/// of previous files.
+#[derive(Default, Debug, PartialEq)]
+struct RenamedIds {
+    read_groups: HashMap<String, String>,
+    programs: HashMap<String, String>,
+}
+
+/// Replaces value of string tag of record if it was renamed.
+fn rename_tag(rec: &mut GbamRecord, tag: &[u8; 2], renamed: &HashMap<String, String>) {
+    if renamed.is_empty() {
+        return;
+    }
+    let new_id = rec
+        .get_string_tag(tag)
+        .and_then(|id| std::str::from_utf8(id).ok())
+        .and_then(|id| renamed.get(id));
+    if let Some(new_id) = new_id {
+        rec.set_string_tag(tag, new_id.as_bytes());
+    }
+}
+
+/// Builds union of reference dictionaries and mapping of reference ids of
+/// every file into it. References keep their relative order of every file, in
+/// other respects they are ordered by first appearance, so [chr1, chr3] and
+/// [chr2, chr3] give [chr1, chr2, chr3]. Panics if the same reference has
+/// different lengths, or if references are ordered differently in files, since
+/// merged file would not be sorted.
+fn merge_ref_seqs(dicts: &[&Vec<(String, u32)>]) -> (Vec<(String, u32)>, Vec<Vec<i32>>) {
+    // References by first appearance, and edges between adjacent references
+    // of every file.
+    let mut refs: Vec<(String, u32)> = Vec::new();
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    let mut next: Vec<Vec<usize>> = Vec::new();
+    let mut in_degree: Vec<usize> = Vec::new();
+    for dict in dicts {
+        let mut prev: Option<usize> = None;
+        for (name, len) in dict.iter() {
+            let id = *ids.entry(name.clone()).or_insert_with(|| {
+                refs.push((name.clone(), *len));
+                next.push(Vec::new());
+                in_degree.push(0);
+                refs.len() - 1
+            });
+            if refs[id].1 != *len {
+                panic!("Reference sequence {} has different lengths in input files.", name);
+            }
+            if let Some(prev) = prev {
+                next[prev].push(id);
+                in_degree[id] += 1;
+            }
+            prev = Some(id);
+        }
+    }
+
+    // Topological sort, earliest appeared reference is taken first.
+    let mut merged_ids = vec![-1; refs.len()];
+    let mut merged = Vec::with_capacity(refs.len());
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..refs.len()).filter(|&id| in_degree[id] == 0).map(Reverse).collect();
+    while let Some(Reverse(id)) = ready.pop() {
+        merged_ids[id] = merged.len() as i32;
+        merged.push(refs[id].clone());
+        for &next_id in &next[id] {
+            in_degree[next_id] -= 1;
+            if in_degree[next_id] == 0 {
+                ready.push(Reverse(next_id));
+            }
+        }
+    }
+    if merged.len() != refs.len() {
+        panic!("Reference sequences are ordered differently in input files.");
+    }
+
+    let ref_maps = dicts
+        .iter()
+        .map(|dict| dict.iter().map(|(name, _)| merged_ids[ids[name]]).collect())
+        .collect();
+    (merged, ref_maps)
+}
+
+fn header_line_id(line: &str) -> Option<&str> {
+    line.split('\t').find_map(|tag| tag.strip_prefix("ID:"))
+}
+
+/// Replaces values of `tag` (like "ID:") of header line which are renamed.
+fn rename_header_tag(line: &str, tag: &str, renamed: &HashMap<String, String>) -> String {
+    line.split('\t')
+        .map(|field| match field.strip_prefix(tag).and_then(|value| renamed.get(value)) {
+            Some(new_value) => format!("{}{}", tag, new_value),
+            None => field.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\t")
+}
+
+/// Merges header texts: @HD of the first file, @SQ lines of merged reference
+/// dictionary, @RG and @PG lines of all files, then the rest of lines without
+/// duplicates. Like in `samtools merge`, @RG and @PG lines which clash with
+/// different lines of previous files get unique suffix ("-1", "-2", ...)
+/// appended to their IDs (and PP tags of @PG lines follow), identical lines are
+/// combined. Returns header text and renamed IDs of every file.
+fn merge_header_texts(texts: &[String], ref_seqs: &[(String, u32)], read_groups: &[String]) -> (String, Vec<RenamedIds>) {
+    let mut hd_line = None;
+    let mut sq_lines: HashMap<&str, &str> = HashMap::new();
+    // (record type, ID) -> index of line in `id_lines`.
+    let mut ids: HashMap<(&str, String), usize> = HashMap::new();
+    let mut id_lines: Vec<String> = Vec::new();
+    let mut other_lines: Vec<&str> = Vec::new();
+    let mut renamed_ids = Vec::with_capacity(texts.len());
+
+    for text in texts {
+        let mut renamed = RenamedIds::default();
+        for line in text.lines() {
+            let record_type = line.get(..3).unwrap_or(line);
+            match record_type {
+                "@HD" => {
+                    hd_line.get_or_insert(line);
+                }
+                "@SQ" => {
+                    if let Some(name) = line.split('\t').find_map(|tag| tag.strip_prefix("SN:")) {
+                        sq_lines.entry(name).or_insert(line);
+                    }
+                }
+                "@RG" | "@PG" => {
+                    let mut line = rename_header_tag(line, "PP:", &renamed.programs);
+                    let id = header_line_id(&line).unwrap_or_default().to_owned();
+                    match ids.get(&(record_type, id.clone())) {
+                        Some(&idx) if id_lines[idx] == line => continue,
+                        Some(_) => {
+                            let new_id = (1..)
+                                .map(|n| format!("{}-{}", id, n))
+                                .find(|new_id| !ids.contains_key(&(record_type, new_id.clone())))
+                                .unwrap();
+                            let renamed = match record_type {
+                                "@RG" => &mut renamed.read_groups,
+                                _ => &mut renamed.programs,
+                            };
+                            renamed.insert(id.clone(), new_id.clone());
+                            line = rename_header_tag(&line, "ID:", renamed);
+                            ids.insert((record_type, new_id), id_lines.len());
+                        }
+                        None => {
+                            ids.insert((record_type, id), id_lines.len());
+                        }
+                    }
+                    id_lines.push(line);
+                }
+                _ if !line.is_empty() && !other_lines.contains(&line) => other_lines.push(line),
+                _ => {}
+            }
+        }
+        renamed_ids.push(renamed);
+    }
+    for read_group in read_groups {
+        if let Entry::Vacant(entry) = ids.entry(("@RG", read_group.clone())) {
+            entry.insert(id_lines.len());
+            id_lines.push(format!("@RG\tID:{}", read_group));
+        }
+    }
+
+    let mut text = String::new();
+    if let Some(hd_line) = hd_line {
+        text.push_str(hd_line);
+        text.push('\n');
+    }
+    for (name, len) in ref_seqs {
+        match sq_lines.get(&name[..]) {
+            Some(line) => text.push_str(line),
+            None => text.push_str(&format!("@SQ\tSN:{}\tLN:{}", name, len)),
+        }
+        text.push('\n');
+    }
+    for line in id_lines.iter().map(|l| &l[..]).chain(other_lines) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    (text, renamed_ids)
+}
+
+/// Merges coordinate sorted GBAM files into one sorted GBAM file. Reference
+/// dictionaries are reconciled and RefID/NextRefID are remapped. If
+/// `attach_rg` is set, every read gets RG tag named after its source file (file
+/// name without extension), replacing existing one. Files converted with index
+/// sort must be materialized first.
+pub fn merge_gbams(in_paths: &[&str], out_path: &str, attach_rg: bool, full_command: String) {
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let readers: Vec<Reader> = in_paths
+        .iter()
+        .map(|path| {
+            let reader = Reader::new(File::open(path).unwrap(), template.clone()).unwrap();
+            // Index sorted files are marked as sorted, but their records are not.
+            if reader.physical_sort_order() != SortOrder::Coordinate {
+                panic!("File {} is not sorted by coordinate (index sorted files have to be materialized first).", path);
+            }
+            reader
+        })
+        .collect();
+
+    let dicts: Vec<&Vec<(String, u32)>> = readers.iter().map(|r| r.file_meta.get_ref_seqs()).collect();
+    let (ref_seqs, ref_maps) = merge_ref_seqs(&dicts);
+
+    let read_groups: Vec<String> = match attach_rg {
+        true => in_paths
+            .iter()
+            .map(|path| Path::new(path).file_stem().unwrap().to_string_lossy().into_owned())
+            .collect(),
+        false => Vec::new(),
+    };
+    let texts: Vec<String> = readers.iter().map(|r| sam_header_text(r.file_meta.get_sam_header())).collect();
+    let (text, renamed_ids) = merge_header_texts(&texts, &ref_seqs, &read_groups);
+    let sam_header = set_header_sort_order(&build_sam_header(&text, &ref_seqs), SortOrder::Coordinate);
+
+    let mut stats_fields = STATS_FIELDS.to_vec();
+    stats_fields.push(Fields::Pos);
+    let mut writer = Writer::new(
+        BufWriter::new(File::create(out_path).expect("failed")),
+        vec![*readers[0].file_meta.get_field_codec(&Fields::RefID); FIELDS_NUM],
+        8,
+        stats_fields,
+        ref_seqs,
+        sam_header,
+        full_command,
+        SortOrder::Coordinate,
+    );
+
+    let mut sources: Vec<Source> = readers
+        .into_iter()
+        .zip(ref_maps)
+        .zip(renamed_ids)
+        .enumerate()
+        .map(|(i, ((reader, ref_map), renamed))| Source {
+            reader,
+            next_rec: 0,
+            rec: GbamRecord::default(),
+            ref_map,
+            read_group: read_groups.get(i).map(|rg| rg.as_bytes().to_vec()),
+            renamed,
+        })
+        .collect();
+
+    let mut heap = BinaryHeap::new();
+    for (i, source) in sources.iter_mut().enumerate() {
+        if let Some(key) = source.advance(i) {
+            heap.push(Reverse(key));
+        }
+    }
+
+    let mut buf = Vec::new();
+    while let Some(Reverse((_, _, _, source_idx))) = heap.pop() {
+        let source = &mut sources[source_idx];
+        source.prepare_record().convert_to_bytes(&mut buf);
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+        if let Some(key) = source.advance(source_idx) {
+            heap.push(Reverse(key));
+        }
+    }
+
+    writer.finish().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(names: &[&str]) -> Vec<(String, u32)> {
+        names.iter().map(|name| (name.to_string(), 100)).collect()
+    }
+
+    #[test]
+    fn test_merge_ref_seqs() {
+        let (a, b) = (dict(&["chr1", "chr2"]), dict(&["chr2", "chr3"]));
+        let (merged, ref_maps) = merge_ref_seqs(&[&a, &b]);
+        assert_eq!(merged, dict(&["chr1", "chr2", "chr3"]));
+        assert_eq!(ref_maps, vec![vec![0, 1], vec![1, 2]]);
+    }
+
+    #[test]
+    fn test_merge_ref_seqs_interleaved() {
+        let (a, b) = (dict(&["chr1", "chr3"]), dict(&["chr2", "chr3", "chr4"]));
+        let (merged, ref_maps) = merge_ref_seqs(&[&a, &b]);
+        assert_eq!(merged, dict(&["chr1", "chr2", "chr3", "chr4"]));
+        assert_eq!(ref_maps, vec![vec![0, 2], vec![1, 2, 3]]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_merge_ref_seqs_different_order() {
+        let (a, b) = (dict(&["chr1", "chr2"]), dict(&["chr2", "chr1"]));
+        merge_ref_seqs(&[&a, &b]);
+    }
+
+    #[test]
+    fn test_merge_header_texts() {
+        let texts = vec![
+            String::from("@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100\tM5:abc\n@RG\tID:a\n@PG\tID:bwa\n"),
+            String::from("@HD\tVN:1.4\n@SQ\tSN:chr2\tLN:100\n@RG\tID:b\n@PG\tID:bwa\n@CO\tnote\n"),
+        ];
+        let (text, renamed_ids) = merge_header_texts(&texts, &dict(&["chr1", "chr2"]), &[String::from("lane1")]);
+        assert_eq!(
+            text,
+            "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100\tM5:abc\n@SQ\tSN:chr2\tLN:100\n\
+             @RG\tID:a\n@PG\tID:bwa\n@RG\tID:b\n@RG\tID:lane1\n@CO\tnote\n"
+        );
+        assert_eq!(renamed_ids, vec![RenamedIds::default(), RenamedIds::default()]);
+    }
+
+    #[test]
+    fn test_merge_header_texts_clashing_ids() {
+        let texts = vec![
+            String::from("@RG\tID:a\tSM:x\n@RG\tID:a-1\tSM:y\n@PG\tID:bwa\tVN:1\n"),
+            String::from("@RG\tID:a\tSM:z\n@PG\tID:bwa\tVN:2\n@PG\tID:sort\tPP:bwa\n"),
+        ];
+        let (text, renamed_ids) = merge_header_texts(&texts, &[], &[]);
+        assert_eq!(
+            text,
+            "@RG\tID:a\tSM:x\n@RG\tID:a-1\tSM:y\n@PG\tID:bwa\tVN:1\n\
+             @RG\tID:a-2\tSM:z\n@PG\tID:bwa-1\tVN:2\n@PG\tID:sort\tPP:bwa-1\n"
+        );
+        assert!(renamed_ids[0] == RenamedIds::default());
+        let renamed = |pairs: &[(&str, &str)]| pairs.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect();
+        assert_eq!(renamed_ids[1].read_groups, renamed(&[("a", "a-2")]));
+        assert_eq!(renamed_ids[1].programs, renamed(&[("bwa", "bwa-1")]));
+
+        let mut rec = GbamRecord { tags: Some(Vec::new()), ..Default::default() };
+        rec.set_string_tag(b"RG", b"a");
+        rename_tag(&mut rec, b"RG", &renamed_ids[1].read_groups);
+        assert_eq!(rec.get_string_tag(b"RG"), Some(&b"a-2"[..]));
+        rename_tag(&mut rec, b"RG", &renamed_ids[1].read_groups);
+        assert_eq!(rec.get_string_tag(b"RG"), Some(&b"a-2"[..]));
+    }
+}
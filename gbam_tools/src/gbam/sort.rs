@@ -0,0 +1,148 @@
+//! Sorting of GBAM files without converting them back to BAM.
+use crate::bam::bam_to_gbam::{set_header_sort_order, STATS_FIELDS};
+use crate::meta::SortOrder;
+use crate::reader::parallel::par_map_reduce;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::{Writer, MEGA_BYTE_SIZE};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+
+/// Approximate amount of memory used to buffer records while gathering them.
+const MEM_LIMIT: usize = 2000 * MEGA_BYTE_SIZE;
+
+const BAM_FREVERSE: u16 = 16;
+
+/// Sort key of record, same as in BAM sort by coordinates and strand. Unmapped
+/// reads (-1) are located at the end. Record number keeps the sort stable.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct CoordinateKey {
+    ref_id: u32,
+    pos: u32,
+    reverse: bool,
+    rec_num: u32,
+}
+
+/// Reads only RefID, Pos and Flags columns and returns record numbers in
+/// coordinate sorted order.
+fn coordinate_permutation(file: &File) -> Vec<u32> {
+    let tmplt = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::Flags]);
+    let mut keys = par_map_reduce(
+        file,
+        tmplt,
+        |reader, records_range| {
+            let mut rec = GbamRecord::default();
+            records_range
+                .map(|rec_num| {
+                    reader.fill_record(rec_num, &mut rec);
+                    CoordinateKey {
+                        ref_id: rec.refid.unwrap() as u32,
+                        pos: rec.pos.unwrap() as u32,
+                        reverse: rec.flag.unwrap() & BAM_FREVERSE != 0,
+                        rec_num: rec_num as u32,
+                    }
+                })
+                .collect::<Vec<CoordinateKey>>()
+        },
+        |mut a, mut b| {
+            a.append(&mut b);
+            a
+        },
+    );
+    keys.par_sort_unstable();
+    keys.into_iter().map(|key| key.rec_num).collect()
+}
+
+/// Estimates size of record in BAM format by uncompressed sizes of columns.
+fn average_record_size(reader: &Reader) -> usize {
+    let total: u64 = Fields::iterator()
+        .flat_map(|field| reader.file_meta.view_blocks(field))
+        .map(|block| block.uncompressed_size)
+        .sum();
+    // block_size and l_seq are not stored.
+    total as usize / std::cmp::max(reader.amount, 1) + 8
+}
+
+/// Writes records of `reader` into `writer` in order of `permutation`, where
+/// `permutation[i]` is the physical number of i-th output record. Records are
+/// gathered in windows which fit in memory; records of a window are read in
+/// physical order, so every block is decompressed at most once per window.
+/// Reader parsing template has to include all fields and reader must not have
+/// index mapping.
+pub(crate) fn write_permuted<W: Write + Seek>(reader: &mut Reader, permutation: &[u32], writer: &mut Writer<W>) {
+    assert!(!reader.is_index_mapped());
+    let window_size = std::cmp::max(1, MEM_LIMIT / average_record_size(reader));
+
+    let mut rec = GbamRecord::default();
+    let mut buf = Vec::new();
+    let mut data = Vec::new();
+    for window in permutation.chunks(window_size) {
+        let mut order: Vec<(u32, usize)> = window.iter().enumerate().map(|(slot, &src)| (src, slot)).collect();
+        order.sort_unstable();
+
+        data.clear();
+        let mut slots = vec![0..0; window.len()];
+        for (src, slot) in order {
+            reader.fill_record(src as usize, &mut rec);
+            rec.convert_to_bytes(&mut buf);
+            slots[slot] = data.len()..data.len() + buf.len();
+            data.extend_from_slice(&buf);
+        }
+        for slot in slots {
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(&data[slot])));
+        }
+    }
+}
+
+/// Creates writer for coordinate sorted copy of file read by `reader`.
+pub(crate) fn sorted_writer(reader: &Reader, out_path: &str, full_command: String) -> Writer<BufWriter<File>> {
+    let fout = File::create(out_path).expect("failed");
+    let file_meta = &reader.file_meta;
+    let mut stats_fields = STATS_FIELDS.to_vec();
+    stats_fields.push(Fields::Pos);
+    Writer::new(
+        BufWriter::new(fout),
+        vec![*file_meta.get_field_codec(&Fields::RefID); FIELDS_NUM],
+        8,
+        stats_fields,
+        file_meta.get_ref_seqs().clone(),
+        set_header_sort_order(file_meta.get_sam_header(), SortOrder::Coordinate),
+        full_command,
+        SortOrder::Coordinate,
+    )
+}
+
+/// Sorts GBAM file by coordinates and strand into new GBAM file. Only key
+/// columns are read to compute the order, then records are gathered in the
+/// sorted order.
+pub fn sort_gbam(in_path: &str, out_path: &str, full_command: String) {
+    let file = File::open(in_path).unwrap();
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::new(file.try_clone().unwrap(), template).unwrap();
+
+    let permutation = coordinate_permutation(&file);
+
+    let mut writer = sorted_writer(&reader, out_path, full_command);
+    write_permuted(&mut reader, &permutation, &mut writer);
+    writer.finish().unwrap();
+}
+
+/// Writes physically sorted copy of file which was sorted with index sort, in
+/// order of its index file (`.gbai`). Memory usage is bounded, apart from the
+/// index itself.
+pub fn materialize_index_sort(in_path: &str, index: &[u32], out_path: &str, full_command: String) {
+    let file = File::open(in_path).unwrap();
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::new(file, template).unwrap();
+    assert_eq!(index.len(), reader.amount, "Index file does not match GBAM file.");
+
+    let mut writer = sorted_writer(&reader, out_path, full_command);
+    write_permuted(&mut reader, index, &mut writer);
+    writer.finish().unwrap();
+}
@@ -0,0 +1,461 @@
+//! Splitting of GBAM file by reference sequence, read group or record count.
+use crate::bam::bam_to_gbam::{build_sam_header, sam_header_text, set_header_sort_order, STATS_FIELDS};
+use crate::compressor::compress;
+use crate::meta::{BlockMeta, FileInfo, FileMeta, SortOrder, Stat, FILE_INFO_SIZE};
+use crate::name_filter::{self, NameFilterMeta};
+use crate::reader::column::{block_ranges, read_block};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::writer::{fixed_field_value, write_meta};
+use crate::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{field_type, is_data_field, var_size_field_to_index, FieldType, Fields, FIELDS_NUM};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Compression threads per output file when records are routed into several
+/// outputs at once.
+const ROUTING_THREAD_NUM: usize = 2;
+
+/// How to split GBAM file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitBy {
+    /// One file per reference sequence, plus one for unmapped reads.
+    Reference,
+    /// One file per value of RG tag, plus one for reads without it.
+    ReadGroup,
+    /// Files of N records.
+    Records(usize),
+}
+
+impl FromStr for SplitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reference" => Ok(SplitBy::Reference),
+            "rg" => Ok(SplitBy::ReadGroup),
+            _ => match s.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(SplitBy::Records(n)),
+                _ => Err(format!("Unknown split mode {}. Possible values: reference, rg, number of records.", s)),
+            },
+        }
+    }
+}
+
+/// Random access to items of fixed sized column, keeps one decompressed block.
+struct FixedItems<'a> {
+    reader: &'a Reader,
+    field: Fields,
+    item_size: usize,
+    ranges: Vec<Range<usize>>,
+    block: Option<(usize, Vec<u8>)>,
+}
+
+impl<'a> FixedItems<'a> {
+    fn new(reader: &'a Reader, field: Fields) -> Self {
+        Self {
+            reader,
+            field,
+            item_size: reader.file_meta.get_field_size(&field).unwrap() as usize,
+            ranges: block_ranges(&reader.file_meta, &field),
+            block: None,
+        }
+    }
+
+    fn value(&mut self, rec_num: usize) -> i32 {
+        let block_num = self.ranges.partition_point(|range| range.end <= rec_num);
+        if self.block.as_ref().map(|(num, _)| *num) != Some(block_num) {
+            let block = &self.reader.file_meta.view_blocks(&self.field)[block_num];
+            self.block = Some((block_num, read_block(self.reader.source.as_ref(), &self.reader.file_meta, &self.field, block)));
+        }
+        let offset = (rec_num - self.ranges[block_num].start) * self.item_size;
+        fixed_field_value(&self.block.as_ref().unwrap().1[offset..offset + self.item_size])
+    }
+}
+
+/// Copies record ranges of columns into new file. Blocks which lie entirely
+/// within the range are copied verbatim, boundary blocks are decompressed, cut
+/// and compressed again.
+struct RangeCopier<'a, W: Write + Seek> {
+    reader: &'a Reader,
+    out: W,
+    file_meta: FileMeta,
+}
+
+impl<'a, W: Write + Seek> RangeCopier<'a, W> {
+    fn copy_block(&mut self, field: &Fields, block: &BlockMeta) {
+        let mut block = block.clone();
+        let start = block.seekpos as usize;
+        block.seekpos = self.out.stream_position().unwrap();
+        self.out.write_all(&self.reader.source.read_at(start, block.block_size as usize).unwrap()).unwrap();
+        if let Some(filter) = block.name_filter.as_mut() {
+            let start = filter.seekpos as usize;
+            filter.seekpos = self.out.stream_position().unwrap();
+            self.out.write_all(&self.reader.source.read_at(start, filter.size as usize).unwrap()).unwrap();
+        }
+        self.file_meta.get_blocks(field).push(block);
+    }
+
+    fn write_block(&mut self, field: &Fields, data: &[u8], numitems: usize, stats: Option<Stat>, filter: Option<Vec<u8>>) {
+        let compressed = compress(data, Vec::new(), *self.reader.file_meta.get_field_codec(field));
+        let seekpos = self.out.stream_position().unwrap();
+        self.out.write_all(&compressed).unwrap();
+        let name_filter = filter.map(|bits| {
+            let meta = NameFilterMeta {
+                seekpos: self.out.stream_position().unwrap(),
+                size: bits.len() as u32,
+                num_hashes: name_filter::NUM_HASHES,
+            };
+            self.out.write_all(&bits).unwrap();
+            meta
+        });
+        self.file_meta.get_blocks(field).push(BlockMeta {
+            seekpos,
+            numitems: numitems as u32,
+            block_size: compressed.len() as u32,
+            uncompressed_size: data.len() as u64,
+            stats,
+            name_filter,
+        });
+    }
+
+    /// Copies fixed sized column. Values of records within `shift` range are
+    /// decreased by shift (used for index columns, whose first data block was
+    /// cut).
+    fn copy_fixed(&mut self, field: &Fields, range: Range<usize>, shift: Option<(Range<usize>, u32)>) {
+        let file_meta = self.reader.file_meta.clone();
+        let item_size = file_meta.get_field_size(field).unwrap() as usize;
+        let mut ref_ids = FixedItems::new(self.reader, Fields::RefID);
+
+        for (block, block_range) in file_meta.view_blocks(field).iter().zip(block_ranges(&file_meta, field)) {
+            let lo = std::cmp::max(range.start, block_range.start);
+            let hi = std::cmp::min(range.end, block_range.end);
+            if lo >= hi {
+                continue;
+            }
+            let shifted = shift.as_ref().is_some_and(|(shift_range, _)| shift_range.start < hi && lo < shift_range.end);
+            if lo == block_range.start && hi == block_range.end && !shifted {
+                self.copy_block(field, block);
+                continue;
+            }
+
+            let data = read_block(self.reader.source.as_ref(), &self.reader.file_meta, field, block);
+            let mut cut = data[(lo - block_range.start) * item_size..(hi - block_range.start) * item_size].to_vec();
+            if let Some((shift_range, shift)) = &shift {
+                for (rec_num, item) in (lo..hi).zip(cut.chunks_mut(item_size)) {
+                    if shift_range.contains(&rec_num) {
+                        let value = fixed_field_value(item) as u32 - shift;
+                        item.copy_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+            let stats = block.stats.as_ref().map(|block_stat| {
+                let mut stat = Stat::new_for(field);
+                for (rec_num, item) in (lo..hi).zip(cut.chunks(item_size)) {
+                    let value = fixed_field_value(item);
+                    stat.update(value);
+                    if *field == Fields::Pos {
+                        stat.update_key(ref_ids.value(rec_num), value);
+                    }
+                }
+                // Alignment ends need CIGAR, the bound of the whole block is
+                // still valid for its part.
+                stat.max_end_key = block_stat.max_end_key;
+                stat
+            });
+            self.write_block(field, &cut, hi - lo, stats, None);
+        }
+    }
+
+    /// Copies variable sized column together with its index column.
+    fn copy_variable(&mut self, field: &Fields, range: Range<usize>) {
+        let file_meta = self.reader.file_meta.clone();
+        let index_field = var_size_field_to_index(field);
+        let mut offsets = FixedItems::new(self.reader, index_field);
+        let mut shift = None;
+
+        for (block, block_range) in file_meta.view_blocks(field).iter().zip(block_ranges(&file_meta, field)) {
+            let lo = std::cmp::max(range.start, block_range.start);
+            let hi = std::cmp::min(range.end, block_range.end);
+            if lo >= hi {
+                continue;
+            }
+            if lo == block_range.start && hi == block_range.end {
+                self.copy_block(field, block);
+                continue;
+            }
+
+            let data = read_block(self.reader.source.as_ref(), &self.reader.file_meta, field, block);
+            let start = match lo == block_range.start {
+                true => 0,
+                false => offsets.value(lo - 1) as usize,
+            };
+            let ends: Vec<usize> = (lo..hi).map(|rec_num| offsets.value(rec_num) as usize).collect();
+            if start > 0 {
+                shift = Some((lo..hi, start as u32));
+            }
+            let items = || {
+                std::iter::once(start)
+                    .chain(ends.iter().copied())
+                    .zip(ends.iter().copied())
+                    .map(|(item_start, item_end)| &data[item_start..item_end])
+            };
+            let stats = block.stats.as_ref().map(|_| {
+                let mut stat = Stat::new_for(field);
+                items().for_each(|item| stat.update(item.len() as i32));
+                stat
+            });
+            let filter = block.name_filter.as_ref().map(|_| {
+                let hashes: Vec<u64> = items().map(|item| name_filter::hash_name(name_filter::strip_nul(item))).collect();
+                name_filter::build_filter(&hashes)
+            });
+            self.write_block(field, &data[start..*ends.last().unwrap()], hi - lo, stats, filter);
+        }
+
+        self.copy_fixed(&index_field, range, shift);
+    }
+}
+
+/// Writes records within range into new GBAM file, copying compressed blocks
+/// where possible.
+fn copy_record_range(reader: &Reader, range: Range<usize>, out_path: &str, sam_header: Vec<u8>, sort_order: SortOrder, full_command: &str) {
+    let mut out = BufWriter::new(create_file(out_path));
+    out.seek(SeekFrom::Start(FILE_INFO_SIZE as u64)).unwrap();
+    let file_meta = FileMeta::new(
+        *reader.file_meta.get_field_codec(&Fields::RefID),
+        reader.file_meta.get_ref_seqs().clone(),
+        sam_header,
+    );
+    let mut copier = RangeCopier { reader, out, file_meta };
+    for field in Fields::iterator().filter(|f| is_data_field(f)) {
+        match field_type(field) {
+            FieldType::FixedSized => copier.copy_fixed(field, range.clone(), None),
+            FieldType::VariableSized => copier.copy_variable(field, range.clone()),
+        }
+    }
+    let mut file_info = FileInfo::new([1, 0], 0, 0, full_command.to_owned(), sort_order);
+    write_meta(&mut copier.out, &copier.file_meta, &mut file_info).unwrap();
+}
+
+/// Finds record ranges of every reference in physically sorted file. Blocks
+/// which contain single reference according to RefID stats are not
+/// decompressed.
+fn reference_ranges(reader: &Reader) -> Vec<(i32, Range<usize>)> {
+    let mut ranges: Vec<(i32, Range<usize>)> = Vec::new();
+    let mut push_run = |ref_id: i32, run: Range<usize>| match ranges.last_mut() {
+        Some((last_id, last_run)) if *last_id == ref_id && last_run.end == run.start => last_run.end = run.end,
+        _ => ranges.push((ref_id, run)),
+    };
+    let mut ref_ids = FixedItems::new(reader, Fields::RefID);
+    let file_meta = &reader.file_meta;
+    for (block, block_range) in file_meta.view_blocks(&Fields::RefID).iter().zip(block_ranges(file_meta, &Fields::RefID)) {
+        match &block.stats {
+            Some(stats) if stats.min_value == stats.max_value => push_run(stats.min_value, block_range),
+            _ => block_range.for_each(|rec_num| push_run(ref_ids.value(rec_num), rec_num..rec_num + 1)),
+        }
+    }
+    ranges
+}
+
+/// Name of reference sequence, `None` for unmapped reads.
+fn reference_name(reader: &Reader, ref_id: i32) -> Option<&str> {
+    match ref_id {
+        -1 => None,
+        _ => Some(&reader.file_meta.get_ref_seqs()[ref_id as usize].0),
+    }
+}
+
+fn create_file(path: &str) -> File {
+    File::create(path).unwrap_or_else(|e| panic!("Can't create output file {}: {}", path, e))
+}
+
+/// Replaces characters outside of `[A-Za-z0-9._-]` with `_`, so that names
+/// from the file can't point outside of output directory.
+fn file_name_part(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '_' })
+        .collect()
+}
+
+/// Output paths of per reference and per read group splits. Different names
+/// may give the same path after sanitizing, such files are rejected instead of
+/// being mixed.
+struct OutputPaths<'a> {
+    prefix: &'a str,
+    /// Part of path for records without reference or read group.
+    missing_part: &'static str,
+    /// Path -> name it was created for.
+    used: HashMap<String, Option<String>>,
+}
+
+impl<'a> OutputPaths<'a> {
+    fn new(prefix: &'a str, missing_part: &'static str) -> Self {
+        OutputPaths { prefix, missing_part, used: HashMap::new() }
+    }
+
+    fn path(&mut self, name: Option<&str>) -> String {
+        let part = name.map_or_else(|| self.missing_part.to_owned(), file_name_part);
+        let path = format!("{}.{}.gbam", self.prefix, part);
+        match self.used.entry(path.clone()) {
+            Entry::Occupied(entry) if entry.get().as_deref() != name => {
+                let missing_part = self.missing_part;
+                let describe = |name: Option<&str>| name.map_or_else(|| format!("records marked as {}", missing_part), |name| format!("'{}'", name));
+                panic!("Both {} and {} would be written into {}.", describe(entry.get().as_deref()), describe(name), path);
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(entry) => {
+                entry.insert(name.map(str::to_owned));
+            }
+        }
+        path
+    }
+}
+
+/// Keeps only @RG line of the read group in SAM header.
+fn header_with_read_group(reader: &Reader, read_group: Option<&str>) -> Vec<u8> {
+    let text: String = sam_header_text(reader.file_meta.get_sam_header())
+        .lines()
+        .filter(|line| !line.starts_with("@RG") || read_group.is_some_and(|rg| line.split('\t').any(|tag| tag.strip_prefix("ID:") == Some(rg))))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    build_sam_header(&text, reader.file_meta.get_ref_seqs())
+}
+
+/// Decodes records and routes them into writers created on first use, one per
+/// output name (`None` for records without reference or read group).
+fn route_records<F, H>(reader: &mut Reader, paths: &mut OutputPaths, sort_order: SortOrder, full_command: &str, mut output_name: F, header_for: H)
+where
+    F: FnMut(&GbamRecord) -> Option<String>,
+    H: Fn(Option<&str>) -> Vec<u8>,
+{
+    let mut stats_fields = STATS_FIELDS.to_vec();
+    if sort_order == SortOrder::Coordinate {
+        stats_fields.push(Fields::Pos);
+    }
+    let codec = *reader.file_meta.get_field_codec(&Fields::RefID);
+    let ref_seqs = reader.file_meta.get_ref_seqs().clone();
+
+    let mut writers: HashMap<Option<String>, Writer<BufWriter<File>>> = HashMap::new();
+    let mut rec = GbamRecord::default();
+    let mut buf = Vec::new();
+    for rec_num in 0..reader.amount {
+        reader.fill_record(rec_num, &mut rec);
+        let writer = writers.entry(output_name(&rec)).or_insert_with_key(|name| {
+            Writer::new(
+                BufWriter::new(create_file(&paths.path(name.as_deref()))),
+                vec![codec; FIELDS_NUM],
+                ROUTING_THREAD_NUM,
+                stats_fields.clone(),
+                ref_seqs.clone(),
+                set_header_sort_order(&header_for(name.as_deref()), sort_order),
+                full_command.to_owned(),
+                sort_order,
+            )
+        });
+        rec.convert_to_bytes(&mut buf);
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+    }
+    for writer in writers.values_mut() {
+        writer.finish().unwrap();
+    }
+}
+
+/// Splits GBAM file into files named `<out_prefix>.<part>.gbam`, where part is
+/// reference name (or `unmapped`), read group (or `no_rg`), or number of the
+/// part. Characters of names outside of `[A-Za-z0-9._-]` are replaced with `_`.
+/// Records are kept in physical order. Per reference and per record
+/// count splits of physically sorted files copy compressed blocks where
+/// possible, other splits decode records.
+pub fn split_gbam(in_path: &str, out_prefix: &str, split_by: SplitBy, full_command: String) {
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::new(File::open(in_path).unwrap(), template).unwrap();
+    let sort_order = reader.physical_sort_order();
+    let sam_header = set_header_sort_order(reader.file_meta.get_sam_header(), sort_order);
+
+    match split_by {
+        SplitBy::Records(n) => {
+            for (part, start) in (0..reader.amount).step_by(n).enumerate() {
+                let range = start..std::cmp::min(start + n, reader.amount);
+                let out_path = format!("{}.{}.gbam", out_prefix, part);
+                copy_record_range(&reader, range, &out_path, sam_header.clone(), sort_order, &full_command);
+            }
+        }
+        SplitBy::Reference if sort_order == SortOrder::Coordinate => {
+            let mut paths = OutputPaths::new(out_prefix, "unmapped");
+            for (ref_id, range) in reference_ranges(&reader) {
+                let out_path = paths.path(reference_name(&reader, ref_id));
+                copy_record_range(&reader, range, &out_path, sam_header.clone(), sort_order, &full_command);
+            }
+        }
+        SplitBy::Reference => {
+            let names: Vec<Option<String>> = (-1..reader.file_meta.get_ref_seqs().len() as i32)
+                .map(|ref_id| reference_name(&reader, ref_id).map(str::to_owned))
+                .collect();
+            let header = reader.file_meta.get_sam_header().to_vec();
+            let mut paths = OutputPaths::new(out_prefix, "unmapped");
+            route_records(&mut reader, &mut paths, sort_order, &full_command, |rec| names[(rec.refid.unwrap() + 1) as usize].clone(), |_| header.clone());
+        }
+        SplitBy::ReadGroup => {
+            let headers: HashMap<String, Vec<u8>> = sam_header_text(reader.file_meta.get_sam_header())
+                .lines()
+                .filter(|line| line.starts_with("@RG"))
+                .filter_map(|line| line.split('\t').find_map(|tag| tag.strip_prefix("ID:")))
+                .map(|rg| (rg.to_owned(), header_with_read_group(&reader, Some(rg))))
+                .collect();
+            let no_rg_header = header_with_read_group(&reader, None);
+            route_records(
+                &mut reader,
+                &mut OutputPaths::new(out_prefix, "no_rg"),
+                sort_order,
+                &full_command,
+                |rec| rec.get_string_tag(b"RG").map(|rg| String::from_utf8_lossy(rg).into_owned()),
+                |name| name.and_then(|rg| headers.get(rg)).unwrap_or(&no_rg_header).clone(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_by_from_str() {
+        assert_eq!("reference".parse::<SplitBy>(), Ok(SplitBy::Reference));
+        assert_eq!("rg".parse::<SplitBy>(), Ok(SplitBy::ReadGroup));
+        assert_eq!("1000".parse::<SplitBy>(), Ok(SplitBy::Records(1000)));
+        assert!("0".parse::<SplitBy>().is_err());
+    }
+
+    #[test]
+    fn test_output_paths() {
+        let mut paths = OutputPaths::new("out/sample", "no_rg");
+        assert_eq!(paths.path(Some("../lane 1:A")), "out/sample..._lane_1_A.gbam");
+        assert_eq!(paths.path(Some("../lane 1:A")), "out/sample..._lane_1_A.gbam");
+        assert_eq!(paths.path(None), "out/sample.no_rg.gbam");
+        assert_eq!(paths.path(Some("rg-1.a_b")), "out/sample.rg-1.a_b.gbam");
+    }
+
+    #[test]
+    #[should_panic(expected = "would be written into out.lane_1_A.gbam")]
+    fn test_output_paths_collision() {
+        let mut paths = OutputPaths::new("out", "no_rg");
+        paths.path(Some("lane 1:A"));
+        paths.path(Some("lane 1/A"));
+    }
+
+    #[test]
+    #[should_panic(expected = "records marked as no_rg")]
+    fn test_output_paths_missing_collision() {
+        let mut paths = OutputPaths::new("out", "no_rg");
+        paths.path(Some("no_rg"));
+        paths.path(None);
+    }
+}
@@ -0,0 +1,120 @@
+//! Random subsampling of reads, like `samtools view -s`.
+use crate::bam::bam_to_gbam::{set_header_sort_order, STATS_FIELDS};
+use crate::meta::SortOrder;
+use crate::name_filter::strip_nul;
+use crate::reader::parallel::par_map_reduce;
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::Writer;
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{Fields, FIELDS_NUM};
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+
+/// X31 string hash of htslib (`__ac_X31_hash_string`).
+fn x31_hash(name: &[u8]) -> u32 {
+    match name.split_first() {
+        Some((&first, rest)) => rest
+            .iter()
+            .fold(first as u32, |hash, &byte| (hash << 5).wrapping_sub(hash).wrapping_add(byte as u32)),
+        None => 0,
+    }
+}
+
+/// Integer hash of htslib (`__ac_Wang_hash`).
+fn wang_hash(mut key: u32) -> u32 {
+    key = key.wrapping_add(!(key << 15));
+    key ^= key >> 10;
+    key = key.wrapping_add(key << 3);
+    key ^= key >> 6;
+    key = key.wrapping_add(!(key << 11));
+    key ^= key >> 16;
+    key
+}
+
+/// Decides whether read is kept. Depends on read name only, so all alignments
+/// of a template are kept or dropped together. Same selection as `samtools
+/// view -s` with zero seed; samtools scrambles non-zero seeds with `rand()`,
+/// here they are used as is.
+fn is_kept(name: &[u8], fraction: f64, seed: u32) -> bool {
+    let hash = wang_hash(x31_hash(name) ^ seed);
+    ((hash & 0xffffff) as f64 / 0x1000000 as f64) < fraction
+}
+
+/// Reads only ReadName column and returns physical numbers of kept records in
+/// ascending order.
+fn kept_records(file: &File, fraction: f64, seed: u32) -> Vec<u32> {
+    par_map_reduce(
+        file,
+        ParsingTemplate::new_with(&[Fields::ReadName]),
+        |reader, records_range| {
+            let mut rec = GbamRecord::default();
+            records_range
+                .filter(|&rec_num| {
+                    reader.fill_record(rec_num, &mut rec);
+                    is_kept(strip_nul(rec.read_name.as_ref().unwrap()), fraction, seed)
+                })
+                .map(|rec_num| rec_num as u32)
+                .collect::<Vec<u32>>()
+        },
+        |mut a, mut b| {
+            a.append(&mut b);
+            a
+        },
+    )
+}
+
+/// Writes approximately `fraction` of reads of GBAM file into new GBAM file,
+/// keeping mates together. Read names are read first, then kept records are
+/// streamed into the new file in their order.
+pub fn subsample_gbam(in_path: &str, out_path: &str, fraction: f64, seed: u32, full_command: String) {
+    assert!((0.0..=1.0).contains(&fraction), "Fraction must be between 0 and 1.");
+    let file = File::open(in_path).unwrap();
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let mut reader = Reader::new(file.try_clone().unwrap(), template).unwrap();
+
+    let kept = kept_records(&file, fraction, seed);
+
+    let file_meta = &reader.file_meta;
+    let sort_order = reader.physical_sort_order();
+    let mut stats_fields = STATS_FIELDS.to_vec();
+    if sort_order == SortOrder::Coordinate {
+        stats_fields.push(Fields::Pos);
+    }
+    let mut writer = Writer::new(
+        BufWriter::new(File::create(out_path).expect("failed")),
+        vec![*file_meta.get_field_codec(&Fields::RefID); FIELDS_NUM],
+        8,
+        stats_fields,
+        file_meta.get_ref_seqs().clone(),
+        set_header_sort_order(file_meta.get_sam_header(), sort_order),
+        full_command,
+        sort_order,
+    );
+    let mut rec = GbamRecord::default();
+    let mut buf = Vec::new();
+    for &rec_num in &kept {
+        reader.fill_record(rec_num as usize, &mut rec);
+        rec.convert_to_bytes(&mut buf);
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+    }
+    writer.finish().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_kept() {
+        let names: Vec<String> = (0..10000).map(|i| format!("read_{}", i)).collect();
+        let kept = names.iter().filter(|n| is_kept(n.as_bytes(), 0.25, 0)).count();
+        assert!((2000..3000).contains(&kept));
+        assert!(names.iter().all(|n| is_kept(n.as_bytes(), 1.0, 7)));
+        assert!(!names.iter().any(|n| is_kept(n.as_bytes(), 0.0, 7)));
+        // Selection with smaller fraction is a subset of selection with larger one.
+        assert!(names.iter().filter(|n| is_kept(n.as_bytes(), 0.1, 3)).all(|n| is_kept(n.as_bytes(), 0.2, 3)));
+    }
+}
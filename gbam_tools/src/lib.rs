@@ -10,34 +10,72 @@ pub mod bam {
     pub mod gbam_to_bam;
 }
 
+pub mod gbam {
+    /// Concatenation of GBAM files
+    pub mod cat;
+    /// Merge of sorted GBAM files
+    pub mod merge;
+    /// GBAM to GBAM sort
+    pub mod sort;
+    /// Split of GBAM files
+    pub mod split;
+    /// Random subsampling of reads
+    pub mod subsample;
+}
+
 pub mod utils {
     /// BED reader
     pub mod bed;
+    /// bigWig writer
+    pub mod bigwig;
+    /// FASTA reader
+    pub mod fasta;
 }
 
 pub mod reader {
+    /// Columnar batches of records
+    pub mod batch;
     pub(crate) mod column;
+    /// Parallel processing of records
+    pub mod parallel;
     pub mod parse_tmplt;
     /// GBAM reader
     #[allow(clippy::module_inception)]
     pub mod reader;
     pub mod record;
     pub mod records;
+    /// Random access byte sources of GBAM files
+    pub mod source;
 
 }
 
-#[cfg(not(feature = "python-ffi"))]
 pub mod query {
     pub mod cigar;
     pub mod depth;
     pub mod flagstat;
     pub mod int2str;
+    pub mod mpileup;
+    pub mod pileup;
 }
 
+/// C API
+#[cfg(feature = "c-api")]
+mod capi;
 /// Manages parallel compression
 mod compressor;
+/// Python bindings
+#[cfg(feature = "python-ffi")]
+mod ffi;
+/// Export of GBAM columns into Apache Arrow
+#[cfg(feature = "arrow")]
+pub mod arrow_export;
 /// Meta information for GBAM file
 pub mod meta;
+/// Export of GBAM files into Parquet and import back
+#[cfg(feature = "parquet")]
+pub mod parquet_io;
+/// Read name filter for lookups by read name
+pub mod name_filter;
 /// Manages stats collection
 mod stats;
 /// GBAM writer
@@ -57,44 +95,3 @@ const MEGA_BYTE_SIZE: usize = 1_048_576;
 /// 16777216 bytes
 const SIZE_LIMIT: usize = 8 * MEGA_BYTE_SIZE;
 static GBAM_MAGIC: &[u8] = b"geeBAM10";
-
-#[cfg(feature = "python-ffi")]
-mod ffi {
-    use crate::{
-        Codecs, {bam_sort_to_gbam, bam_to_gbam},
-    };
-
-    use crate::reader::{parse_tmplt, record, records};
-
-    use pyo3::prelude::*;
-    use pyo3::wrap_pyfunction;
-
-    /// Workaround, since it seems wrap_pyfunction cant access another module namespace.
-    #[pyfunction]
-    pub fn bam_to_gbam_python(in_path: String, out_path: String, codec_str: String, sort: bool) {
-        let codec = match &codec_str[..] {
-            "gzip" => Codecs::Gzip,
-            "lz4" => Codecs::Lz4,
-            _ => panic!("Codec <{}> is not supported.", codec_str),
-        };
-        if sort {
-            bam_sort_to_gbam(&in_path, &out_path, codec);
-        } else {
-            bam_to_gbam(&in_path, &out_path, codec);
-        }
-    }
-
-    #[pyfunction]
-    pub fn test() {}
-
-    #[pymodule]
-    fn gbam_tools(_: Python, m: &PyModule) -> PyResult<()> {
-        m.add_function(wrap_pyfunction!(test, m)?).unwrap();
-        m.add_function(wrap_pyfunction!(bam_to_gbam_python, m)?)
-            .unwrap();
-        m.add_class::<records::PyRecords>()?;
-        m.add_class::<parse_tmplt::ParsingTemplate>()?;
-        m.add_class::<record::GbamRecord>()?;
-        Ok(())
-    }
-}
@@ -1,4 +1,5 @@
 use super::GBAM_MAGIC;
+use crate::name_filter::NameFilterMeta;
 use bam_tools::record::fields::{field_item_size, Fields, FIELDS_NUM};
 use serde::ser::{SerializeMap, Serializer};
 use serde::{Deserialize, Deserializer, Serialize};
@@ -8,6 +9,45 @@ use serde::de::{MapAccess, Visitor};
 // use serde::de::{Deserialize, Deserializer};
 // use serde_json::Result;
 use std::collections::HashMap;
+use std::ops::Range;
+use std::str::FromStr;
+
+/// Order of records in GBAM file.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    Unsorted,
+    /// By reference and coordinates, unmapped reads at the end.
+    Coordinate,
+    /// By read name.
+    QueryName,
+    /// Alignments with the same read name are adjacent, mates are matched.
+    Collate,
+}
+
+impl SortOrder {
+    /// Returns SO (and GO) values of SAM header @HD line.
+    pub fn header_tags(&self) -> &'static str {
+        match self {
+            SortOrder::Unsorted => "SO:unsorted",
+            SortOrder::Coordinate => "SO:coordinate",
+            SortOrder::QueryName => "SO:queryname",
+            SortOrder::Collate => "SO:unsorted\tGO:query",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "coordinate" => Ok(SortOrder::Coordinate),
+            "name" | "queryname" => Ok(SortOrder::QueryName),
+            "collate" => Ok(SortOrder::Collate),
+            _ => Err(format!("Unknown sort order {}. Possible values: coordinate, name, collate.", s)),
+        }
+    }
+}
 
 /// Holds data related to GBAM file: gbam version, seekpos to meta.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -16,19 +56,48 @@ pub(crate) struct FileInfo {
     pub gbam_version: [u32; 2],
     pub seekpos: u64,
     pub crc32: u32,
+    /// Whether file is sorted by coordinate.
     pub is_sorted: bool,
     pub creation_command: String,
+    /// Absent in files written before sort orders other than coordinate were
+    /// supported.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sort_order: Option<SortOrder>,
+    /// Whether coordinate order is kept only in index file, records are
+    /// physically unsorted. Absent in files written before it was recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_sorted: Option<bool>,
 }
 
 impl FileInfo {
-    pub fn new(gbam_version: [u32; 2], seekpos: u64, crc32: u32, full_command: String, is_sorted: bool) -> Self {
+    pub fn new(gbam_version: [u32; 2], seekpos: u64, crc32: u32, full_command: String, sort_order: SortOrder) -> Self {
         FileInfo {
             magic: String::from_utf8(GBAM_MAGIC.to_owned()).unwrap(),
             gbam_version,
             seekpos,
             crc32,
             creation_command: full_command,
-            is_sorted
+            is_sorted: sort_order == SortOrder::Coordinate,
+            sort_order: Some(sort_order),
+            index_sorted: Some(false),
+        }
+    }
+
+    pub fn sort_order(&self) -> SortOrder {
+        match self.sort_order {
+            Some(sort_order) => sort_order,
+            None if self.is_sorted => SortOrder::Coordinate,
+            None => SortOrder::Unsorted,
+        }
+    }
+
+    /// Order of records as they are physically laid out. Files which don't
+    /// record whether they are index sorted are taken as index sorted only if
+    /// they are opened with index file (`index_mapped`).
+    pub fn physical_sort_order(&self, index_mapped: bool) -> SortOrder {
+        match self.sort_order() {
+            SortOrder::Coordinate if self.index_sorted.unwrap_or(index_mapped) => SortOrder::Unsorted,
+            sort_order => sort_order,
         }
     }
 }
@@ -38,7 +107,7 @@ pub const FILE_INFO_SIZE: usize = 1000;
 
 /// Type of encoding used in GBAM writer
 /// TODO: use MessagePack or another compact form of serialization.
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Codecs {
     /// Gzip encoding
     Gzip,
@@ -49,16 +118,94 @@ pub enum Codecs {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
-/// Currently block stats only for RefID or POS are supported.
+/// Block stats. For fixed sized fields min and max are collected on field
+/// values, for variable sized fields on item lengths in bytes (so for RawQual
+/// it is read length). Some fields have additional summaries.
 pub struct Stat {
     pub min_value: i32,
     pub max_value: i32,
+    /// Bitwise OR of all values in block (Flags only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub or_mask: Option<u32>,
+    /// Bitwise AND of all values in block (Flags only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub and_mask: Option<u32>,
+    /// Amount of items with each value (Mapq only). Stored as (value, amount)
+    /// pairs of present values, since blocks usually have few distinct ones.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "sparse_histogram")]
+    pub histogram: Option<Vec<u32>>,
+    /// (RefID, Pos) of the first record in block (Pos only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_key: Option<(i32, i32)>,
+    /// (RefID, Pos) of the last record in block (Pos only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_key: Option<(i32, i32)>,
+    /// The largest (RefID, alignment end) of records in block, the end is
+    /// exclusive (Pos only). Absent in files written before it was collected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_end_key: Option<(i32, i32)>,
 }
 
 impl Stat {
+    /// Creates stats collector with summaries supported by the field.
+    pub fn new_for(field: &Fields) -> Self {
+        let mut stat = Stat::default();
+        match field {
+            Fields::Flags => {
+                stat.or_mask = Some(0);
+                stat.and_mask = Some(u32::MAX);
+            }
+            Fields::Mapq => stat.histogram = Some(vec![0; MAPQ_HISTOGRAM_SIZE]),
+            _ => {}
+        }
+        stat
+    }
+
     pub fn update(&mut self, val: i32) {
         self.max_value = std::cmp::max(val, self.max_value);
         self.min_value = std::cmp::min(val, self.min_value);
+        if let Some(mask) = self.or_mask.as_mut() {
+            *mask |= val as u32;
+        }
+        if let Some(mask) = self.and_mask.as_mut() {
+            *mask &= val as u32;
+        }
+        if let Some(histogram) = self.histogram.as_mut() {
+            histogram[val as usize] += 1;
+        }
+    }
+
+    /// Records (RefID, Pos) of the record. Only meaningful for files sorted by
+    /// coordinate, where start and end keys are bounds of the block.
+    pub fn update_key(&mut self, ref_id: i32, pos: i32) {
+        if self.start_key.is_none() {
+            self.start_key = Some((ref_id, pos));
+        }
+        self.end_key = Some((ref_id, pos));
+    }
+
+    /// Records (RefID, alignment end) of the record.
+    pub fn update_end_key(&mut self, ref_id: i32, end: i32) {
+        if self.max_end_key.is_none_or(|max_end_key| position_key(max_end_key) < position_key((ref_id, end))) {
+            self.max_end_key = Some((ref_id, end));
+        }
+    }
+
+    /// Checks if block may contain values within [min, max]. Used to skip blocks.
+    pub fn may_contain_range(&self, min: i32, max: i32) -> bool {
+        self.max_value >= min && self.min_value <= max
+    }
+
+    /// Checks if any item in block has any of the bits of mask set. Always true
+    /// if bitmasks were not collected.
+    pub fn any_has_bits(&self, mask: u32) -> bool {
+        self.or_mask.is_none_or(|or_mask| or_mask & mask != 0)
+    }
+
+    /// Checks if all items in block have all bits of mask set. Always false if
+    /// bitmasks were not collected.
+    pub fn all_have_bits(&self, mask: u32) -> bool {
+        self.and_mask.is_some_and(|and_mask| and_mask & mask == mask)
     }
 
     /// Checks if it's in reset state.
@@ -74,11 +221,57 @@ impl Stat {
     }
 }
 
+/// Mapq is a single byte.
+const MAPQ_HISTOGRAM_SIZE: usize = 256;
+
+mod sparse_histogram {
+    use super::MAPQ_HISTOGRAM_SIZE;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(histogram: &Option<Vec<u32>>, serializer: S) -> Result<S::Ok, S::Error> {
+        histogram
+            .as_ref()
+            .map(|histogram| {
+                histogram
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &amount)| amount > 0)
+                    .map(|(value, &amount)| (value, amount))
+                    .collect::<Vec<(usize, u32)>>()
+            })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u32>>, D::Error> {
+        let Some(pairs) = Option::<Vec<(usize, u32)>>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let mut histogram = vec![0; MAPQ_HISTOGRAM_SIZE];
+        for (value, amount) in pairs {
+            *histogram.get_mut(value).ok_or_else(|| D::Error::custom("histogram value is out of range"))? = amount;
+        }
+        Ok(Some(histogram))
+    }
+}
+
+/// Orders (RefID, Pos) keys as in sorted BAM, where unmapped reads (-1) are
+/// located at the end.
+pub(crate) fn position_key((ref_id, pos): (i32, i32)) -> (u32, u32) {
+    (ref_id as u32, pos as u32)
+}
+
 impl Default for Stat {
     fn default() -> Self { 
         Self {
             min_value:std::i32::MAX,
             max_value:std::i32::MIN,
+            or_mask: None,
+            and_mask: None,
+            histogram: None,
+            start_key: None,
+            end_key: None,
+            max_end_key: None,
         }
     }
 }
@@ -90,6 +283,9 @@ pub struct BlockMeta {
     pub block_size: u32,
     pub uncompressed_size: u64,
     pub stats: Option<Stat>,
+    /// Read name filter (ReadName only, if enabled).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_filter: Option<NameFilterMeta>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -265,4 +461,178 @@ impl FileMeta {
     pub fn get_field_codec(&self, field: &Fields) -> &Codecs {
         &self.field_to_meta[*field as usize].codec
     }
+
+    /// Returns ranges of record numbers covered by blocks of the field for
+    /// which `keep` returns true. Blocks without stats are always kept, so the
+    /// result can be used to skip blocks, e.g. ones without mapq >= 30 reads:
+    /// `meta.record_ranges_where(&Fields::Mapq, |s| s.may_contain_range(30, 255))`.
+    pub fn record_ranges_where<F>(&self, field: &Fields, keep: F) -> Vec<Range<usize>>
+    where
+        F: Fn(&Stat) -> bool,
+    {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        let mut start = 0;
+        for block in self.view_blocks(field) {
+            let end = start + block.numitems as usize;
+            if block.stats.as_ref().is_none_or(&keep) {
+                match ranges.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => ranges.push(start..end),
+                }
+            }
+            start = end;
+        }
+        ranges
+    }
+
+    /// Returns range of record numbers of the block which contains the first
+    /// record with (RefID, Pos) not less than given, so positional lookups in
+    /// coordinate sorted files can be narrowed down to a single block. If there
+    /// is no such record, the range is empty and starts at the end of file.
+    /// Returns None if the file has no position keys.
+    pub fn position_block_range(&self, ref_id: i32, pos: i32) -> Option<Range<usize>> {
+        let blocks = self.view_blocks(&Fields::Pos);
+        let key = position_key((ref_id, pos));
+        let mut ends = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            ends.push(position_key(block.stats.as_ref()?.end_key?));
+        }
+        let block_idx = ends.partition_point(|end| *end < key);
+        let start = blocks[..block_idx].iter().map(|b| b.numitems as usize).sum();
+        let end = start + blocks.get(block_idx).map_or(0, |b| b.numitems as usize);
+        Some(start..end)
+    }
+
+    /// Returns range of record numbers which contains the first record of
+    /// reference `ref_id` overlapping `pos`: from the first block with
+    /// alignments ending after `pos` up to the end of `position_block_range`
+    /// block. Files without alignment ends are searched from the start of the
+    /// reference. Returns None if the file has no position keys.
+    pub fn overlap_block_range(&self, ref_id: i32, pos: i32) -> Option<Range<usize>> {
+        let range = self.position_block_range(ref_id, pos)?;
+        let key = position_key((ref_id, pos));
+        let mut start = 0;
+        for block in self.view_blocks(&Fields::Pos) {
+            if start >= range.start {
+                break;
+            }
+            match block.stats.as_ref().and_then(|stats| stats.max_end_key) {
+                Some(max_end_key) if position_key(max_end_key) <= key => start += block.numitems as usize,
+                Some(_) => break,
+                None => return Some(self.position_block_range(ref_id, 0)?.start..range.end),
+            }
+        }
+        Some(start..range.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_physical_sort_order() {
+        let mut file_info = FileInfo::new([1, 0], 0, 0, String::new(), SortOrder::Coordinate);
+        assert_eq!(file_info.physical_sort_order(true), SortOrder::Coordinate);
+        file_info.index_sorted = Some(true);
+        assert_eq!(file_info.physical_sort_order(false), SortOrder::Unsorted);
+
+        let legacy: FileInfo = serde_json::from_str(
+            r#"{"magic":"GBAM","gbam_version":[1,0],"seekpos":0,"crc32":0,"is_sorted":true,"creation_command":""}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.physical_sort_order(false), SortOrder::Coordinate);
+        assert_eq!(legacy.physical_sort_order(true), SortOrder::Unsorted);
+
+        let unsorted = FileInfo::new([1, 0], 0, 0, String::new(), SortOrder::QueryName);
+        assert_eq!(unsorted.physical_sort_order(false), SortOrder::QueryName);
+    }
+
+    #[test]
+    fn test_block_stats() {
+        let mut flags = Stat::new_for(&Fields::Flags);
+        for flag in [0b1000011, 0b1010011] {
+            flags.update(flag);
+        }
+        assert!(flags.any_has_bits(0b10000));
+        assert!(!flags.any_has_bits(0b10000000000));
+        assert!(flags.all_have_bits(0b11));
+        assert!(!flags.all_have_bits(0b10000));
+
+        let mut mapq = Stat::new_for(&Fields::Mapq);
+        mapq.update(10);
+        mapq.update(60);
+        let histogram = mapq.histogram.as_ref().unwrap();
+        assert_eq!((histogram[10], histogram[60]), (1, 1));
+        // Only present values are stored.
+        let json = serde_json::to_string(&mapq).unwrap();
+        assert!(json.contains(r#""histogram":[[10,1],[60,1]]"#));
+        let parsed: Stat = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.histogram, mapq.histogram);
+
+        let mut meta = FileMeta::new(Codecs::Gzip, Vec::new(), Vec::new());
+        let blocks = meta.get_blocks(&Fields::Mapq);
+        for max_value in [10, 60, 60, 5] {
+            blocks.push(BlockMeta {
+                numitems: 100,
+                stats: Some(Stat { min_value: 0, max_value, ..Default::default() }),
+                ..Default::default()
+            });
+        }
+        blocks.push(BlockMeta { numitems: 50, ..Default::default() });
+        let ranges = meta.record_ranges_where(&Fields::Mapq, |s| s.may_contain_range(30, 255));
+        assert_eq!(ranges, vec![100..300, 400..450]);
+
+        let mut meta = FileMeta::new(Codecs::Gzip, Vec::new(), Vec::new());
+        for (start_key, end_key) in [((0, 5), (0, 90)), ((0, 90), (2, 10)), ((2, 15), (-1, -1))] {
+            meta.get_blocks(&Fields::Pos).push(BlockMeta {
+                numitems: 10,
+                stats: Some(Stat { start_key: Some(start_key), end_key: Some(end_key), ..Default::default() }),
+                ..Default::default()
+            });
+        }
+        assert_eq!(meta.position_block_range(0, 0), Some(0..10));
+        assert_eq!(meta.position_block_range(0, 90), Some(0..10));
+        assert_eq!(meta.position_block_range(1, 0), Some(10..20));
+        assert_eq!(meta.position_block_range(2, 11), Some(20..30));
+        assert_eq!(meta.position_block_range(-1, 0), Some(20..30));
+        // Without alignment ends the whole reference is searched.
+        assert_eq!(meta.overlap_block_range(2, 11), Some(10..30));
+
+        // Reference 0 spans five blocks, read of the second block is long.
+        let mut meta = FileMeta::new(Codecs::Gzip, Vec::new(), Vec::new());
+        for (start_key, end_key, max_end_key) in [
+            ((0, 0), (0, 100), (0, 150)),
+            ((0, 100), (0, 200), (0, 1000)),
+            ((0, 200), (0, 300), (0, 350)),
+            ((0, 300), (0, 400), (0, 450)),
+            ((0, 400), (0, 500), (0, 550)),
+        ] {
+            meta.get_blocks(&Fields::Pos).push(BlockMeta {
+                numitems: 10,
+                stats: Some(Stat {
+                    start_key: Some(start_key),
+                    end_key: Some(end_key),
+                    max_end_key: Some(max_end_key),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+        assert_eq!(meta.overlap_block_range(0, 120), Some(0..20));
+        assert_eq!(meta.overlap_block_range(0, 160), Some(10..20));
+        // The long read still overlaps position 420.
+        assert_eq!(meta.overlap_block_range(0, 420), Some(10..50));
+        assert_eq!(meta.overlap_block_range(0, 1000), Some(50..50));
+
+        let mut stat = Stat::new_for(&Fields::Pos);
+        for (ref_id, end) in [(0, 500), (0, 200), (1, 10), (-1, 0)] {
+            stat.update_end_key(ref_id, end);
+        }
+        assert_eq!(stat.max_end_key, Some((-1, 0)));
+
+        // Old metas without summaries are still readable.
+        let stat: Stat = serde_json::from_str(r#"{"min_value":1,"max_value":2}"#).unwrap();
+        assert!(stat.or_mask.is_none() && stat.any_has_bits(1));
+    }
 }
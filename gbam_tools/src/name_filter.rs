@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+/// Amount of filter bits per read name. Gives about 1% false positives.
+const BITS_PER_NAME: usize = 10;
+/// Amount of probes per read name, optimal for `BITS_PER_NAME`.
+pub(crate) const NUM_HASHES: u32 = 7;
+
+/// Location of the read name filter of ReadName block. The filter is written
+/// right after the compressed block.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NameFilterMeta {
+    pub seekpos: u64,
+    pub size: u32,
+    pub num_hashes: u32,
+}
+
+/// FNV-1a. Filter bits are persisted, so the hash has to be stable across
+/// builds, which is not guaranteed for std hashers.
+pub(crate) fn hash_name(name: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &byte in name {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Read names are stored with trailing NUL, as in BAM.
+pub(crate) fn strip_nul(name: &[u8]) -> &[u8] {
+    name.strip_suffix(&[0]).unwrap_or(name)
+}
+
+/// Bit positions of the name are derived from two halves of its hash (double
+/// hashing).
+fn probes(hash: u64, num_hashes: u32, num_bits: usize) -> impl Iterator<Item = usize> {
+    let h1 = hash & 0xFFFF_FFFF;
+    let h2 = (hash >> 32) | 1;
+    (0..num_hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits as u64) as usize)
+}
+
+/// Builds bloom filter bits out of read name hashes.
+pub(crate) fn build_filter(hashes: &[u64]) -> Vec<u8> {
+    let num_bits = std::cmp::max(hashes.len() * BITS_PER_NAME, 8).next_multiple_of(8);
+    let mut bits = vec![0u8; num_bits / 8];
+    for &hash in hashes {
+        for bit in probes(hash, NUM_HASHES, num_bits) {
+            bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+    bits
+}
+
+/// Checks if block may contain the read name. False positives are possible,
+/// false negatives are not.
+pub(crate) fn may_contain(bits: &[u8], num_hashes: u32, name: &[u8]) -> bool {
+    probes(hash_name(name), num_hashes, bits.len() * 8).all(|bit| bits[bit / 8] & (1 << (bit % 8)) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name_filter() {
+        let names: Vec<String> = (0..1000).map(|i| format!("read_{}", i)).collect();
+        let hashes: Vec<u64> = names.iter().map(|n| hash_name(n.as_bytes())).collect();
+        let bits = build_filter(&hashes);
+        assert!(names.iter().all(|n| may_contain(&bits, NUM_HASHES, n.as_bytes())));
+        let false_positives = (1000..2000)
+            .filter(|i| may_contain(&bits, NUM_HASHES, format!("read_{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 50);
+        assert_eq!(strip_nul(b"read\0"), b"read");
+    }
+}
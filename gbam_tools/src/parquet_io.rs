@@ -0,0 +1,238 @@
+//! Export of GBAM files into Parquet and import back. Parquet file has one
+//! column per GBAM data field (see `arrow_export::arrow_schema`); index fields
+//! (LName, NCigar, SequenceLength) are implied by lengths of variable sized
+//! values. Reference sequences, SAM header and sort order are kept in schema
+//! metadata.
+use crate::arrow_export::{arrow_schema, column_name, column_type, to_record_batch};
+use crate::bam::bam_to_gbam::{build_sam_header, sam_header_text, set_header_sort_order, STATS_FIELDS};
+use crate::meta::SortOrder;
+use crate::query::cigar::{Cigar, Op};
+use crate::reader::batch::BatchReader;
+use crate::reader::column::block_ranges;
+use crate::reader::{parse_tmplt::ParsingTemplate, reader::Reader, record::GbamRecord};
+use crate::{Codecs, Writer};
+use arrow::array::{Array, AsArray, RecordBatch};
+use arrow::datatypes::{Int32Type, Schema, UInt16Type, UInt32Type, UInt8Type};
+use bam_tools::record::bamrawrecord::BAMRawRecord;
+use bam_tools::record::fields::{is_data_field, Fields, FIELDS_NUM};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::Arc;
+
+const REF_SEQS_KEY: &str = "gbam.ref_seqs";
+const SAM_HEADER_KEY: &str = "gbam.sam_header";
+const SORT_ORDER_KEY: &str = "gbam.sort_order";
+
+fn data_fields() -> impl Iterator<Item = &'static Fields> {
+    Fields::iterator().filter(|field| is_data_field(field))
+}
+
+/// Writes GBAM file into Parquet file. Every row group holds records of one
+/// RefID block, so row groups match GBAM blocks.
+pub fn gbam_to_parquet(in_path: &str, out_path: &str) {
+    let mut template = ParsingTemplate::new();
+    template.set_all();
+    let reader = Reader::new(File::open(in_path).unwrap(), template).unwrap();
+    let file_meta = &reader.file_meta;
+
+    let metadata = HashMap::from([
+        (REF_SEQS_KEY.to_owned(), serde_json::to_string(file_meta.get_ref_seqs()).unwrap()),
+        (SAM_HEADER_KEY.to_owned(), sam_header_text(file_meta.get_sam_header())),
+        (SORT_ORDER_KEY.to_owned(), serde_json::to_string(&reader.physical_sort_order()).unwrap()),
+    ]);
+    let schema = Arc::new(arrow_schema(&reader.parsing_template).as_ref().clone().with_metadata(metadata));
+
+    let row_groups = block_ranges(file_meta, &Fields::RefID);
+    let max_row_group_size = row_groups.iter().map(|range| range.len()).max().unwrap_or(1);
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(std::cmp::max(max_row_group_size, 1))
+        .build();
+    let mut writer = ArrowWriter::try_new(BufWriter::new(File::create(out_path).expect("failed")), schema.clone(), Some(props)).unwrap();
+    let mut batch_reader = BatchReader::new(&reader);
+    for range in row_groups.into_iter().filter(|range| !range.is_empty()) {
+        let batch = to_record_batch(batch_reader.read(range)).with_schema(schema.clone()).unwrap();
+        writer.write(&batch).unwrap();
+        writer.flush().unwrap();
+    }
+    writer.close().unwrap();
+}
+
+/// Checks that schema has columns of all GBAM data fields with expected types
+/// and GBAM metadata. Other columns are allowed and ignored on import.
+fn validate_schema(schema: &Schema) -> Result<(), String> {
+    for field in data_fields() {
+        let name = column_name(field).unwrap();
+        match schema.field_with_name(name) {
+            Ok(column) if *column.data_type() == column_type(field) => {}
+            Ok(column) => return Err(format!("column {} has type {}, expected {}", name, column.data_type(), column_type(field))),
+            Err(_) => return Err(format!("column {} is missing", name)),
+        }
+    }
+    for key in [REF_SEQS_KEY, SAM_HEADER_KEY] {
+        if !schema.metadata().contains_key(key) {
+            return Err(format!("metadata {} is missing", key));
+        }
+    }
+    Ok(())
+}
+
+/// Fills record with values of row of record batch.
+fn fill_record(batch: &RecordBatch, row: usize, rec: &mut GbamRecord) {
+    let column = |field: &Fields| {
+        let name = column_name(field).unwrap();
+        let column = batch.column_by_name(name).unwrap();
+        assert!(!column.is_null(row), "Column {} contains nulls.", name);
+        column
+    };
+    let int32 = |field: &Fields| column(field).as_primitive::<Int32Type>().value(row);
+    rec.refid = Some(int32(&Fields::RefID));
+    rec.pos = Some(int32(&Fields::Pos));
+    rec.next_ref_id = Some(int32(&Fields::NextRefID));
+    rec.next_pos = Some(int32(&Fields::NextPos));
+    rec.tlen = Some(int32(&Fields::TemplateLength));
+    rec.mapq = Some(column(&Fields::Mapq).as_primitive::<UInt8Type>().value(row));
+    rec.bin = Some(column(&Fields::Bin).as_primitive::<UInt16Type>().value(row));
+    rec.flag = Some(column(&Fields::Flags).as_primitive::<UInt16Type>().value(row));
+
+    let read_name = rec.read_name.get_or_insert_with(Vec::new);
+    read_name.clear();
+    read_name.extend_from_slice(column(&Fields::ReadName).as_string::<i32>().value(row).as_bytes());
+    read_name.push(0);
+
+    let ops = column(&Fields::RawCigar).as_list::<i32>().value(row);
+    let cigar = rec.cigar.get_or_insert_with(|| Cigar::new(Vec::new()));
+    cigar.0.clear();
+    cigar.0.extend(ops.as_primitive::<UInt32Type>().values().iter().map(|&op| Op::new(op)));
+
+    let seq = rec.seq.get_or_insert_with(String::new);
+    seq.clear();
+    seq.push_str(column(&Fields::RawSequence).as_string::<i32>().value(row));
+    rec.qual = Some(column(&Fields::RawQual).as_binary::<i32>().value(row).to_vec());
+    rec.tags = Some(column(&Fields::RawTags).as_binary::<i32>().value(row).to_vec());
+}
+
+/// Converts Parquet file written by `gbam_to_parquet` (or any file with the
+/// same columns and metadata) into GBAM file. Panics if schema does not match.
+pub fn parquet_to_gbam(in_path: &str, out_path: &str, full_command: String) {
+    let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(in_path).unwrap()).unwrap();
+    let schema = builder.schema().clone();
+    if let Err(e) = validate_schema(&schema) {
+        panic!("Parquet file {} can not be converted to GBAM: {}.", in_path, e);
+    }
+    let metadata = schema.metadata();
+    let ref_seqs: Vec<(String, u32)> = serde_json::from_str(&metadata[REF_SEQS_KEY]).expect("Reference sequences metadata is corrupted.");
+    let sort_order: SortOrder = metadata
+        .get(SORT_ORDER_KEY)
+        .map_or(SortOrder::Unsorted, |value| serde_json::from_str(value).expect("Sort order metadata is corrupted."));
+    let sam_header = set_header_sort_order(&build_sam_header(&metadata[SAM_HEADER_KEY], &ref_seqs), sort_order);
+
+    let mut stats_fields = STATS_FIELDS.to_vec();
+    if sort_order == SortOrder::Coordinate {
+        stats_fields.push(Fields::Pos);
+    }
+    let mut writer = Writer::new(
+        BufWriter::new(File::create(out_path).expect("failed")),
+        vec![Codecs::Lz4; FIELDS_NUM],
+        8,
+        stats_fields,
+        ref_seqs,
+        sam_header,
+        full_command,
+        sort_order,
+    );
+
+    let mut rec = GbamRecord::default();
+    let mut buf = Vec::new();
+    for batch in builder.build().unwrap() {
+        let batch = batch.unwrap();
+        for row in 0..batch.num_rows() {
+            fill_record(&batch, row, &mut rec);
+            rec.convert_to_bytes(&mut buf);
+            writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+        }
+    }
+    writer.finish().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::reader::{assert_record_eq, test_record, write_sorted_to_memory};
+    use arrow::datatypes::{DataType, Field};
+    use tempdir::TempDir;
+
+    fn gbam_schema() -> Schema {
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let metadata = HashMap::from([(REF_SEQS_KEY.to_owned(), String::from("[]")), (SAM_HEADER_KEY.to_owned(), String::new())]);
+        arrow_schema(&template).as_ref().clone().with_metadata(metadata)
+    }
+
+    #[test]
+    fn test_validate_schema() {
+        assert_eq!(validate_schema(&gbam_schema()), Ok(()));
+
+        let schema = gbam_schema();
+        let mut fields: Vec<Field> = schema.fields().iter().map(|f| f.as_ref().clone()).collect();
+        fields.push(Field::new("sample", DataType::Utf8, true));
+        assert_eq!(validate_schema(&Schema::new_with_metadata(fields.clone(), schema.metadata().clone())), Ok(()));
+
+        fields[1] = Field::new("pos", DataType::Int64, false);
+        assert_eq!(
+            validate_schema(&Schema::new_with_metadata(fields.clone(), schema.metadata().clone())),
+            Err(String::from("column pos has type Int64, expected Int32"))
+        );
+        fields.remove(1);
+        assert_eq!(validate_schema(&Schema::new_with_metadata(fields, schema.metadata().clone())), Err(String::from("column pos is missing")));
+        assert_eq!(validate_schema(&Schema::new(gbam_schema().fields().clone())), Err(format!("metadata {} is missing", REF_SEQS_KEY)));
+    }
+
+    #[test]
+    fn test_parquet_round_trip() {
+        let ref_seqs = vec![(String::from("chr1"), 100), (String::from("chr2"), 200)];
+        let records: Vec<GbamRecord> = [(0, 10), (0, 20), (1, 5), (-1, -1)]
+            .iter()
+            .enumerate()
+            .map(|(i, &(refid, pos))| GbamRecord {
+                mapq: Some(60 - i as u8),
+                bin: Some(4680 + i as u16),
+                flag: Some(99 + i as u16),
+                next_ref_id: Some(refid),
+                next_pos: Some(pos + 50),
+                tlen: Some(-(i as i32)),
+                read_name: Some(format!("read{}\0", i).into_bytes()),
+                cigar: Some(Cigar::new(vec![Op::new(2 << 4), Op::new((i as u32 + 1) << 4 | 4)])),
+                seq: Some(String::from(&"ACGTACGT"[..2 * (i + 1)])),
+                qual: Some(vec![30 + i as u8; 2 * (i + 1)]),
+                tags: Some(format!("NMi{}", i).into_bytes()),
+                ..test_record(refid, pos)
+            })
+            .collect();
+        let dir = TempDir::new("parquet_round_trip").unwrap();
+        let path = |name: &str| dir.path().join(name).to_str().unwrap().to_owned();
+        std::fs::write(path("in.gbam"), write_sorted_to_memory(ref_seqs.clone(), &records)).unwrap();
+
+        gbam_to_parquet(&path("in.gbam"), &path("out.parquet"));
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(path("out.parquet")).unwrap()).unwrap();
+        let sort_order: SortOrder = serde_json::from_str(&builder.schema().metadata()[SORT_ORDER_KEY]).unwrap();
+        assert_eq!(sort_order, SortOrder::Coordinate);
+
+        parquet_to_gbam(&path("out.parquet"), &path("back.gbam"), String::new());
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = Reader::new(File::open(path("back.gbam")).unwrap(), template).unwrap();
+        assert_eq!(reader.file_meta.get_ref_seqs(), &ref_seqs);
+        assert_eq!(reader.physical_sort_order(), SortOrder::Coordinate);
+        assert_eq!(reader.amount, records.len());
+        for (expected, rec) in records.iter().zip(reader.records()) {
+            assert_record_eq(&rec, expected);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use bam_tools::record::fields::Fields;
 use std::cmp::min;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::io::{Write, BufWriter, StdoutLock};
 use std::ops::{RangeInclusive, Range};
 use std::sync::Arc;
@@ -12,10 +12,13 @@ use crate::utils::bed;
 use crate::meta::{BlockMeta, FileMeta};
 use crate::reader::{reader::Reader, record::GbamRecord};
 use std::path::{PathBuf};
+use std::str::FromStr;
 use crossbeam::channel::{Receiver, Sender, bounded};
 use std::thread;
 use std::thread::JoinHandle;
 use super::int2str::{i32toa_countlut, u32toa_countlut};
+use super::pileup::{find_first_record, FILTERED_FLAGS};
+use crate::utils::bigwig::BigWigWriter;
 use flate2::Compression;
 use flate2::write::GzEncoder;
 use rayon::prelude::*;
@@ -25,36 +28,60 @@ fn panic_err() {
     panic!("The query you entered is incorrect. The format is as following: <ref name>:<position>\ne.g. chr1:1257\n");
 }
 
-fn process_range(preparsed_records: Arc<Vec<DepthUnit>>, index_file: Option<Arc<Vec<u32>>>, rec_range: Range<usize>, mut scan_line: Vec<i32>, target_id: i32) -> Vec<i32> {
+fn process_range(preparsed_records: Arc<PreparsedRecords>, index_file: Option<Arc<Vec<u32>>>, rec_range: Range<usize>, mut scan_line: Vec<i32>, target_id: i32) -> Vec<i32> {
     // let mut rec = GbamRecord::default();
     for idx in rec_range {
-        let rec = preparsed_records[index_file.as_ref().unwrap()[idx] as usize];
+        let rec_idx = index_file.as_ref().unwrap()[idx] as usize;
+        let rec = preparsed_records.units[rec_idx];
         if rec.refid != target_id {
             break;
         }
-        if rec.cigar == 0 {
+        if rec.span == 0 {
             continue;
         }
-        let read_start: usize = rec.pos as usize;
-        let mut base_cov = rec.cigar as usize;
-        if(rec.flag & 0b11100000100) != 0 {
-            base_cov = 0;
-        }
-        if base_cov == 0 {
-            base_cov = 1;
-        }
-      
-        let read_end = read_start + base_cov;
-
-        scan_line[read_start] += 1;
-        scan_line[read_end] -= 1;
+        // Fragments may extend past the end of reference.
+        let last = scan_line.len() - 1;
+        let gaps = preparsed_records.gaps(rec_idx, &rec);
+        add_coverage(&mut scan_line, rec.pos as u32, rec.span, rec.flag, gaps, |pos| min(pos as usize, last));
         // buf.increments.push(read_start);
         // buf.decrements.push(read_end);
     }
     scan_line
 }
 
-fn calc_depth(preparsed_records: Arc<Vec<DepthUnit>>, file_meta: Arc<FileMeta>, index_file: Option<Arc<Vec<u32>>>, number_of_records: usize, ref_id: i32, mut coverage_arr: Vec<i32>, ref_len: usize) -> Vec<i32> {
+/// Computes span of record (0 if it is not counted) and pushes its skipped
+/// intervals into `gaps`.
+fn record_coverage(rec: &GbamRecord, mode: DepthMode, cigar_mode: CigarMode, gaps: &mut Vec<(u32, u32)>) -> u32 {
+    let span = record_span(rec, mode);
+    // Fragments also cover the insert, so only reads are split.
+    if span != 0 && cigar_mode != CigarMode::Span && mode != DepthMode::Fragment {
+        collect_gaps(rec, cigar_mode, gaps);
+    }
+    span
+}
+
+/// Adds record covering [read_start, read_start + span) without `gaps` into
+/// scan line, positions are mapped to slots by `clip`. Filtered reads cover
+/// only their first base.
+fn add_coverage<C>(scan_line: &mut [i32], read_start: u32, span: u32, flag: u16, gaps: &[(u32, u32)], clip: C)
+where
+    C: Fn(u32) -> usize,
+{
+    let filtered = flag & FILTERED_FLAGS != 0;
+    let read_end = read_start + if filtered { 1 } else { span };
+    scan_line[clip(read_start)] += 1;
+    scan_line[clip(read_end)] -= 1;
+
+    // Skipped CIGAR operations are subtracted from the read span.
+    if !filtered {
+        for &(gap_start, gap_end) in gaps {
+            scan_line[clip(gap_start.min(read_end))] -= 1;
+            scan_line[clip(gap_end.min(read_end))] += 1;
+        }
+    }
+}
+
+fn calc_depth(preparsed_records: Arc<PreparsedRecords>, file_meta: Arc<FileMeta>, index_file: Option<Arc<Vec<u32>>>, number_of_records: usize, ref_id: i32, mut coverage_arr: Vec<i32>, ref_len: usize) -> Vec<i32> {
     coverage_arr.resize(ref_len+1, 0);
 
     // let lower_bound = if let Some(block_num) = find_leftmost_block(ref_id, file_meta.view_blocks(&Fields::RefID)) {
@@ -67,12 +94,12 @@ fn calc_depth(preparsed_records: Arc<Vec<DepthUnit>>, file_meta: Arc<FileMeta>,
     // let upper_bound = find_rightmost_block(ref_id, file_meta.view_blocks(&Fields::RefID)) as usize;
     let mut first_rec:i64 = -1;
     
-    let mut last_rec:i64=  preparsed_records.len() as i64;
+    let mut last_rec:i64=  preparsed_records.units.len() as i64;
     
   
     while(last_rec - first_rec > 1){
         let mid: usize = ((first_rec + last_rec)/2) as usize;
-        let buf = preparsed_records[index_file.as_ref().unwrap()[mid] as usize];
+        let buf = preparsed_records.units[index_file.as_ref().unwrap()[mid] as usize];
         if buf.refid >= ref_id || buf.refid == -1 {
             last_rec = mid as i64;
         }
@@ -81,9 +108,9 @@ fn calc_depth(preparsed_records: Arc<Vec<DepthUnit>>, file_meta: Arc<FileMeta>,
         }
     }
     first_rec += 1;
-    let amount = preparsed_records.len();
+    let amount = preparsed_records.units.len();
 
-    if first_rec as usize == amount || preparsed_records[index_file.as_ref().unwrap()[first_rec as usize] as usize].refid != ref_id {
+    if first_rec as usize == amount || preparsed_records.units[index_file.as_ref().unwrap()[first_rec as usize] as usize].refid != ref_id {
         return coverage_arr;
     }
 
@@ -116,11 +143,158 @@ fn calc_depth(preparsed_records: Arc<Vec<DepthUnit>>, file_meta: Arc<FileMeta>,
 struct DepthUnit {
     refid: i32,
     pos: i32,
-    cigar: u32,
+    // Length of covered interval, records with zero span are skipped.
+    span: u32,
     flag: u16,
+    // Skipped CIGAR operations of record, located in gaps table of its chunk.
+    gaps_len: u32,
+    gaps_start: u32,
+}
+
+/// Records are preparsed in block aligned chunks of about this size.
+const PREPARSE_CHUNK_SIZE: usize = 2_000_000;
+
+struct PreparsedRecords {
+    units: Vec<DepthUnit>,
+    // Intervals [start, end) inside of records spans which are not covered
+    // (skipped CIGAR operations). One table per chunk of records.
+    gaps: Vec<Vec<(u32, u32)>>,
+    // First record of every chunk.
+    chunk_starts: Vec<usize>,
+}
+
+impl PreparsedRecords {
+    fn gaps(&self, rec_idx: usize, unit: &DepthUnit) -> &[(u32, u32)] {
+        let chunk = self.chunk_starts.partition_point(|&start| start <= rec_idx) - 1;
+        let start = unit.gaps_start as usize;
+        &self.gaps[chunk][start..start + unit.gaps_len as usize]
+    }
+}
+
+/// Which CIGAR operations inside of alignment span are counted as covered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CigarMode {
+    /// Whole alignment span is covered (as in mosdepth fast mode).
+    Span,
+    /// Reference skips (N) are not covered. Needed for RNA-seq.
+    SkipSplices,
+    /// Neither reference skips (N) nor deletions (D) are covered.
+    SkipSplicesAndDeletions,
+}
+
+/// Pushes intervals of skipped CIGAR operations into gaps table.
+fn collect_gaps(rec: &GbamRecord, cigar_mode: CigarMode, gaps: &mut Vec<(u32, u32)>) {
+    let mut ref_pos = rec.pos.unwrap() as u32;
+    for op in rec.cigar.as_ref().unwrap().ops() {
+        let skipped = match op.op_type() {
+            'N' => cigar_mode != CigarMode::Span,
+            'D' => cigar_mode == CigarMode::SkipSplicesAndDeletions,
+            _ => false,
+        };
+        if skipped {
+            gaps.push((ref_pos, ref_pos + op.length()));
+        }
+        if op.is_consuming_reference() {
+            ref_pos += op.length();
+        }
+    }
+}
+
+/// What is counted as coverage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DepthMode {
+    /// All reads.
+    Reads,
+    /// Only reads mapped to forward strand.
+    Forward,
+    /// Only reads mapped to reverse strand.
+    Reverse,
+    /// Full insert between mates of proper pairs (from leftmost mate start to
+    /// the end of the fragment).
+    Fragment,
+}
+
+impl FromStr for DepthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reads" => Ok(DepthMode::Reads),
+            "forward" => Ok(DepthMode::Forward),
+            "reverse" => Ok(DepthMode::Reverse),
+            "fragment" => Ok(DepthMode::Fragment),
+            _ => Err(format!("Unknown depth mode <{}>. Available: reads, forward, reverse, fragment.", s)),
+        }
+    }
+}
+
+const BAM_FPROPER_PAIR: u16 = 2;
+const BAM_FSUPPLEMENTARY: u16 = 2048;
+
+/// Only leftmost mate of a proper pair covers the fragment, so it is counted once.
+fn fragment_span(rec: &GbamRecord) -> u32 {
+    let flag = rec.flag.unwrap();
+    let tlen = rec.tlen.unwrap();
+    if flag & BAM_FPROPER_PAIR == 0 || flag & BAM_FSUPPLEMENTARY != 0 || tlen <= 0 || rec.next_ref_id != rec.refid || rec.pos.unwrap() > rec.next_pos.unwrap() {
+        return 0;
+    }
+    tlen as u32
+}
+
+fn record_span(rec: &GbamRecord, mode: DepthMode) -> u32 {
+    match mode {
+        DepthMode::Reads => rec.alignment_span(),
+        DepthMode::Forward if !rec.is_reverse_complemented() => rec.alignment_span(),
+        DepthMode::Reverse if rec.is_reverse_complemented() => rec.alignment_span(),
+        DepthMode::Forward | DepthMode::Reverse => 0,
+        DepthMode::Fragment => fragment_span(rec),
+    }
+}
+
+/// Fields needed to compute depth in `mode`.
+pub fn depth_fields(mode: DepthMode) -> Vec<Fields> {
+    let mut fields = vec![Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::Flags];
+    if mode == DepthMode::Fragment {
+        fields.extend_from_slice(&[Fields::TemplateLength, Fields::NextRefID, Fields::NextPos]);
+    }
+    fields
+}
+
+/// Computes depth of every position of region [start, end) of reference
+/// `ref_id`, counting records the same way as `main_depth`. Reader has to
+/// fetch `depth_fields(mode)` and the file has to be sorted by coordinate
+/// (either physically or through index file passed to the reader).
+pub fn region_depth(reader: &mut Reader, ref_id: i32, start: u32, end: u32, mode: DepthMode, cigar_mode: CigarMode) -> Vec<i32> {
+    let len = end.saturating_sub(start) as usize;
+    let mut scan_line = vec![0i32; len + 1];
+    // Positions are clipped to the region, so reads outside of it cancel out.
+    let clip = |pos: u32| (pos.clamp(start, start + len as u32) - start) as usize;
+    let mut rec = GbamRecord::default();
+    let mut gaps = Vec::new();
+
+    for rec_num in find_first_record(reader, ref_id, start)..reader.amount {
+        reader.fill_record(rec_num, &mut rec);
+        if rec.refid.unwrap() != ref_id || rec.pos.unwrap() as u32 >= end {
+            break;
+        }
+        gaps.clear();
+        let span = record_coverage(&rec, mode, cigar_mode, &mut gaps);
+        if span != 0 {
+            add_coverage(&mut scan_line, rec.pos.unwrap() as u32, span, rec.flag.unwrap(), &gaps, clip);
+        }
+    }
+
+    let mut acc = 0;
+    for slot in scan_line.iter_mut() {
+        acc += *slot;
+        *slot = acc;
+    }
+    scan_line.truncate(len);
+    scan_line
 }
 
-pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Option<Arc<Vec<u32>>>, bed_cli_request: Option<String>, _mapq: Option<u32>, bed_gz_path: Option<PathBuf>, thread_num: Option<usize>){
+#[allow(clippy::too_many_arguments)]
+pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Option<Arc<Vec<u32>>>, bed_cli_request: Option<String>, mapq: Option<u32>, out_path: Option<PathBuf>, thread_num: Option<usize>, mode: DepthMode, cigar_mode: CigarMode){
     let mut queries = HashMap::<String, Vec<(u32, u32)>>::new();
     if let Some(bed_path) = bed_file {
         queries = bed::parse_bed_from_file(bed_path).expect("BED file is corrupted.");
@@ -136,6 +310,9 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
     let number_of_records = reader.amount;
     drop(reader);
 
+    // Depth of each position is written once, in order of coordinates.
+    queries.values_mut().for_each(bed::merge_regions);
+
     // Calculate for whole file.
     if queries.is_empty() {
         ref_seqs.iter().for_each(|(chr, len)| {queries.insert(chr.clone(), vec![(0, *len)]);});
@@ -146,7 +323,7 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
         buffers = vec![Vec::<i32>::new();std::cmp::min(thread_num.unwrap(), 8)];
     }
 
-    type VectorOfSendersAndReceivers= Vec::<Option<(Sender<(Arc<Vec<DepthUnit>>, Arc<FileMeta>, Option<Arc<Vec<u32>>>, usize, i32, Vec<i32>, usize, String)>,Receiver<(String, Vec<i32>)>)>>;
+    type VectorOfSendersAndReceivers= Vec::<Option<(Sender<(Arc<PreparsedRecords>, Arc<FileMeta>, Option<Arc<Vec<u32>>>, usize, i32, Vec<i32>, usize, String)>,Receiver<(String, Vec<i32>)>)>>;
     let mut circular_buf_channels = VectorOfSendersAndReceivers::new();
     (0..buffers.len()).for_each(|_|circular_buf_channels.push(None));
     let mut handles: Vec::<JoinHandle<()>> = Vec::new();
@@ -158,31 +335,66 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
     
     let mut iter = ref_seqs.iter();
     let mut accum = 0;  
-    let mut bed_gz_printer = bed_gz_path.map(BedGzPrinter::new);
+    let mut region_printer = out_path.map(|path| new_region_printer(path, &ref_seqs));
 
     let st = std::io::stdout();
     let lock = st.lock();
     let mut printer = ConsolePrinter::new(lock);
 
+    let min_mapq = mapq.unwrap_or(0);
+    let mut fields = depth_fields(mode);
+    if min_mapq > 0 {
+        fields.push(Fields::Mapq);
+    }
+    // Only RefID and Pos of records in blocks without reads passing mapq
+    // threshold are read, so their CIGAR blocks are not decompressed.
+    let mapq_ranges = file_meta.record_ranges_where(&Fields::Mapq, |stat| stat.may_contain_range(min_mapq as i32, i32::MAX));
+    let chunks = Reader::new_with_meta(gbam_file.try_clone().unwrap(), ParsingTemplate::new_with(&fields), &file_meta, None)
+        .unwrap()
+        .plan_chunks(PREPARSE_CHUNK_SIZE);
+
     let mut preparsed = vec![DepthUnit::default(); number_of_records];
+    let mut chunk_units = Vec::with_capacity(chunks.len());
+    let mut rest = &mut preparsed[..];
+    for chunk in &chunks {
+        let (units, tail) = std::mem::take(&mut rest).split_at_mut(chunk.len());
+        chunk_units.push(units);
+        rest = tail;
+    }
 
-    preparsed.par_iter_mut().zip(0..number_of_records).chunks(2_000_000).for_each(|records_range| {
+    let gaps: Vec<Vec<(u32, u32)>> = chunk_units.into_par_iter().zip(chunks.par_iter()).map(|(units, records_range)| {
+        let mut chunk_gaps = Vec::new();
         let mut rec =  GbamRecord::default();
-        let mut tmplt = ParsingTemplate::new();
-        tmplt.set(&Fields::RawCigar, true);
-    
-        let mut reader = Reader::new_with_meta(gbam_file.try_clone().unwrap(), ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos, Fields::RawCigar, Fields::Flags]), &file_meta, None).unwrap();
-
-        for (dest, rec_num) in records_range {
+        let mut reader = Reader::new_with_meta(gbam_file.try_clone().unwrap(), ParsingTemplate::new_with(&fields), &file_meta, None).unwrap();
+
+        let mut full_template = true;
+        for (dest, rec_num) in units.iter_mut().zip(records_range.clone()) {
+            let idx = mapq_ranges.partition_point(|range| range.end <= rec_num);
+            let may_pass = mapq_ranges.get(idx).is_some_and(|range| range.start <= rec_num);
+            if may_pass != full_template {
+                match may_pass {
+                    true => reader.restore_template(),
+                    false => reader.fetch_only(&[Fields::RefID, Fields::Pos]),
+                }
+                full_template = may_pass;
+            }
             reader.fill_record(rec_num, &mut rec);
             dest.refid = rec.refid.unwrap();
             dest.pos = rec.pos.unwrap();
-            dest.cigar = rec.cigar.as_ref().unwrap().base_coverage();
+            // Records with zero span are skipped.
+            if !may_pass || (min_mapq > 0 && (rec.mapq.unwrap() as u32) < min_mapq) {
+                continue;
+            }
             dest.flag = rec.flag.unwrap();
+            dest.gaps_start = u32::try_from(chunk_gaps.len()).unwrap();
+            dest.span = record_coverage(&rec, mode, cigar_mode, &mut chunk_gaps);
+            dest.gaps_len = u32::try_from(chunk_gaps.len() - dest.gaps_start as usize).unwrap();
         }
-    });
+        chunk_gaps
+    }).collect();
 
-    let arc_of_records = Arc::new(preparsed);
+    let chunk_starts = chunks.iter().map(|chunk| chunk.start).collect();
+    let arc_of_records = Arc::new(PreparsedRecords { units: preparsed, gaps, chunk_starts });
 
     dbg!("Finished parsing all records to RAM buffer.");
 
@@ -203,7 +415,7 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
                 
                 // printer.set_chr(thread_chr.clone());
                 let now = Instant::now();
-                if bed_gz_printer.is_none() {
+                if region_printer.is_none() {
                     
                     for bed_region in bed_regions {
                         let st = bed_region.0 as usize;
@@ -236,7 +448,7 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
                                     } 
                                     else if prev_depth.unwrap() != cur_depth {
                                         
-                                            bed_gz_printer.as_mut().unwrap().write_region(&thread_chr, prev_coord.unwrap(), coord, prev_depth.unwrap());
+                                            region_printer.as_mut().unwrap().write_region(&thread_chr, prev_coord.unwrap(), coord, prev_depth.unwrap());
                                             prev_depth = Some(cur_depth);
                                             prev_coord = Some(coord);
                                         
@@ -245,7 +457,10 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
                             }
                         }
 
-                        bed_gz_printer.as_mut().unwrap().write_region(&thread_chr, prev_coord.unwrap() , en , prev_depth.unwrap());                        
+                        // Region may lie beyond the end of reference sequence.
+                        if let (Some(prev_coord), Some(prev_depth)) = (prev_coord, prev_depth) {
+                            region_printer.as_mut().unwrap().write_region(&thread_chr, prev_coord, en, prev_depth);
+                        }
                     }
                 }
                 accum += now.elapsed().as_millis();
@@ -295,6 +510,10 @@ pub fn main_depth(gbam_file: File, bed_file: Option<&PathBuf>, index_file: Optio
         h.join().unwrap();
     }
 
+    if let Some(printer) = region_printer.as_mut() {
+        printer.finish();
+    }
+
     dbg!(accum);
     // Shouldn't allocate more.
     // assert!(coverage_arr.capacity() == longest_chr as usize);
@@ -424,9 +643,43 @@ impl BedGzPrinter {
             compressor: GzEncoder::new(BufWriter::with_capacity(64 * 1024, file), Compression::default()),
         }
     }
+}
+
+/// Output for regions of equal depth.
+trait RegionPrinter {
+    fn write_region(&mut self, chr: &str, prev_coord: u32, coord: u32, prev_depth: i32);
+
+    /// Called once all regions are written.
+    fn finish(&mut self) {}
+}
 
+/// Picks output format by extension of the path: bigWig for `.bw` and
+/// `.bigwig`, gzipped BED otherwise.
+fn new_region_printer(path: PathBuf, ref_seqs: &[(String, u32)]) -> Box<dyn RegionPrinter> {
+    let is_bigwig = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("bw") || ext.eq_ignore_ascii_case("bigwig"));
+    if is_bigwig {
+        Box::new(BigWigWriter::create(&path, ref_seqs).expect("Failed to create depth file."))
+    } else {
+        Box::new(BedGzPrinter::new(path))
+    }
+}
+
+impl RegionPrinter for BigWigWriter<BufWriter<File>> {
+    fn write_region(&mut self, chr: &str, prev_coord: u32, coord: u32, prev_depth: i32) {
+        BigWigWriter::write_region(self, chr, prev_coord, coord, prev_depth as f32).unwrap();
+    }
+
+    fn finish(&mut self) {
+        BigWigWriter::finish(self).unwrap();
+    }
+}
+
+impl RegionPrinter for BedGzPrinter {
     /// Done in reversed direction because we don't know what is the size of integers beforehand.
-    pub fn write_region(&mut self, chr: &str, prev_coord: u32, coord: u32, prev_depth: i32){
+    fn write_region(&mut self, chr: &str, prev_coord: u32, coord: u32, prev_depth: i32){
         let mut buff_ptr = self.buffer.as_mut_ptr();
         let orig: *mut u8 = self.buffer.as_mut_ptr();
         unsafe {
@@ -448,4 +701,88 @@ impl BedGzPrinter {
             self.compressor.write_all(&self.buffer[..(buff_ptr as usize - orig as usize)]).unwrap();
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::{Cigar, Op};
+    use crate::reader::reader::{reader_from_records, test_record};
+
+    fn op(len: u32, code: u32) -> Op {
+        Op::new(len << 4 | code)
+    }
+
+    /// 10M 5D 10M mapped to reference 0.
+    fn record(pos: i32, flag: u16, next_ref_id: i32, next_pos: i32, tlen: i32) -> GbamRecord {
+        GbamRecord {
+            refid: Some(0),
+            pos: Some(pos),
+            flag: Some(flag),
+            next_ref_id: Some(next_ref_id),
+            next_pos: Some(next_pos),
+            tlen: Some(tlen),
+            cigar: Some(Cigar::new(vec![op(10, 0), op(5, 2), op(10, 0)])),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_record_span() {
+        let forward = record(100, 0, -1, -1, 0);
+        let reverse = record(100, 16, -1, -1, 0);
+        assert_eq!(record_span(&forward, DepthMode::Reads), 25);
+        assert_eq!(record_span(&reverse, DepthMode::Reads), 25);
+        assert_eq!(record_span(&forward, DepthMode::Forward), 25);
+        assert_eq!(record_span(&reverse, DepthMode::Forward), 0);
+        assert_eq!(record_span(&forward, DepthMode::Reverse), 0);
+        assert_eq!(record_span(&reverse, DepthMode::Reverse), 25);
+    }
+
+    #[test]
+    fn test_fragment_span() {
+        // Leftmost mate of proper pair covers the whole insert.
+        let leftmost = record(100, 1 | 2 | 32 | 64, 0, 300, 250);
+        assert_eq!(record_span(&leftmost, DepthMode::Fragment), 250);
+        // Rightmost mate has negative TLEN and is not counted.
+        let rightmost = record(300, 1 | 2 | 16 | 128, 0, 100, -250);
+        assert_eq!(record_span(&rightmost, DepthMode::Fragment), 0);
+        // Not a proper pair.
+        let improper = record(100, 1 | 32 | 64, 0, 300, 250);
+        assert_eq!(record_span(&improper, DepthMode::Fragment), 0);
+        // Mate on another chromosome.
+        let other_chr = record(100, 1 | 2 | 32 | 64, 1, 300, 250);
+        assert_eq!(record_span(&other_chr, DepthMode::Fragment), 0);
+        let supplementary = record(100, 1 | 2 | 32 | 64 | 2048, 0, 300, 250);
+        assert_eq!(record_span(&supplementary, DepthMode::Fragment), 0);
+    }
+
+    fn mapped(pos: i32, flag: u16, cigar: Vec<Op>) -> GbamRecord {
+        GbamRecord {
+            flag: Some(flag),
+            cigar: Some(Cigar::new(cigar)),
+            ..test_record(0, pos)
+        }
+    }
+
+    #[test]
+    fn test_region_depth() {
+        let records = [
+            // Starts before the region.
+            mapped(2, 0, vec![op(10, 0)]),
+            // 5M 3N 5M.
+            mapped(10, 0, vec![op(5, 0), op(3, 3), op(5, 0)]),
+            // Duplicate, covers only its first base.
+            mapped(12, 1024, vec![op(4, 0)]),
+            // Starts after the region.
+            mapped(30, 0, vec![op(4, 0)]),
+        ];
+        let template = ParsingTemplate::new_with(&depth_fields(DepthMode::Reads));
+        let mut reader = reader_from_records(vec![(String::from("chr1"), 100)], &records, template);
+
+        let depth = region_depth(&mut reader, 0, 8, 20, DepthMode::Reads, CigarMode::SkipSplices);
+        assert_eq!(depth, vec![1, 1, 2, 2, 2, 1, 1, 0, 0, 0, 1, 1]);
+        let depth = region_depth(&mut reader, 0, 8, 20, DepthMode::Reads, CigarMode::Span);
+        assert_eq!(depth, vec![1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1]);
+    }
+}
@@ -1,8 +1,7 @@
-use crate::reader::record::GbamRecord;
-use crate::reader::reader::Reader;
+use crate::reader::batch::BatchReader;
+use crate::reader::parallel::par_map_reduce;
 use bitflags::bitflags;
 use std::fmt;
-use rayon::prelude::*;
 use std::fs::File;
 use std::str;
 use std::string::String;
@@ -40,8 +39,10 @@ bitflags! {
     }
 }
 
+/// Counters of `samtools flagstat`, every one is a pair of QC-passed and
+/// QC-failed reads.
 #[derive(Default)]
-struct Stats {
+pub struct Stats {
     pub n_reads: [i64; 2],
     pub n_mapped: [i64; 2],
     pub n_pair_all: [i64; 2],
@@ -119,8 +120,8 @@ impl fmt::Display for Stats {
     }
 }
 
-fn collect(rec: &GbamRecord, stats: &mut Stats) {
-    let record_flag = BamFlags::from_bits(rec.flag.unwrap() as u32).unwrap();
+fn collect(flag: u16, ref_id: i32, next_ref_id: i32, mapq: u8, stats: &mut Stats) {
+    let record_flag = BamFlags::from_bits(flag as u32).unwrap();
     let w = record_flag.contains(BamFlags::BAM_FQCFAIL) as usize;
     
     stats.n_reads[w] += 1;
@@ -149,9 +150,9 @@ fn collect(rec: &GbamRecord, stats: &mut Stats) {
             }
             if !record_flag.contains(BamFlags::BAM_FUNMAP) &&  !record_flag.contains(BamFlags::BAM_FMUNMAP){
                 stats.n_pair_map[w] += 1;
-                if rec.next_ref_id.unwrap() != rec.refid.unwrap() {
+                if next_ref_id != ref_id {
                     stats.n_diffchr[w] += 1;
-                    if rec.mapq.unwrap() >= 5 {
+                    if mapq >= 5 {
                         stats.n_diffhigh[w] += 1;
                     }
                 }
@@ -173,32 +174,24 @@ fn collect(rec: &GbamRecord, stats: &mut Stats) {
     }
 }
 
-pub fn collect_stats(file: File) {
-    let tmplt = ParsingTemplate::new();
-    let reader = Reader::new(file.try_clone().unwrap(), tmplt).unwrap();
-    let total_records = reader.amount;
-    let file_meta = reader.file_meta;
-    
-    let file_stats = (0..total_records).into_par_iter().chunks(500_000).map(|records_range| {
+/// Counts flag statistics of all records of GBAM file.
+pub fn flagstat(file: &File) -> Stats {
+    let tmplt = ParsingTemplate::new_with(&[Fields::Flags, Fields::RefID, Fields::NextRefID, Fields::Mapq]);
+    par_map_reduce(file, tmplt, |reader, records_range| {
         let mut stats = Stats::default();
+        let batch = BatchReader::new(reader).read(records_range);
+        let (flags, ref_ids) = (batch.u16s(&Fields::Flags), batch.i32s(&Fields::RefID));
+        let (next_ref_ids, mapqs) = (batch.i32s(&Fields::NextRefID), batch.u8s(&Fields::Mapq));
 
-        let mut rec =  GbamRecord::default();
-        let mut tmplt = ParsingTemplate::new();
-        tmplt.set(&Fields::Flags, true);
-        tmplt.set(&Fields::RefID, true);
-        tmplt.set(&Fields::NextRefID, true);
-        tmplt.set(&Fields::Mapq, true);
-    
-        let mut reader = Reader::new_with_meta(file.try_clone().unwrap(), tmplt, &file_meta, None).unwrap();
-
-        for rec_num in records_range {
-            reader.fill_record(rec_num, &mut rec);
-            collect(&rec, &mut stats);
+        for i in 0..batch.len() {
+            collect(flags[i], ref_ids[i], next_ref_ids[i], mapqs[i], &mut stats);
         }
 
         stats
 
-    }).reduce(Stats::default, |mut a, b| {a.add(&b); a});
+    }, |mut a, b| {a.add(&b); a})
+}
 
-    println!("{file_stats}");
+pub fn collect_stats(file: File) {
+    println!("{}", flagstat(&file));
 }
@@ -0,0 +1,319 @@
+//! This module provides `samtools mpileup` compatible text output.
+use super::cigar::Op;
+use super::pileup::{find_ref_id, for_each_overlapping_record};
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::utils::{bed, fasta};
+use bam_tools::record::fields::Fields;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Same default as in samtools mpileup.
+pub const DEFAULT_MIN_BASE_QUAL: u8 = 13;
+
+const BAM_FPAIRED: u16 = 1;
+const BAM_FPROPER_PAIR: u16 = 2;
+
+/// Pileup of single reference position.
+#[derive(Default, Clone)]
+struct PileupColumn {
+    /// Whether any read is aligned here, even if all bases were filtered out.
+    covered: bool,
+    depth: u32,
+    bases: Vec<u8>,
+    quals: Vec<u8>,
+}
+
+fn strand_case(base: u8, reverse: bool) -> u8 {
+    if reverse {
+        base.to_ascii_lowercase()
+    } else {
+        base.to_ascii_uppercase()
+    }
+}
+
+fn qual_to_char(qual: u8) -> u8 {
+    std::cmp::min(qual as u32 + 33, 126) as u8
+}
+
+/// Appends insertion (+3ACG) or deletion (-2NN) which follows the base.
+fn push_indel(
+    column: &mut PileupColumn,
+    next_op: &Op,
+    rec_seq: &[u8],
+    read_pos: usize,
+    ref_pos: u32,
+    ref_seq: Option<&[u8]>,
+    reverse: bool,
+) {
+    let len = next_op.length();
+    match next_op.op_type() {
+        'I' => {
+            column.bases.push(b'+');
+            column.bases.extend_from_slice(len.to_string().as_bytes());
+            for i in 0..len as usize {
+                // Records without SEQ (`*`) have unknown bases.
+                let base = rec_seq.get(read_pos + i).copied().unwrap_or(b'N');
+                column.bases.push(strand_case(base, reverse));
+            }
+        }
+        'D' => {
+            column.bases.push(b'-');
+            column.bases.extend_from_slice(len.to_string().as_bytes());
+            for pos in ref_pos..ref_pos + len {
+                let base = ref_seq.and_then(|s| s.get(pos as usize)).copied().unwrap_or(b'N');
+                column.bases.push(strand_case(base, reverse));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Walks over CIGAR of record and adds its bases within [start, end) to
+/// pileup columns. Deletions are printed as `*`, or as `#` on reverse strand
+/// if `reverse_del` is set.
+fn add_record_to_mpileup(
+    rec: &GbamRecord,
+    start: u32,
+    end: u32,
+    ref_seq: Option<&[u8]>,
+    min_base_qual: u8,
+    reverse_del: bool,
+    columns: &mut [PileupColumn],
+) {
+    let reverse = rec.is_reverse_complemented();
+    let seq = rec.seq.as_ref().unwrap().as_bytes();
+    let qual = rec.qual.as_ref().unwrap();
+    let ops = &rec.cigar.as_ref().unwrap().0;
+    let last_ref_op = ops.iter().rposition(|op| op.is_consuming_reference());
+
+    let mut ref_pos = rec.pos.unwrap() as u32;
+    let mut read_pos = 0usize;
+    let mut first = true;
+
+    for (op_idx, op) in ops.iter().enumerate() {
+        if ref_pos >= end {
+            break;
+        }
+        let len = op.length();
+        let op_type = op.op_type();
+        match op_type {
+            'M' | '=' | 'X' | 'D' | 'N' => {
+                for i in 0..len {
+                    let pos = ref_pos + i;
+                    if pos < start || pos >= end {
+                        continue;
+                    }
+                    let column = &mut columns[(pos - start) as usize];
+                    column.covered = true;
+
+                    // For deletions and reference skips quality of the next base is used.
+                    let read_idx = if op.consumes_read() { read_pos + i as usize } else { read_pos };
+                    // Missing qualities (0xFF) always pass. Records without SEQ have
+                    // no qualities at all, they are filtered like in samtools.
+                    let base_qual = qual.get(read_idx).copied().unwrap_or(0);
+                    if base_qual < min_base_qual {
+                        continue;
+                    }
+
+                    if first && i == 0 {
+                        column.bases.push(b'^');
+                        column.bases.push(qual_to_char(rec.mapq.unwrap()));
+                    }
+                    let symbol = match op_type {
+                        'D' => if reverse && reverse_del { b'#' } else { b'*' },
+                        'N' => if reverse { b'<' } else { b'>' },
+                        _ => {
+                            let base = seq.get(read_idx).copied().unwrap_or(b'N');
+                            let ref_base = ref_seq.and_then(|s| s.get(pos as usize));
+                            match ref_base {
+                                Some(r) if r.eq_ignore_ascii_case(&base) => {
+                                    if reverse { b',' } else { b'.' }
+                                }
+                                _ => strand_case(base, reverse),
+                            }
+                        }
+                    };
+                    column.bases.push(symbol);
+                    if i == len - 1 {
+                        // Insertion may also directly follow deletion or skip.
+                        match ops.get(op_idx + 1) {
+                            Some(next_op) if op.consumes_read() => {
+                                push_indel(column, next_op, seq, read_idx + 1, pos + 1, ref_seq, reverse);
+                            }
+                            Some(next_op) if next_op.op_type() == 'I' => {
+                                push_indel(column, next_op, seq, read_pos, pos + 1, ref_seq, reverse);
+                            }
+                            _ => {}
+                        }
+                    }
+                    if Some(op_idx) == last_ref_op && i == len - 1 {
+                        column.bases.push(b'$');
+                    }
+                    column.quals.push(qual_to_char(base_qual));
+                    column.depth += 1;
+                }
+                ref_pos += len;
+                if op.consumes_read() {
+                    read_pos += len as usize;
+                }
+                first = false;
+            }
+            'I' | 'S' => read_pos += len as usize,
+            _ => {}
+        }
+    }
+}
+
+/// Prints pileup of region query (1-based, inclusive, e.g. chr1:1000-1010) in
+/// `samtools mpileup` format: chr, pos, ref base, depth, read bases, base
+/// qualities. If reference FASTA is not provided, reference bases are printed as
+/// N and all read bases are printed explicitly. Reads with any of UNMAP,
+/// SECONDARY, QCFAIL, DUP flags are skipped, as well as orphan reads (paired
+/// but not properly paired) unless `count_orphans` is set.
+#[allow(clippy::too_many_arguments)]
+pub fn main_mpileup(
+    gbam_file: File,
+    index_file: Option<Arc<Vec<u32>>>,
+    region: &str,
+    reference: Option<&PathBuf>,
+    min_base_qual: Option<u8>,
+    min_mapq: Option<u32>,
+    count_orphans: bool,
+    reverse_del: bool,
+) {
+    let (chr, left, right) =
+        bed::parse_region_query(region).expect("The region query is incorrect. Example: chr1:1000-1010");
+    let template = ParsingTemplate::new_with(&[
+        Fields::RefID,
+        Fields::Pos,
+        Fields::Mapq,
+        Fields::Flags,
+        Fields::RawCigar,
+        Fields::RawSequence,
+        Fields::RawQual,
+    ]);
+    let mut reader = Reader::new_with_index(gbam_file, template, index_file).unwrap();
+    let ref_id = find_ref_id(&reader, chr);
+
+    let ref_seq = reference.map(|path| {
+        fasta::read_ref_seq_from_file(path, chr)
+            .expect("Failed to read reference FASTA file.")
+            .unwrap_or_else(|| panic!("Reference sequence {} is not present in FASTA file.", chr))
+    });
+
+    let start = left.saturating_sub(1);
+    let min_base_qual = min_base_qual.unwrap_or(DEFAULT_MIN_BASE_QUAL);
+    let min_mapq = min_mapq.unwrap_or(0);
+    let mut columns = vec![PileupColumn::default(); right.saturating_sub(start) as usize];
+
+    for_each_overlapping_record(&mut reader, ref_id, start, right, |rec| {
+        let flag = rec.flag.unwrap();
+        if !count_orphans && flag & BAM_FPAIRED != 0 && flag & BAM_FPROPER_PAIR == 0 {
+            return;
+        }
+        if (rec.mapq.unwrap() as u32) < min_mapq {
+            return;
+        }
+        add_record_to_mpileup(rec, start, right, ref_seq.as_deref(), min_base_qual, reverse_del, &mut columns);
+    });
+
+    let st = std::io::stdout();
+    let mut out = BufWriter::with_capacity(64 * 1024, st.lock());
+    for (i, column) in columns.iter().enumerate().filter(|(_, c)| c.covered) {
+        let pos = start as usize + i;
+        let ref_base = ref_seq.as_ref().and_then(|s| s.get(pos)).copied().unwrap_or(b'N');
+        write!(out, "{}\t{}\t{}\t{}\t", chr, pos + 1, ref_base as char, column.depth).unwrap();
+        if column.depth == 0 {
+            out.write_all(b"*\t*\n").unwrap();
+            continue;
+        }
+        out.write_all(&column.bases).unwrap();
+        out.write_all(b"\t").unwrap();
+        out.write_all(&column.quals).unwrap();
+        out.write_all(b"\n").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::Cigar;
+
+    fn op(len: u32, code: u32) -> Op {
+        Op::new(len << 4 | code)
+    }
+
+    #[test]
+    fn test_mpileup_column() {
+        // 2M 1I 1M 1D 1M starting at position 0 on reverse strand.
+        let rec = GbamRecord {
+            pos: Some(0),
+            mapq: Some(60),
+            flag: Some(16),
+            cigar: Some(Cigar::new(vec![op(2, 0), op(1, 1), op(1, 0), op(1, 2), op(1, 0)])),
+            seq: Some(String::from("ACTGG")),
+            qual: Some(vec![30; 5]),
+            ..Default::default()
+        };
+        let ref_seq = b"ACGATT";
+        let mut columns = vec![PileupColumn::default(); 6];
+        add_record_to_mpileup(&rec, 0, 6, Some(&ref_seq[..]), 13, false, &mut columns);
+
+        let bases: Vec<&[u8]> = columns.iter().map(|c| &c.bases[..]).collect();
+        assert_eq!(bases, vec![&b"^],"[..], b",+1t", b",-1a", b"*", b"g$", b""]);
+        assert_eq!(columns[3].quals, b"?");
+        assert_eq!(columns[4].depth, 1);
+        assert!(!columns[5].covered);
+
+        let mut columns = vec![PileupColumn::default(); 6];
+        add_record_to_mpileup(&rec, 0, 6, Some(&ref_seq[..]), 13, true, &mut columns);
+        assert_eq!(columns[3].bases, b"#");
+    }
+
+    #[test]
+    fn test_mpileup_insertion_after_deletion() {
+        // 2M 1D 1I 2M starting at position 0 on forward strand.
+        let rec = GbamRecord {
+            pos: Some(0),
+            mapq: Some(60),
+            flag: Some(0),
+            cigar: Some(Cigar::new(vec![op(2, 0), op(1, 2), op(1, 1), op(2, 0)])),
+            seq: Some(String::from("ACTGG")),
+            qual: Some(vec![30; 5]),
+            ..Default::default()
+        };
+        let ref_seq = b"ACGGGT";
+        let mut columns = vec![PileupColumn::default(); 5];
+        add_record_to_mpileup(&rec, 0, 5, Some(&ref_seq[..]), 13, false, &mut columns);
+
+        let bases: Vec<&[u8]> = columns.iter().map(|c| &c.bases[..]).collect();
+        assert_eq!(bases, vec![&b"^]."[..], b".-1G", b"*+1T", b".", b".$"]);
+    }
+
+    #[test]
+    fn test_mpileup_without_seq() {
+        // 2M 1I 1M without SEQ and qualities.
+        let rec = GbamRecord {
+            pos: Some(0),
+            mapq: Some(60),
+            flag: Some(0),
+            cigar: Some(Cigar::new(vec![op(2, 0), op(1, 1), op(1, 0)])),
+            seq: Some(String::new()),
+            qual: Some(Vec::new()),
+            ..Default::default()
+        };
+        let mut columns = vec![PileupColumn::default(); 3];
+        add_record_to_mpileup(&rec, 0, 3, Some(&b"ACG"[..]), 13, false, &mut columns);
+        // Bases have quality 0, so they are below minimal base quality.
+        assert!(columns.iter().all(|c| c.covered && c.depth == 0 && c.bases.is_empty()));
+
+        let mut columns = vec![PileupColumn::default(); 3];
+        add_record_to_mpileup(&rec, 0, 3, Some(&b"ACG"[..]), 0, false, &mut columns);
+        let bases: Vec<&[u8]> = columns.iter().map(|c| &c.bases[..]).collect();
+        assert_eq!(bases, vec![&b"^]N"[..], b"N+1N", b"N$"]);
+        assert!(columns.iter().all(|c| c.depth == 1));
+    }
+}
@@ -0,0 +1,326 @@
+//! This module provides per-base pileup counts (base composition) for a region.
+use crate::reader::parse_tmplt::ParsingTemplate;
+use crate::reader::{reader::Reader, record::GbamRecord};
+use crate::utils::bed;
+use bam_tools::record::fields::Fields;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+
+/// Reads which are unmapped, secondary, QC failed or duplicates are skipped (same as in depth).
+pub(crate) const FILTERED_FLAGS: u16 = 0b11100000100;
+
+/// Counts of bases observed at a single reference position.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BaseCounts {
+    pub a: u32,
+    pub c: u32,
+    pub g: u32,
+    pub t: u32,
+    pub n: u32,
+    pub del: u32,
+}
+
+impl BaseCounts {
+    /// Total amount of reads covering position, deletions included.
+    pub fn depth(&self) -> u32 {
+        self.a + self.c + self.g + self.t + self.n + self.del
+    }
+
+    fn add_base(&mut self, base: u8) {
+        match base.to_ascii_uppercase() {
+            b'A' => self.a += 1,
+            b'C' => self.c += 1,
+            b'G' => self.g += 1,
+            b'T' => self.t += 1,
+            _ => self.n += 1,
+        }
+    }
+}
+
+/// Fields required to build a pileup.
+fn pileup_fields(with_qual: bool) -> Vec<Fields> {
+    let mut fields = vec![
+        Fields::RefID,
+        Fields::Pos,
+        Fields::Flags,
+        Fields::RawCigar,
+        Fields::RawSequence,
+    ];
+    if with_qual {
+        fields.push(Fields::RawQual);
+    }
+    fields
+}
+
+/// Finds record (in sorted order) from which records of `ref_id` overlapping
+/// `pos` have to be searched: the first record of `ref_id`, or a later one if
+/// the file is physically sorted and block stats show that earlier records
+/// end before `pos`. Returns `reader.amount` if there is no such record.
+/// Unmapped reads (-1) are located at the end of sorted file.
+pub(crate) fn find_first_record(reader: &mut Reader, ref_id: i32, pos: u32) -> usize {
+    let mut rec = GbamRecord::default();
+    let mut first_rec: i64 = -1;
+    let mut last_rec: i64 = reader.amount as i64;
+    if !reader.is_index_mapped() {
+        if let Some(blocks) = reader.file_meta.overlap_block_range(ref_id, pos as i32) {
+            first_rec = blocks.start as i64 - 1;
+            last_rec = blocks.end as i64;
+        }
+    }
+    reader.fetch_only(&[Fields::RefID]);
+    while last_rec - first_rec > 1 {
+        let mid = (first_rec + last_rec) / 2;
+        reader.fill_record(mid as usize, &mut rec);
+        let refid = rec.refid.unwrap();
+        if refid >= ref_id || refid == -1 {
+            last_rec = mid;
+        } else {
+            first_rec = mid;
+        }
+    }
+    reader.restore_template();
+    (first_rec + 1) as usize
+}
+
+/// Walks over all bases of record aligned within [start, end) and updates counts.
+fn add_record_to_pileup(
+    rec: &GbamRecord,
+    start: u32,
+    end: u32,
+    min_base_qual: Option<u8>,
+    counts: &mut [BaseCounts],
+) {
+    let seq = rec.seq.as_ref().unwrap().as_bytes();
+    let qual = rec.qual.as_ref();
+    let mut ref_pos = rec.pos.unwrap() as u32;
+    let mut read_pos = 0usize;
+
+    for op in rec.cigar.as_ref().unwrap().ops() {
+        if ref_pos >= end {
+            break;
+        }
+        let len = op.length();
+        match op.op_type() {
+            'M' | '=' | 'X' => {
+                for i in 0..len {
+                    let pos = ref_pos + i;
+                    if pos < start || pos >= end {
+                        continue;
+                    }
+                    let read_idx = read_pos + i as usize;
+                    if let (Some(min_qual), Some(qual)) = (min_base_qual, qual) {
+                        // Missing qualities are stored as 0xFF.
+                        if qual.get(read_idx).is_some_and(|&q| q != 0xFF && q < min_qual) {
+                            continue;
+                        }
+                    }
+                    // Records without SEQ (`*`) have unknown bases.
+                    counts[(pos - start) as usize].add_base(seq.get(read_idx).copied().unwrap_or(b'N'));
+                }
+                ref_pos += len;
+                read_pos += len as usize;
+            }
+            'D' => {
+                for pos in ref_pos.max(start)..(ref_pos + len).min(end) {
+                    counts[(pos - start) as usize].del += 1;
+                }
+                ref_pos += len;
+            }
+            'N' => ref_pos += len,
+            'I' | 'S' => read_pos += len as usize,
+            _ => {}
+        }
+    }
+}
+
+/// Calls `f` for every record of reference `ref_id` which has at least one
+/// aligned base within [start, end). Filtered reads are skipped. Records are
+/// filled according to reader parsing template, but only RefID, Pos, Flags and
+/// CIGAR are fetched for records outside of the region. The file has to be
+/// sorted by coordinate (either physically or through index file passed to
+/// the reader).
+pub(crate) fn for_each_overlapping_record<F>(
+    reader: &mut Reader,
+    ref_id: i32,
+    start: u32,
+    end: u32,
+    mut f: F,
+) where
+    F: FnMut(&GbamRecord),
+{
+    let mut rec = GbamRecord::default();
+
+    let first_rec = find_first_record(reader, ref_id, start);
+    for rec_num in first_rec..reader.amount {
+        reader.fetch_only(&[Fields::RefID, Fields::Pos, Fields::Flags, Fields::RawCigar]);
+        reader.fill_record(rec_num, &mut rec);
+        if rec.refid.unwrap() != ref_id || rec.pos.unwrap() as u32 >= end {
+            break;
+        }
+        if rec.flag.unwrap() & FILTERED_FLAGS != 0 || rec.cigar.as_ref().unwrap().0.is_empty() {
+            continue;
+        }
+        if rec.pos.unwrap() as u32 + rec.alignment_span() <= start {
+            continue;
+        }
+        reader.restore_template();
+        reader.fill_record(rec_num, &mut rec);
+        f(&rec);
+    }
+    reader.restore_template();
+}
+
+/// Builds base composition for every position of region [start, end) of
+/// reference `ref_id`. The file has to be sorted by coordinate.
+pub fn pileup_region(
+    reader: &mut Reader,
+    ref_id: i32,
+    start: u32,
+    end: u32,
+    min_base_qual: Option<u8>,
+) -> Vec<BaseCounts> {
+    let mut counts = vec![BaseCounts::default(); end.saturating_sub(start) as usize];
+    for_each_overlapping_record(reader, ref_id, start, end, |rec| {
+        add_record_to_pileup(rec, start, end, min_base_qual, &mut counts)
+    });
+    counts
+}
+
+/// Parses region (chr1:1000-2000, 1-based, inclusive, or just chr1 for whole
+/// reference) into reference id and 0-based half-open interval.
+pub fn parse_region(ref_seqs: &[(String, u32)], region: &str) -> Result<(i32, u32, u32), String> {
+    let chr = region.split(':').next().unwrap();
+    let ref_id = ref_seqs
+        .iter()
+        .position(|(name, _)| name == chr)
+        .ok_or_else(|| format!("Reference sequence {} is not present in file.", chr))?;
+    if !region.contains(':') {
+        return Ok((ref_id as i32, 0, ref_seqs[ref_id].1));
+    }
+    match bed::parse_region_query(region) {
+        Ok((_, left, right)) if left <= right => Ok((ref_id as i32, left.saturating_sub(1), right)),
+        _ => Err(format!("The region query {} is incorrect. Example: chr1:1000-1010", region)),
+    }
+}
+
+/// Returns numbers of records of reference `ref_id` which overlap [start, end),
+/// filtered reads included. Records without CIGAR overlap at their position.
+/// Reader has to fetch RefID, Pos and CIGAR, the file has to be sorted by
+/// coordinate.
+pub fn overlapping_records(reader: &mut Reader, ref_id: i32, start: u32, end: u32) -> Vec<usize> {
+    let mut rec = GbamRecord::default();
+    let mut res = Vec::new();
+    for rec_num in find_first_record(reader, ref_id, start)..reader.amount {
+        reader.fill_record(rec_num, &mut rec);
+        let pos = rec.pos.unwrap() as u32;
+        if rec.refid.unwrap() != ref_id || pos >= end {
+            break;
+        }
+        if pos + std::cmp::max(rec.alignment_span(), 1) > start {
+            res.push(rec_num);
+        }
+    }
+    res
+}
+
+/// Returns id of reference sequence by its name. Panics if there is none.
+pub(crate) fn find_ref_id(reader: &Reader, chr: &str) -> i32 {
+    reader
+        .file_meta
+        .get_ref_seqs()
+        .iter()
+        .position(|(name, _)| name == chr)
+        .unwrap_or_else(|| panic!("Reference sequence {} is not present in file.", chr))
+        as i32
+}
+
+/// Prints base composition for region query (1-based, inclusive, e.g.
+/// chr1:1000-1010) in format: chr, pos, A, C, G, T, N, deletions.
+pub fn main_pileup(
+    gbam_file: File,
+    index_file: Option<Arc<Vec<u32>>>,
+    region: &str,
+    min_base_qual: Option<u8>,
+) {
+    let (chr, left, right) =
+        bed::parse_region_query(region).expect("The region query is incorrect. Example: chr1:1000-1010");
+    let template = ParsingTemplate::new_with(&pileup_fields(min_base_qual.is_some()));
+    let mut reader = Reader::new_with_index(gbam_file, template, index_file).unwrap();
+
+    let ref_id = find_ref_id(&reader, chr);
+
+    let start = left.saturating_sub(1);
+    let counts = pileup_region(&mut reader, ref_id, start, right, min_base_qual);
+
+    let st = std::io::stdout();
+    let mut out = BufWriter::with_capacity(64 * 1024, st.lock());
+    for (i, c) in counts.iter().enumerate() {
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            chr,
+            start as usize + i + 1,
+            c.a,
+            c.c,
+            c.g,
+            c.t,
+            c.n,
+            c.del
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::{Cigar, Op};
+
+    fn op(len: u32, code: u32) -> Op {
+        Op::new(len << 4 | code)
+    }
+
+    #[test]
+    fn test_pileup_counts() {
+        // 3M 2D 1N 2M starting at position 10.
+        let rec = GbamRecord {
+            pos: Some(10),
+            cigar: Some(Cigar::new(vec![op(3, 0), op(2, 2), op(1, 3), op(2, 0)])),
+            seq: Some(String::from("ACGTA")),
+            qual: Some(vec![30, 30, 5, 30, 30]),
+            ..Default::default()
+        };
+        let mut counts = vec![BaseCounts::default(); 10];
+        add_record_to_pileup(&rec, 9, 19, Some(20), &mut counts);
+
+        assert_eq!(counts[0].depth(), 0);
+        assert_eq!(counts[1].a, 1);
+        assert_eq!(counts[2].c, 1);
+        // Low base quality is filtered out.
+        assert_eq!(counts[3].depth(), 0);
+        assert_eq!(counts[4].del, 1);
+        assert_eq!(counts[5].del, 1);
+        // Splice is not counted.
+        assert_eq!(counts[6].depth(), 0);
+        assert_eq!(counts[7].t, 1);
+        assert_eq!(counts[8].a, 1);
+        assert_eq!(counts[9].depth(), 0);
+    }
+
+    #[test]
+    fn test_pileup_without_seq() {
+        let rec = GbamRecord {
+            pos: Some(10),
+            cigar: Some(Cigar::new(vec![op(2, 0), op(1, 2), op(1, 0)])),
+            seq: Some(String::new()),
+            qual: Some(Vec::new()),
+            ..Default::default()
+        };
+        let mut counts = vec![BaseCounts::default(); 4];
+        add_record_to_pileup(&rec, 10, 14, Some(20), &mut counts);
+
+        assert_eq!(counts.iter().map(|c| c.n).collect::<Vec<_>>(), vec![1, 1, 0, 1]);
+        assert_eq!(counts[2].del, 1);
+    }
+}
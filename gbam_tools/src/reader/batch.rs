@@ -0,0 +1,253 @@
+//! Columnar access to GBAM records: whole columns of records range are decoded
+//! into arrays, without filling `GbamRecord` per record.
+use super::column::{block_ranges, read_block};
+use super::reader::Reader;
+use bam_tools::record::fields::{field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM};
+use std::ops::Range;
+
+/// Decoded column of records range. Fixed sized fields are stored by item size:
+/// 1 byte (Mapq), 2 bytes (Bin, Flags), 4 bytes (the rest).
+pub enum BatchColumn {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    I32(Vec<i32>),
+    Variable(VariableData),
+}
+
+/// Items of variable sized field stored back to back. Item `i` is located at
+/// `data[offsets[i]..offsets[i + 1]]`.
+pub struct VariableData {
+    offsets: Vec<u32>,
+    data: Vec<u8>,
+}
+
+impl VariableData {
+    /// Consumes items, returns offsets and data.
+    pub fn into_parts(self) -> (Vec<u32>, Vec<u8>) {
+        (self.offsets, self.data)
+    }
+
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn get(&self, item_num: usize) -> &[u8] {
+        &self.data[self.offsets[item_num] as usize..self.offsets[item_num + 1] as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Columns of parsing template fields for range of records.
+pub struct Batch {
+    range: Range<usize>,
+    columns: Vec<Option<BatchColumn>>,
+}
+
+impl Batch {
+    /// Physical numbers of records in batch.
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.range.is_empty()
+    }
+
+    /// Consumes batch, returns decoded columns in order of fields.
+    pub fn into_columns(self) -> Vec<(Fields, BatchColumn)> {
+        Fields::iterator()
+            .zip(self.columns)
+            .filter_map(|(field, column)| column.map(|column| (*field, column)))
+            .collect()
+    }
+
+    pub fn column(&self, field: &Fields) -> &BatchColumn {
+        self.columns[*field as usize]
+            .as_ref()
+            .expect("Field is not present in parsing template.")
+    }
+
+    pub fn u8s(&self, field: &Fields) -> &[u8] {
+        match self.column(field) {
+            BatchColumn::U8(items) => items,
+            _ => panic!("Field {} is not 1 byte sized.", field),
+        }
+    }
+
+    pub fn u16s(&self, field: &Fields) -> &[u16] {
+        match self.column(field) {
+            BatchColumn::U16(items) => items,
+            _ => panic!("Field {} is not 2 bytes sized.", field),
+        }
+    }
+
+    pub fn i32s(&self, field: &Fields) -> &[i32] {
+        match self.column(field) {
+            BatchColumn::I32(items) => items,
+            _ => panic!("Field {} is not 4 bytes sized.", field),
+        }
+    }
+
+    pub fn variable(&self, field: &Fields) -> &VariableData {
+        match self.column(field) {
+            BatchColumn::Variable(items) => items,
+            _ => panic!("Field {} is not variable sized.", field),
+        }
+    }
+}
+
+/// Reads batches of records of fields active in reader parsing template. Keeps
+/// the last decompressed block of every column, so consecutive batches which
+/// share a block decompress it once. Record numbers are physical ones, index
+/// mapping is not applied.
+pub struct BatchReader<'a> {
+    reader: &'a Reader,
+    blocks: Vec<Vec<Range<usize>>>,
+    cache: Vec<Option<(usize, Vec<u8>)>>,
+}
+
+impl<'a> BatchReader<'a> {
+    pub fn new(reader: &'a Reader) -> Self {
+        Self {
+            blocks: Fields::iterator().map(|field| block_ranges(&reader.file_meta, field)).collect(),
+            cache: (0..FIELDS_NUM).map(|_| None).collect(),
+            reader,
+        }
+    }
+
+    pub fn read(&mut self, range: Range<usize>) -> Batch {
+        assert!(range.end <= self.reader.amount, "Batch range is out of bounds.");
+        let mut columns: Vec<Option<BatchColumn>> = (0..FIELDS_NUM).map(|_| None).collect();
+        for &field in self.reader.parsing_template.get_active_data_fields_iter() {
+            columns[field as usize] = Some(match field_type(&field) {
+                FieldType::FixedSized => self.read_fixed(field, range.clone()),
+                FieldType::VariableSized => BatchColumn::Variable(self.read_variable(field, range.clone())),
+            });
+        }
+        Batch { range, columns }
+    }
+
+    /// Calls `f` with decompressed data, records range and overlap with `range`
+    /// of every block of the field which overlaps `range`.
+    fn for_each_block<F>(&mut self, field: Fields, range: Range<usize>, mut f: F)
+    where
+        F: FnMut(&[u8], &Range<usize>, Range<usize>),
+    {
+        let blocks = &self.blocks[field as usize];
+        let first = blocks.partition_point(|block| block.end <= range.start);
+        for (block_num, block_range) in blocks.iter().enumerate().skip(first) {
+            if block_range.start >= range.end {
+                break;
+            }
+            let cache = &mut self.cache[field as usize];
+            if cache.as_ref().map(|(num, _)| *num) != Some(block_num) {
+                let meta = &self.reader.file_meta;
+                *cache = Some((block_num, read_block(self.reader.source.as_ref(), meta, &field, &meta.view_blocks(&field)[block_num])));
+            }
+            let overlap = std::cmp::max(range.start, block_range.start)..std::cmp::min(range.end, block_range.end);
+            f(&cache.as_ref().unwrap().1, block_range, overlap);
+        }
+    }
+
+    fn read_fixed(&mut self, field: Fields, range: Range<usize>) -> BatchColumn {
+        let item_size = self.reader.file_meta.get_field_size(&field).unwrap() as usize;
+        let mut bytes = Vec::with_capacity(range.len() * item_size);
+        self.for_each_block(field, range, |data, block_range, overlap| {
+            bytes.extend_from_slice(&data[(overlap.start - block_range.start) * item_size..(overlap.end - block_range.start) * item_size]);
+        });
+        match item_size {
+            1 => BatchColumn::U8(bytes),
+            2 => BatchColumn::U16(bytes.chunks_exact(2).map(|item| u16::from_le_bytes([item[0], item[1]])).collect()),
+            _ => BatchColumn::I32(bytes.chunks_exact(4).map(|item| i32::from_le_bytes([item[0], item[1], item[2], item[3]])).collect()),
+        }
+    }
+
+    fn read_variable(&mut self, field: Fields, range: Range<usize>) -> VariableData {
+        // Index values are end offsets of items within their data block. The
+        // offset preceding the range is needed if range starts inside of block.
+        let index_start = range.start.saturating_sub(1);
+        let index = match self.read_fixed(var_size_field_to_index(&field), index_start..range.end) {
+            BatchColumn::I32(index) => index,
+            _ => unreachable!(),
+        };
+        let end_offset = |rec_num: usize| index[rec_num - index_start] as u32 as usize;
+
+        let mut offsets = Vec::with_capacity(range.len() + 1);
+        offsets.push(0);
+        let mut data = Vec::new();
+        self.for_each_block(field, range, |block_data, block_range, overlap| {
+            if overlap.is_empty() {
+                return;
+            }
+            let start = match overlap.start == block_range.start {
+                true => 0,
+                false => end_offset(overlap.start - 1),
+            };
+            let base = data.len();
+            data.extend_from_slice(&block_data[start..end_offset(overlap.end - 1)]);
+            offsets.extend(overlap.map(|rec_num| (base + end_offset(rec_num) - start) as u32));
+        });
+        VariableData { offsets, data }
+    }
+}
+
+impl Reader {
+    /// Iterator over batches of about `batch_size` records, cut at block
+    /// boundaries (see `plan_chunks`).
+    pub fn batches(&self, batch_size: usize) -> impl Iterator<Item = Batch> + '_ {
+        let mut batch_reader = BatchReader::new(self);
+        self.plan_chunks(batch_size).into_iter().map(move |range| batch_reader.read(range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::{reader_from_records, test_record};
+    use crate::reader::record::GbamRecord;
+    use crate::SIZE_LIMIT;
+
+    /// Tags of record `i` are `SIZE_LIMIT / 3 + i` bytes of value `i`, so
+    /// every tags block holds two records.
+    fn tags(i: usize) -> Vec<u8> {
+        vec![i as u8; SIZE_LIMIT / 3 + i]
+    }
+
+    #[test]
+    fn test_read_variable() {
+        let records: Vec<GbamRecord> = (0..4)
+            .map(|i| GbamRecord {
+                tags: Some(tags(i)),
+                ..test_record(-1, -1)
+            })
+            .collect();
+        let reader = reader_from_records(Vec::new(), &records, ParsingTemplate::new_with(&[Fields::RawTags]));
+        assert_eq!(block_ranges(&reader.file_meta, &Fields::RawTags), vec![0..2, 2..4]);
+
+        // Starts at 0, starts in the middle of block, crosses two blocks.
+        for range in [0..1, 0..2, 1..2, 3..4, 1..3, 1..4, 0..4] {
+            let mut batch_reader = BatchReader::new(&reader);
+            let items = batch_reader.read_variable(Fields::RawTags, range.clone());
+            assert_eq!(items.len(), range.len());
+            for (item_num, rec_num) in range.enumerate() {
+                assert_eq!(items.get(item_num), &tags(rec_num)[..]);
+            }
+        }
+    }
+}
@@ -1,16 +1,16 @@
-use std::{collections::BTreeMap, io::Result, sync::Arc};
+use std::{collections::BTreeMap, io::Result, ops::Range, sync::Arc};
 
 use super::reader::generate_block_treemap;
 use super::record::GbamRecord;
+use super::source::ByteSource;
 use crate::SIZE_LIMIT;
 use lzzzz::{lz4};
 use bam_tools::record::fields::Fields;
 use byteorder::{LittleEndian, ReadBytesExt};
 use flate2::write::GzDecoder;
-use memmap2::Mmap;
 use std::convert::TryFrom;
 
-use crate::{meta::FileMeta, Codecs};
+use crate::{meta::{BlockMeta, FileMeta}, Codecs};
 
 // Contains fields needed both for fixed sized fields and variable sized fields.
 pub struct Inner {
@@ -20,11 +20,11 @@ pub struct Inner {
     range_end: usize,
     field: Fields,
     buffer: Vec<u8>,
-    reader: Arc<Mmap>,
+    reader: Arc<dyn ByteSource>,
 }
 
 impl Inner {
-    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Arc<Mmap>) -> Self {
+    pub(crate) fn new(meta: Arc<FileMeta>, field: Fields, reader: Arc<dyn ByteSource>) -> Self {
         Inner {
             meta,
             range_begin: 0,
@@ -44,47 +44,65 @@ pub trait Column {
 }
 
 /// GBAM file column. Responsible for fetching data.
-pub struct FixedColumn(Inner, usize);
+pub struct FixedColumn {
+    inner: Inner,
+    item_size: usize,
+    // Used to quickly determine what block record belongs to. Blocks may
+    // contain different amount of items if file was concatenated.
+    blocks: BTreeMap<usize, usize>,
+}
 
 impl Column for FixedColumn {
     /// Fetches data into provider record buffer. If item is located outside of
     /// currently loaded data block, the new block will be loaded and
     /// decompressed.
     fn fill_record_field(&mut self, item_num: usize, rec: &mut GbamRecord) {
-        rec.parse_from_bytes(&self.0.field.clone(), self.get_item(item_num));
+        rec.parse_from_bytes(&self.inner.field.clone(), self.get_item(item_num));
     }
 }
 
 impl FixedColumn {
-    pub fn new(inner: Inner, field_size: usize) -> Self {
-        Self(inner, field_size)
+    pub fn new(inner: Inner, item_size: usize) -> Self {
+        Self {
+            blocks: generate_block_treemap(&inner.meta, &inner.field),
+            inner,
+            item_size,
+        }
     }
     fn get_item(&mut self, item_num: usize) -> &[u8] {
-        if let Some(block_num) = self.find_block(item_num) {
-            Self::update_buffer(&mut self.0, block_num);
+        if let Some((range_begin, block_num)) = find_block(&self.inner, &self.blocks, item_num) {
+            update_buffer(&mut self.inner, block_num, range_begin);
         }
-        let rec_num_in_block = item_num - self.0.range_begin;
-        let item_size = self.1;
+        let rec_num_in_block = item_num - self.inner.range_begin;
+        let item_size = self.item_size;
         let offset = rec_num_in_block * item_size;
-        &self.0.buffer[offset..offset + item_size]
-    }
-    // Finds blocks where record is located. None is returned if block is already loaded.
-    fn find_block(&self, item_num: usize) -> Option<usize> {
-        if item_num >= self.0.range_begin && item_num < self.0.range_end {
-            return None;
-        }
-        // All blocks sizes are equal except maybe the last one since it's a fixed sized column and block size limit is constant.
-        let block_len = self.0.meta.view_blocks(&self.0.field)[0].numitems;
-        Some(item_num / block_len as usize)
+        &self.inner.buffer[offset..offset + item_size]
     }
+}
 
-    fn update_buffer(inner: &mut Inner, block_num: usize) {
-        fetch_block(inner, block_num).unwrap();
-        let block_len = inner.meta.view_blocks(&inner.field)[0].numitems as usize;
-        let cur_block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
-        inner.range_begin = block_num * block_len;
-        inner.range_end = inner.range_begin + cur_block_len;
+/// Finds block where record is located. None is returned if block is already
+/// loaded.
+fn find_block(inner: &Inner, blocks: &BTreeMap<usize, usize>, item_num: usize) -> Option<(usize, usize)> {
+    if item_num >= inner.range_begin && item_num < inner.range_end {
+        return None;
     }
+    // To determine what block record N is in.
+    Some(
+        blocks
+            // Inclusive range.
+            .range(..=item_num)
+            .next_back()
+            .map_or((0, 0), |(&range_begin, &block_num)| {
+                (range_begin, block_num)
+            }),
+    )
+}
+
+fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) {
+    fetch_block(inner, block_num).unwrap();
+    let block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
+    inner.range_begin = range_begin;
+    inner.range_end = inner.range_begin + block_len;
 }
 
 /// Column managing access to variable sized data. Utilizes another column (for fixed sized fields) to index data.
@@ -111,8 +129,8 @@ impl VariableColumn {
     }
 
     fn get_item(&mut self, item_num: usize) -> &[u8] {
-        if let Some((range_begin, block_num)) = self.find_block(item_num) {
-            Self::update_buffer(&mut self.inner, block_num, range_begin);
+        if let Some((range_begin, block_num)) = find_block(&self.inner, &self.blocks, item_num) {
+            update_buffer(&mut self.inner, block_num, range_begin);
         }
         let rec_num_in_block = item_num - self.inner.range_begin;
         let mut read_offset =
@@ -124,30 +142,6 @@ impl VariableColumn {
         let end = read_offset(item_num);
         &self.inner.buffer[start..end]
     }
-
-    // Finds blocks where record is located. None is returned if block is already loaded.
-    fn find_block(&self, item_num: usize) -> Option<(usize, usize)> {
-        if item_num >= self.inner.range_begin && item_num < self.inner.range_end {
-            return None;
-        }
-        // To determine what block record N is in.
-        Some(
-            self.blocks
-                // Inclusive range.
-                .range(..=item_num)
-                .next_back()
-                .map_or((0, 0), |(&range_begin, &block_num)| {
-                    (range_begin, block_num)
-                }),
-        )
-    }
-
-    fn update_buffer(inner: &mut Inner, block_num: usize, range_begin: usize) {
-        fetch_block(inner, block_num).unwrap();
-        let block_len = inner.meta.view_blocks(&inner.field)[block_num].numitems as usize;
-        inner.range_begin = range_begin;
-        inner.range_end = inner.range_begin + block_len;
-    }
 }
 
 /// Fetch and decompress a data block.
@@ -159,20 +153,41 @@ fn fetch_block(inner_column: &mut Inner, block_num: usize) -> Result<()> {
     let block_size = block_meta.block_size;
     let uncompressed_size = block_meta.uncompressed_size;
 
-    let data =
-        &reader[usize::try_from(block_meta.seekpos).unwrap()..usize::try_from(block_meta.seekpos + block_size as u64).unwrap()];
+    let data = reader.read_at(usize::try_from(block_meta.seekpos).unwrap(), block_size as usize)?;
     // inner_column.buffer.clear();
     // dbg!(uncompressed_size);
     inner_column.buffer.resize(uncompressed_size as usize, 0);
     let codec = inner_column.meta.get_field_codec(field);
 
     if uncompressed_size > 0 {
-        decompress_block(data, &mut inner_column.buffer, codec).expect("Decompression failed.");
+        decompress_block(&data, &mut inner_column.buffer, codec).expect("Decompression failed.");
     }
     
     Ok(())
 }
 
+/// Record numbers range of every block of the field.
+pub(crate) fn block_ranges(meta: &FileMeta, field: &Fields) -> Vec<Range<usize>> {
+    let mut start = 0;
+    meta.view_blocks(field)
+        .iter()
+        .map(|block| {
+            let range = start..start + block.numitems as usize;
+            start = range.end;
+            range
+        })
+        .collect()
+}
+
+/// Reads and decompresses block of the field into new buffer.
+pub(crate) fn read_block(source: &dyn ByteSource, meta: &FileMeta, field: &Fields, block: &BlockMeta) -> Vec<u8> {
+    let start = block.seekpos as usize;
+    let mut buf = vec![0; block.uncompressed_size as usize];
+    if block.uncompressed_size > 0 {
+        decompress_block(&source.read_at(start, block.block_size as usize).unwrap(), &mut buf, meta.get_field_codec(field)).expect("Decompression failed.");
+    }
+    buf
+}
 
 pub(crate) fn decompress_block(source: &[u8], dest: &mut Vec<u8>, codec: &Codecs) -> std::io::Result<()> {
     use std::io::Write;
@@ -0,0 +1,125 @@
+//! Parallel processing of GBAM records.
+use super::{parse_tmplt::ParsingTemplate, reader::Reader};
+use crate::meta::FileMeta;
+use rayon::prelude::*;
+use std::fs::File;
+use std::ops::Range;
+
+/// Approximate amount of records processed by one task.
+pub const CHUNK_SIZE: usize = 500_000;
+
+/// Splits records into chunks of about `chunk_size` records. Chunks are cut at
+/// block boundaries of active fields; where boundaries of different fields do
+/// not coincide, the cut which splits the least amount of uncompressed data is
+/// chosen, since split blocks are decompressed by both adjacent tasks.
+pub(crate) fn plan_chunks(file_meta: &FileMeta, template: &ParsingTemplate, amount: usize, chunk_size: usize) -> Vec<Range<usize>> {
+    // (block ends, uncompressed sizes) of every active field.
+    let fields: Vec<(Vec<usize>, Vec<u64>)> = template
+        .get_active_fields_iter()
+        .map(|field| {
+            let blocks = file_meta.view_blocks(field);
+            let ends = blocks
+                .iter()
+                .scan(0, |end, block| {
+                    *end += block.numitems as usize;
+                    Some(*end)
+                })
+                .collect();
+            (ends, blocks.iter().map(|block| block.uncompressed_size).collect())
+        })
+        .collect();
+    let split_cost = |cut: usize| -> u64 {
+        fields
+            .iter()
+            .map(|(ends, sizes)| {
+                let block_num = ends.partition_point(|&end| end <= cut);
+                let block_start = if block_num == 0 { 0 } else { ends[block_num - 1] };
+                match block_start == cut || block_num == ends.len() {
+                    true => 0,
+                    false => sizes[block_num],
+                }
+            })
+            .sum()
+    };
+    let mut cuts: Vec<usize> = fields.iter().flat_map(|(ends, _)| ends.iter().copied()).filter(|&end| end < amount).collect();
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let chunk_size = std::cmp::max(chunk_size, 1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while amount - start > chunk_size + chunk_size / 2 {
+        let target = start + chunk_size;
+        let window = cuts.partition_point(|&cut| cut <= start + chunk_size / 2)..cuts.partition_point(|&cut| cut <= target + chunk_size / 2);
+        let end = match cuts[window].iter().min_by_key(|&&cut| (split_cost(cut), cut.abs_diff(target))) {
+            Some(&cut) => cut,
+            // No block boundary near, cut anyway to keep tasks small.
+            None => target,
+        };
+        chunks.push(start..end);
+        start = end;
+    }
+    if start < amount {
+        chunks.push(start..amount);
+    }
+    chunks
+}
+
+/// Processes records of GBAM file in parallel. Records are split into chunks
+/// aligned to block boundaries of template fields, `map_chunk` is called for
+/// every chunk with reader (reused by the tasks of a thread) and range of
+/// physical record numbers. Results are combined with `reduce` in order of
+/// chunks, so it may concatenate them.
+pub fn par_map_reduce<T, M, R>(file: &File, template: ParsingTemplate, map_chunk: M, reduce: R) -> T
+where
+    T: Default + Send,
+    M: Fn(&mut Reader, Range<usize>) -> T + Sync + Send,
+    R: Fn(T, T) -> T + Sync + Send,
+{
+    let reader = Reader::new(file.try_clone().unwrap(), template.clone()).unwrap();
+    let file_meta = reader.file_meta.clone();
+    reader
+        .plan_chunks(CHUNK_SIZE)
+        .into_par_iter()
+        .map_init(
+            || Reader::new_with_meta(file.try_clone().unwrap(), template.clone(), &file_meta, None).unwrap(),
+            |reader, range| map_chunk(reader, range),
+        )
+        .reduce(T::default, reduce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::BlockMeta;
+    use crate::Codecs;
+    use bam_tools::record::fields::Fields;
+
+    fn push_blocks(file_meta: &mut FileMeta, field: &Fields, numitems: &[u32]) {
+        for &n in numitems {
+            file_meta.get_blocks(field).push(BlockMeta {
+                seekpos: 0,
+                numitems: n,
+                block_size: 0,
+                uncompressed_size: n as u64 * 4,
+                stats: None,
+                name_filter: None,
+            });
+        }
+    }
+
+    #[test]
+    fn test_plan_chunks() {
+        let mut file_meta = FileMeta::new(Codecs::Lz4, Vec::new(), Vec::new());
+        push_blocks(&mut file_meta, &Fields::RefID, &[100, 100, 100, 100]);
+        push_blocks(&mut file_meta, &Fields::Pos, &[90, 110, 90, 110]);
+        let template = ParsingTemplate::new_with(&[Fields::RefID, Fields::Pos]);
+
+        // 200 is a boundary of both fields.
+        assert_eq!(plan_chunks(&file_meta, &template, 400, 180), vec![0..200, 200..400]);
+        // Only RefID is active, so the cut closest to the target is taken.
+        let template = ParsingTemplate::new_with(&[Fields::RefID]);
+        assert_eq!(plan_chunks(&file_meta, &template, 400, 120), vec![0..100, 100..200, 200..300, 300..400]);
+        assert_eq!(plan_chunks(&file_meta, &template, 400, 1000), vec![0..400]);
+    }
+}
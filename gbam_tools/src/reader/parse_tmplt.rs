@@ -1,13 +1,9 @@
-#[cfg(feature = "python-ffi")]
-use pyo3::prelude::*;
-
 use bam_tools::record::fields::{
     field_type, is_data_field, var_size_field_to_index, FieldType, Fields,
     FIELDS_NUM,
 };
 
 /// This struct regulates what fields are getting parsed from GBAM file.
-#[cfg_attr(feature = "python-ffi", pyclass)]
 #[derive(Clone, Debug)]
 pub struct ParsingTemplate {
     inner: Vec<Option<Fields>>,
@@ -117,17 +113,32 @@ impl Default for ParsingTemplate {
     }
 }
 
-#[cfg(feature = "python-ffi")]
-#[pymethods]
-impl ParsingTemplate {
-    #[new]
-    /// Create new ParsingTemplate with predefined values
-    pub fn new_from_python(fields: Vec<bool>) -> Self {
-        assert_eq!(fields.len(), DATA_FIELDS_NUM);
-        let mut tmplt = ParsingTemplate::new();
-        for (field, val) in Fields::iterator().zip(fields) {
-            tmplt.set(field, val);
-        }
-        tmplt
-    }
+/// Fields with their names, as in SAM specification.
+const FIELD_NAMES: [(Fields, &str); 16] = [
+    (Fields::RefID, "ref_id"),
+    (Fields::Pos, "pos"),
+    (Fields::LName, "l_read_name"),
+    (Fields::Mapq, "mapq"),
+    (Fields::Bin, "bin"),
+    (Fields::NCigar, "n_cigar_op"),
+    (Fields::Flags, "flag"),
+    (Fields::SequenceLength, "l_seq"),
+    (Fields::NextRefID, "next_ref_id"),
+    (Fields::NextPos, "next_pos"),
+    (Fields::TemplateLength, "tlen"),
+    (Fields::ReadName, "read_name"),
+    (Fields::RawCigar, "cigar"),
+    (Fields::RawSequence, "seq"),
+    (Fields::RawQual, "qual"),
+    (Fields::RawTags, "tags"),
+];
+
+/// Field name, as in SAM specification. Internal index fields have no name.
+pub fn field_name(field: &Fields) -> Option<&'static str> {
+    FIELD_NAMES.iter().find(|(named, _)| named == field).map(|(_, name)| *name)
+}
+
+/// Field by its name (see `field_name`).
+pub fn field_by_name(name: &str) -> Option<Fields> {
+    FIELD_NAMES.iter().find(|(_, named)| *named == name).map(|(field, _)| *field)
 }
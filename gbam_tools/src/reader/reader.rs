@@ -1,22 +1,27 @@
 use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::{borrow::Borrow, fs::File};
+use std::ops::Range;
+use std::io::{BufReader, Read};
+use std::fs::File;
 
 use bam_tools::record::fields::{
     field_type, var_size_field_to_index, FieldType, Fields, FIELDS_NUM,
 };
 use byteorder::LittleEndian;
-use memmap2::MmapOptions;
 use memmap2::Mmap;
 
-use crate::meta::{FileInfo, FileMeta, FILE_INFO_SIZE, BlockMeta};
+use crate::meta::{FileInfo, FileMeta, SortOrder, FILE_INFO_SIZE, BlockMeta};
+use crate::name_filter;
+use crate::U32_SIZE;
 use crate::writer::calc_crc_for_meta_bytes;
 
 use super::{
     column::{Column, FixedColumn, Inner, VariableColumn},
+    parallel,
     parse_tmplt::ParsingTemplate,
     record::GbamRecord,
     records::Records,
+    source::ByteSource,
 };
 
 use std::convert::TryFrom;
@@ -28,59 +33,88 @@ pub struct Reader {
     original_template: ParsingTemplate,
     pub amount: usize,
     pub file_meta: Arc<FileMeta>,
-    // Kept so File won't drop while used by mmap.
-    _inner: Box<File>,
     index_mapping: Option<Arc<Vec<u32>>>,
-    pub mmap: Arc<Mmap>
+    pub source: Arc<dyn ByteSource>,
 }
 
 impl Reader {
     pub fn new(inner: File, parsing_template: ParsingTemplate) -> std::io::Result<Self> {
-        let inner = inner;
-        let mmap = unsafe { Mmap::map(inner.borrow())? };
-        let file_meta = verify_and_parse_meta(&mmap)?;
-        Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), None)
+        Self::new_with_index(inner, parsing_template, None)
     }
 
     pub fn new_with_index(inner: File, parsing_template: ParsingTemplate, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
-        let inner = inner;
-        let mmap = unsafe { Mmap::map(inner.borrow())? };
-        let file_meta = verify_and_parse_meta(&mmap)?;
-        Self::new_with_meta(inner, parsing_template, &Arc::new(file_meta), index_mapping)
-    }
-
-    pub fn new_with_meta(_inner: File, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
-        let _copy = _inner.try_clone()?;
-        let _inner: Box<File> = Box::new(_inner);
-        
-        let mmap = Arc::new(unsafe { MmapOptions::new().map(&_copy)? });
-        // mmap.advise(memmap2::Advice::WillNeed)?;
+        Self::from_source(map_file(&inner)?, parsing_template, index_mapping)
+    }
+
+    pub fn new_with_meta(inner: File, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
+        Self::from_source_with_meta(map_file(&inner)?, parsing_template, file_meta, index_mapping)
+    }
+
+    /// Reads GBAM file from any random access byte source, like in-memory
+    /// `Cursor<Vec<u8>>` or `Mutex<File>` where mmap is not available.
+    pub fn from_source(source: Arc<dyn ByteSource>, parsing_template: ParsingTemplate, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
+        let file_meta = verify_and_parse_meta(source.as_ref())?;
+        Self::from_source_with_meta(source, parsing_template, &Arc::new(file_meta), index_mapping)
+    }
+
+    pub fn from_source_with_meta(source: Arc<dyn ByteSource>, parsing_template: ParsingTemplate, file_meta: &Arc<FileMeta>, index_mapping: Option<Arc<Vec<u32>>>) -> std::io::Result<Self> {
         // Consumes up to 16 percent of runtime on big files (20GB).
-        // verify(&mmap)?;
+        // verify(source.as_ref())?;
         let amount = usize::try_from(file_meta
             .view_blocks(&Fields::RefID)
             .iter()
             .fold(0, |acc: u64, x| acc + u64::from(x.numitems))).unwrap();
         let meta = file_meta.clone();
 
-        
         Ok(Self {
-            columns: init_columns(&mmap, &parsing_template, &meta),
+            columns: init_columns(&source, &parsing_template, &meta),
             original_template: parsing_template.clone(),
             parsing_template,
             file_meta: meta,
             amount,
-            _inner,
-            mmap,
-            index_mapping: index_mapping.clone(),
+            source,
+            index_mapping,
         })
     }
 
+    /// Order of records in file, as written.
+    pub fn sort_order(&self) -> SortOrder {
+        parse_file_info(self.source.as_ref()).sort_order()
+    }
+
+    /// Order of records as they are physically laid out. Files written with
+    /// index sort are marked as coordinate sorted, but only their index file
+    /// is sorted.
+    pub fn physical_sort_order(&self) -> SortOrder {
+        parse_file_info(self.source.as_ref()).physical_sort_order(self.is_index_mapped())
+    }
+
+    /// Whether records are accessed through index file, so their order differs
+    /// from the physical one.
+    pub fn is_index_mapped(&self) -> bool {
+        self.index_mapping.is_some()
+    }
+
+    /// Whether records are accessed in coordinate order, either because they
+    /// are physically sorted or through index file.
+    pub fn is_coordinate_sorted(&self) -> bool {
+        let sort_order = match self.is_index_mapped() {
+            true => self.sort_order(),
+            false => self.physical_sort_order(),
+        };
+        sort_order == SortOrder::Coordinate
+    }
+
     #[inline(always)]
     pub fn fill_record(&mut self, mut rec_num: usize, rec: &mut GbamRecord) {
         if let Some(index_map) = &self.index_mapping {
             rec_num = index_map[rec_num] as usize;
         }
+        self.fill_physical_record(rec_num, rec);
+    }
+
+    /// Fills record by its number in file, ignoring index mapping.
+    fn fill_physical_record(&mut self, rec_num: usize, rec: &mut GbamRecord) {
         assert!(rec_num < self.amount);
         for &field in self.parsing_template.get_active_data_fields_iter() {
             self.columns[field as usize]
@@ -109,51 +143,118 @@ impl Reader {
         self.parsing_template = self.original_template.clone();
     }
 
+    /// Returns all alignments with read name `name` (without trailing NUL), in
+    /// order of the file. Only blocks which may contain the name according to
+    /// read name filter are decompressed; if the file has no filter, all blocks
+    /// are scanned. Records are filled according to parsing template.
+    pub fn find_by_name(&mut self, name: &[u8]) -> Vec<GbamRecord> {
+        if self.columns[Fields::ReadName as usize].is_none() {
+            self.columns[Fields::ReadName as usize] = Some(init_col(Fields::ReadName, &self.source, &self.file_meta));
+        }
+        let file_meta = self.file_meta.clone();
+        let mut found = Vec::new();
+        let mut rec = GbamRecord::default();
+
+        let mut block_start = 0;
+        for block in file_meta.view_blocks(&Fields::ReadName) {
+            let block_end = block_start + block.numitems as usize;
+            let candidate = block.name_filter.as_ref().is_none_or(|filter| {
+                let start = filter.seekpos as usize;
+                let bits = self.source.read_at(start, filter.size as usize).unwrap();
+                name_filter::may_contain(&bits, filter.num_hashes, name)
+            });
+            if candidate {
+                for rec_num in block_start..block_end {
+                    self.fetch_only(&[Fields::ReadName]);
+                    self.fill_physical_record(rec_num, &mut rec);
+                    if name_filter::strip_nul(rec.read_name.as_ref().unwrap()) != name {
+                        continue;
+                    }
+                    self.restore_template();
+                    let mut found_rec = GbamRecord::default();
+                    self.fill_physical_record(rec_num, &mut found_rec);
+                    found.push(found_rec);
+                }
+            }
+            block_start = block_end;
+        }
+        self.restore_template();
+        found
+    }
+
+    /// Splits records into chunks of about `chunk_size` records for parallel
+    /// processing. Chunks are cut at block boundaries of columns active in
+    /// parsing template where possible, so adjacent chunks rarely decompress
+    /// the same block. Record numbers are physical ones.
+    pub fn plan_chunks(&self, chunk_size: usize) -> Vec<Range<usize>> {
+        parallel::plan_chunks(&self.file_meta, &self.original_template, self.amount, chunk_size)
+    }
+
     /// Get iterator over all GBAM records (according to parsing template).
     pub fn records(&mut self) -> Records {
         Records::new(self)
     }
+
+    /// Get iterator over GBAM records with numbers in range (according to
+    /// parsing template and index mapping).
+    pub fn records_range(&mut self, range: Range<usize>) -> Records<'_> {
+        Records::with_range(self, range)
+    }
+}
+
+/// Reads index file of index sorted GBAM file: physical numbers of records in
+/// sorted order, as little endian u32.
+pub fn read_index(file: File) -> Arc<Vec<u32>> {
+    let mut bytes = Vec::new();
+    BufReader::new(file).read_to_end(&mut bytes).unwrap();
+    Arc::new(bytes.chunks_exact(U32_SIZE).map(|item| u32::from_le_bytes([item[0], item[1], item[2], item[3]])).collect())
+}
+
+/// Maps file into memory. File may be closed afterwards, mapping stays valid.
+fn map_file(file: &File) -> std::io::Result<Arc<dyn ByteSource>> {
+    Ok(Arc::new(unsafe { Mmap::map(file)? }))
 }
 
 fn init_columns(
-    mmap: &Arc<Mmap>,
+    source: &Arc<dyn ByteSource>,
     parse_template: &ParsingTemplate,
     meta: &Arc<FileMeta>,
 ) -> Vec<Option<Box<dyn Column + Send>>> {
     let mut res = Vec::new();
     (0..FIELDS_NUM).for_each(|_| res.push(None));
     for &field in parse_template.get_active_fields_iter() {
-        res[field as usize] = Some(init_col(field, mmap, meta));
+        res[field as usize] = Some(init_col(field, source, meta));
     }
     res
 }
 
-fn init_col(field: Fields, mmap: &Arc<Mmap>, meta: &Arc<FileMeta>) -> Box<dyn Column + Send> {
-    let inner = Inner::new(meta.clone(), field, mmap.clone());
+fn init_col(field: Fields, source: &Arc<dyn ByteSource>, meta: &Arc<FileMeta>) -> Box<dyn Column + Send> {
+    let inner = Inner::new(meta.clone(), field, source.clone());
     match field_type(&field) {
         FieldType::FixedSized => Box::new(FixedColumn::new(inner, meta.get_field_size(&field).unwrap() as usize)),
         FieldType::VariableSized => {
             let idx_field = var_size_field_to_index(&field);
-            let idx_inner = Inner::new(meta.clone(), idx_field, mmap.clone());
+            let idx_inner = Inner::new(meta.clone(), idx_field, source.clone());
             let idx_col = FixedColumn::new(idx_inner, meta.get_field_size(&idx_field).unwrap() as usize);
             Box::new(VariableColumn::new(inner, idx_col))
         }
     }
 }
 
-fn parse_file_info(mmap: &Mmap) -> FileInfo {
-    let file_info_bytes = &mmap[0..FILE_INFO_SIZE];
+fn parse_file_info(source: &dyn ByteSource) -> FileInfo {
+    let file_info_bytes = source.read_at(0, FILE_INFO_SIZE).unwrap();
     let end_of_json = file_info_bytes.iter().position(|&r| r == 0).unwrap();
     let file_info_str = String::from_utf8(file_info_bytes[..end_of_json].to_owned()).unwrap();
     serde_json::from_str(&file_info_str).expect("File meta json string was damaged.")
 }
 
 #[allow(dead_code)]
-fn verify(mmap: &Mmap) -> std::io::Result<()>{
-    let file_info = parse_file_info(mmap);
+fn verify(source: &dyn ByteSource) -> std::io::Result<()>{
+    let file_info = parse_file_info(source);
     // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
-    if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
+    let seekpos = file_info.seekpos as usize;
+    let buf = source.read_at(seekpos, source.len()?.saturating_sub(seekpos))?;
+    if calc_crc_for_meta_bytes(&buf) != file_info.crc32 {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             "Metadata JSON was damaged.",
@@ -161,17 +262,18 @@ fn verify(mmap: &Mmap) -> std::io::Result<()>{
     }
     Ok(())
 }
-fn verify_and_parse_meta(mmap: &Mmap) -> std::io::Result<FileMeta> {
-    let file_info = parse_file_info(mmap);
+fn verify_and_parse_meta(source: &dyn ByteSource) -> std::io::Result<FileMeta> {
+    let file_info = parse_file_info(source);
     // Read file meta
-    let buf = &mmap[file_info.seekpos as usize..];
-    if calc_crc_for_meta_bytes(buf) != file_info.crc32 {
+    let seekpos = file_info.seekpos as usize;
+    let buf = source.read_at(seekpos, source.len()?.saturating_sub(seekpos))?;
+    if calc_crc_for_meta_bytes(&buf) != file_info.crc32 {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             "Metadata JSON was damaged.",
         ));
     }
-    let file_meta_json_str = String::from_utf8(buf.to_owned()).unwrap();
+    let file_meta_json_str = String::from_utf8(buf.into_owned()).unwrap();
     Ok(serde_json::from_str(&file_meta_json_str).expect("File meta json string was damaged."))
 }
 
@@ -188,3 +290,144 @@ pub(crate) fn generate_block_treemap(meta: &FileMeta, field: &Fields) -> BTreeMa
         })
         .collect()
 }
+
+/// Writes records into uncompressed GBAM file in memory.
+#[cfg(test)]
+pub(crate) fn write_to_memory(ref_seqs: Vec<(String, u32)>, records: &[GbamRecord]) -> Vec<u8> {
+    write_records_to_memory(ref_seqs, records, false, SortOrder::Unsorted)
+}
+
+/// Like `write_to_memory`, with read name filter written for every block.
+#[cfg(test)]
+pub(crate) fn write_to_memory_with_name_filter(ref_seqs: Vec<(String, u32)>, records: &[GbamRecord]) -> Vec<u8> {
+    write_records_to_memory(ref_seqs, records, true, SortOrder::Unsorted)
+}
+
+/// Like `write_to_memory`, for records sorted by coordinate: the file is
+/// marked as sorted and has position keys.
+#[cfg(all(test, feature = "parquet"))]
+pub(crate) fn write_sorted_to_memory(ref_seqs: Vec<(String, u32)>, records: &[GbamRecord]) -> Vec<u8> {
+    write_records_to_memory(ref_seqs, records, false, SortOrder::Coordinate)
+}
+
+#[cfg(test)]
+fn write_records_to_memory(ref_seqs: Vec<(String, u32)>, records: &[GbamRecord], name_filter: bool, sort_order: SortOrder) -> Vec<u8> {
+    use crate::bam::bam_to_gbam::{build_sam_header, set_header_sort_order};
+    use crate::writer::Writer;
+    use crate::Codecs;
+    use bam_tools::record::bamrawrecord::BAMRawRecord;
+    use std::borrow::Cow;
+    use std::io::Cursor;
+
+    let mut bytes = Vec::new();
+    let sam_header = set_header_sort_order(&build_sam_header("", &ref_seqs), sort_order);
+    let stats_fields = match sort_order {
+        SortOrder::Coordinate => vec![Fields::Pos],
+        _ => Vec::new(),
+    };
+    let mut writer = Writer::new(Cursor::new(&mut bytes), vec![Codecs::NoCompression; FIELDS_NUM], 1, stats_fields, ref_seqs, sam_header, String::new(), sort_order);
+    if name_filter {
+        writer.enable_name_filter();
+    }
+    let mut buf = Vec::new();
+    for rec in records {
+        rec.convert_to_bytes(&mut buf);
+        writer.push_record(&BAMRawRecord(Cow::Borrowed(&buf)));
+    }
+    writer.finish().unwrap();
+    drop(writer);
+    bytes
+}
+
+/// Record with all fields set for writing test files: mapped to `refid` at
+/// `pos` (unmapped if `refid` is -1), without CIGAR, sequence and tags. Other
+/// fields are set with struct update syntax.
+#[cfg(test)]
+pub(crate) fn test_record(refid: i32, pos: i32) -> GbamRecord {
+    use crate::query::cigar::Cigar;
+
+    GbamRecord {
+        refid: Some(refid),
+        pos: Some(pos),
+        mapq: Some(60),
+        bin: Some(0),
+        flag: Some(if refid < 0 { 4 } else { 0 }),
+        next_ref_id: Some(-1),
+        next_pos: Some(-1),
+        tlen: Some(0),
+        read_name: Some(b"r\0".to_vec()),
+        cigar: Some(Cigar::new(Vec::new())),
+        seq: Some(String::new()),
+        qual: Some(Vec::new()),
+        tags: Some(Vec::new()),
+    }
+}
+
+/// Checks that all fields of `rec` are equal to ones of `expected`.
+#[cfg(test)]
+pub(crate) fn assert_record_eq(rec: &GbamRecord, expected: &GbamRecord) {
+    assert_eq!(rec.refid, expected.refid);
+    assert_eq!(rec.pos, expected.pos);
+    assert_eq!(rec.mapq, expected.mapq);
+    assert_eq!(rec.bin, expected.bin);
+    assert_eq!(rec.flag, expected.flag);
+    assert_eq!(rec.next_ref_id, expected.next_ref_id);
+    assert_eq!(rec.next_pos, expected.next_pos);
+    assert_eq!(rec.tlen, expected.tlen);
+    assert_eq!(rec.read_name, expected.read_name);
+    let ops = |rec: &GbamRecord| rec.cigar.as_ref().unwrap().ops().map(|op| op.0).collect::<Vec<_>>();
+    assert_eq!(ops(rec), ops(expected));
+    assert_eq!(rec.seq, expected.seq);
+    assert_eq!(rec.qual, expected.qual);
+    assert_eq!(rec.tags, expected.tags);
+}
+
+/// Writes `records` to memory with `write_to_memory` and opens reader of them.
+#[cfg(test)]
+pub(crate) fn reader_from_records(ref_seqs: Vec<(String, u32)>, records: &[GbamRecord], template: ParsingTemplate) -> Reader {
+    let bytes = write_to_memory(ref_seqs, records);
+    Reader::from_source(Arc::new(std::io::Cursor::new(bytes)), template, None).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::cigar::{Cigar, Op};
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_reader_from_memory() {
+        let ref_seqs = vec![(String::from("chr1"), 100), (String::from("chr2"), 200)];
+        let records: Vec<GbamRecord> = (0..3)
+            .map(|i| GbamRecord {
+                mapq: Some(60 - i as u8),
+                bin: Some(4680 + i as u16),
+                flag: Some(99 + i as u16),
+                next_ref_id: Some(1 - i % 2),
+                next_pos: Some(100 + i),
+                tlen: Some(-i),
+                read_name: Some(format!("read{}\0", i).into_bytes()),
+                cigar: Some(Cigar::new(vec![Op::new(2 << 4), Op::new((i as u32 + 1) << 4 | 4)])),
+                seq: Some(String::from(&"ACGTAC"[..2 * (i as usize + 1)])),
+                qual: Some(vec![30 + i as u8; 2 * (i as usize + 1)]),
+                tags: Some(format!("NMi{}", i).into_bytes()),
+                ..test_record(i % 2, 10 * i)
+            })
+            .collect();
+
+        let mut template = ParsingTemplate::new();
+        template.set_all();
+        let mut reader = reader_from_records(ref_seqs.clone(), &records, template.clone());
+        assert_eq!(reader.amount, records.len());
+        assert_eq!(reader.file_meta.get_ref_seqs(), &ref_seqs);
+        assert_eq!(reader.sort_order(), SortOrder::Unsorted);
+        let mutex_source = Arc::new(Mutex::new(Cursor::new(write_to_memory(ref_seqs, &records))));
+        let mut mutex_reader = Reader::from_source(mutex_source, template, None).unwrap();
+        assert_eq!(mutex_reader.amount, records.len());
+        let read: Vec<GbamRecord> = reader.records().chain(mutex_reader.records()).collect();
+        for (expected, rec) in records.iter().cycle().zip(read) {
+            assert_record_eq(&rec, expected);
+        }
+    }
+}
@@ -1,8 +1,6 @@
 use std::io::Write;
 
 use itertools::Itertools;
-#[cfg(feature = "python-ffi")]
-use pyo3::prelude::*;
 
 use bam_tools::record::{
     bamrawrecord::{decode_seq, put_sequence},
@@ -11,10 +9,8 @@ use bam_tools::record::{
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::mem;
 
-#[cfg(not(feature = "python-ffi"))]
 use crate::{query::cigar::Cigar, query::cigar::Op, U32_SIZE};
 
-#[cfg(not(feature = "python-ffi"))]
 #[derive(Debug, Default)]
 /// Represents a GBAM record in which some fields may be omitted.
 pub struct GbamRecord {
@@ -46,62 +42,6 @@ pub struct GbamRecord {
     pub tags: Option<Vec<u8>>,
 }
 
-/// cfg_attribute currently doesn't work with pyo3(get)
-#[cfg(feature = "python-ffi")]
-#[pyclass]
-#[derive(Clone, Debug)]
-/// Represents a GBAM record in which some fields may be omitted.
-pub struct GbamRecord {
-    /// Reference sequence ID
-    #[pyo3(get)]
-    pub refid: Option<i32>,
-    /// 0-based leftmost coordinate
-    #[pyo3(get)]
-    pub pos: Option<i32>,
-    /// Mapping quality
-    #[pyo3(get)]
-    pub mapq: Option<u8>,
-    /// BAI index bin,
-    #[pyo3(get)]
-    pub bin: Option<u16>,
-    /// Bitwise flags
-    #[pyo3(get)]
-    pub flag: Option<u16>,
-    /// Ref-ID of the next segment
-    #[pyo3(get)]
-    pub next_ref_id: Option<i32>,
-    /// 0-based leftmost pos of the next segmen
-    #[pyo3(get)]
-    pub next_pos: Option<i32>,
-    /// Template length
-    #[pyo3(get)]
-    pub tlen: Option<i32>,
-    /// Read name
-    #[pyo3(get)]
-    pub read_name: Option<Vec<u8>>,
-    /// CIGAR
-    #[pyo3(get)]
-    pub cigar: Option<String>,
-    /// 4-bit  encoded  read
-    #[pyo3(get)]
-    pub seq: Option<String>,
-    /// Phred-scaled base qualities.
-    #[pyo3(get)]
-    pub qual: Option<Vec<u8>>,
-    /// List of auxiliary data
-    #[pyo3(get)]
-    pub tags: Option<Vec<u8>>,
-}
-
-/// cfg_attribute currently doesn't work with pyo3(get)
-// #[cfg(feature = "python-ffi")]
-// pub fn parse_cigar(bytes: &[u8]) -> String {
-//     decode_cigar(bytes)
-// }
-
-// pub static mut copying: Duration = Duration::from_nanos(0);
-/// This version is for Rust.
-#[cfg(not(feature = "python-ffi"))]
 pub fn parse_cigar(bytes: &[u8], prealloc: &mut Cigar) {
     prealloc.0.resize(bytes.len() / U32_SIZE, Op::new(0));
     for (i, mut chunk) in bytes.chunks(U32_SIZE).enumerate() {
@@ -270,6 +210,58 @@ impl GbamRecord {
         let flag = self.flag.unwrap();
         (flag & rust_htslib::htslib::BAM_FUNMAP as u16) == rust_htslib::htslib::BAM_FUNMAP as u16
     }
+
+    /// Returns range of tag (including its name and type) in tags bytes.
+    fn find_tag(&self, tag: &[u8; 2]) -> Option<std::ops::Range<usize>> {
+        let tags = self.tags.as_ref().unwrap();
+        let mut offset = 0;
+        while offset + 3 <= tags.len() {
+            let end = offset + 3 + tag_value_size(tags[offset + 2], &tags[offset + 3..]);
+            if &tags[offset..offset + 2] == tag {
+                return Some(offset..end);
+            }
+            offset = end;
+        }
+        None
+    }
+
+    /// Returns value of string (Z) tag, without trailing NUL.
+    pub fn get_string_tag(&self, tag: &[u8; 2]) -> Option<&[u8]> {
+        let range = self.find_tag(tag)?;
+        let tags = self.tags.as_ref().unwrap();
+        if tags[range.start + 2] != b'Z' {
+            return None;
+        }
+        Some(&tags[range.start + 3..range.end - 1])
+    }
+
+    /// Sets string (Z) tag, replacing the existing one.
+    pub fn set_string_tag(&mut self, tag: &[u8; 2], value: &[u8]) {
+        if let Some(range) = self.find_tag(tag) {
+            self.tags.as_mut().unwrap().drain(range);
+        }
+        let tags = self.tags.as_mut().unwrap();
+        tags.extend_from_slice(tag);
+        tags.push(b'Z');
+        tags.extend_from_slice(value);
+        tags.push(0);
+    }
+}
+
+/// Size of tag value of type `val_type`. `value` starts right after the type.
+fn tag_value_size(val_type: u8, value: &[u8]) -> usize {
+    match val_type {
+        b'A' | b'c' | b'C' => 1,
+        b's' | b'S' => 2,
+        b'i' | b'I' | b'f' => 4,
+        b'Z' | b'H' => value.iter().position(|&b| b == 0).unwrap() + 1,
+        b'B' => {
+            let item_size = tag_value_size(value[0], &[]);
+            let count = (&value[1..5]).read_u32::<LittleEndian>().unwrap() as usize;
+            5 + item_size * count
+        }
+        _ => panic!("Unknown tag type: {}", val_type as char),
+    }
 }
 
 impl std::fmt::Display for GbamRecord {
@@ -278,7 +270,6 @@ impl std::fmt::Display for GbamRecord {
     }
 }
 
-#[cfg_attr(feature = "python-ffi", pymethods)]
 impl GbamRecord {
     /// Convert GBAM structure to string representation
     pub fn to_str(&self) -> String {
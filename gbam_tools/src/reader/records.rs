@@ -1,76 +1,103 @@
 use super::{reader::Reader, record::GbamRecord};
+use std::ops::Range;
 
-#[cfg(feature = "python-ffi")]
-use pyo3::prelude::*;
-
-/// Iterates over GBAM file.
+/// Iterates over GBAM file records in range. `next_rec` lends record from
+/// internal buffer, which is reused between calls. `Iterator` implementation
+/// returns owned records, so it works with `for` loops and adaptors at the cost
+/// of allocating every record.
 pub struct Records<'a> {
     reader: &'a mut Reader,
-    cur_rec: usize,
-    rec_amount: usize,
+    range: Range<usize>,
     buf: GbamRecord,
 }
 
 impl<'a> Records<'a> {
     pub fn new(reader: &'a mut Reader) -> Self {
+        let amount = reader.amount;
+        Self::with_range(reader, 0..amount)
+    }
+
+    pub fn with_range(reader: &'a mut Reader, range: Range<usize>) -> Self {
+        assert!(range.end <= reader.amount, "Records range is out of bounds.");
         Self {
-            rec_amount: reader.amount,
             reader,
-            cur_rec: 0,
+            range,
             buf: GbamRecord::default(),
         }
     }
 
     pub fn next_rec(&mut self) -> Option<&GbamRecord> {
-        if self.cur_rec == self.rec_amount {
-            return None;
-        }
-        self.reader.fill_record(self.cur_rec, &mut self.buf);
-        self.cur_rec += 1;
+        let rec_num = self.range.next()?;
+        self.reader.fill_record(rec_num, &mut self.buf);
         Some(&self.buf)
     }
+
+    fn fetch_owned(&mut self, rec_num: usize) -> GbamRecord {
+        let mut rec = GbamRecord::default();
+        self.reader.fill_record(rec_num, &mut rec);
+        rec
+    }
 }
 
-#[cfg_attr(feature = "python-ffi", pyclass)]
-/// This class was created because no generics (lifetimes) allowed in PyO3.
-pub struct PyRecords {
-    reader: Reader,
-    cur_rec: usize,
-    rec_amount: usize,
-    buf: GbamRecord,
+impl<'a> Iterator for Records<'a> {
+    type Item = GbamRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rec_num = self.range.next()?;
+        Some(self.fetch_owned(rec_num))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        let rec_num = self.range.nth(n)?;
+        Some(self.fetch_owned(rec_num))
+    }
 }
 
-impl PyRecords {
-    pub fn next_rec(&mut self) -> Option<&GbamRecord> {
-        if self.cur_rec == self.rec_amount {
-            return None;
-        }
-        self.reader.fill_record(self.cur_rec, &mut self.buf);
-        self.cur_rec += 1;
-        Some(&self.buf)
+impl<'a> DoubleEndedIterator for Records<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let rec_num = self.range.next_back()?;
+        Some(self.fetch_owned(rec_num))
     }
 }
 
-// Methods to be called from Python.
-#[cfg(feature = "python-ffi")]
-#[pymethods]
-impl PyRecords {
-    #[allow(clippy::unnecessary_wraps)]
-    fn next_record(&mut self) -> PyResult<Option<GbamRecord>> {
-        let next = self.next_rec().cloned();
-        Ok(next)
+impl<'a> ExactSizeIterator for Records<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use bam_tools::record::fields::Fields;
+    use crate::reader::parse_tmplt::ParsingTemplate;
+    use crate::reader::reader::{reader_from_records, test_record, Reader};
+    use crate::reader::record::GbamRecord;
+
+    fn reader_with_positions(positions: &[i32]) -> Reader {
+        let records: Vec<GbamRecord> = positions.iter().map(|&pos| test_record(0, pos)).collect();
+        reader_from_records(vec![(String::from("chr1"), 1000)], &records, ParsingTemplate::new_with(&[Fields::Pos]))
     }
 
-    /// Create new reader for file at path
-    #[new]
-    pub fn new_reader(path: &str, tmplt: ParsingTemplate) -> Self {
-        let file = File::open(path).unwrap();
-        let reader = Reader::new(file, tmplt).unwrap();
-        PyRecords {
-            rec_amount: reader.amount,
-            reader,
-            cur_rec: 0,
-            buf: GbamRecord::default(),
-        }
+    #[test]
+    fn test_records_both_ends() {
+        let mut reader = reader_with_positions(&[10, 20, 30, 40, 50, 60]);
+        let mut records = reader.records_range(1..6);
+        assert_eq!(records.len(), 5);
+        assert_eq!(records.next().unwrap().pos, Some(20));
+        assert_eq!(records.len(), 4);
+        assert_eq!(records.next_back().unwrap().pos, Some(60));
+        assert_eq!(records.len(), 3);
+        assert_eq!(records.next_back().unwrap().pos, Some(50));
+        assert_eq!(records.len(), 2);
+        assert_eq!(records.next().unwrap().pos, Some(30));
+        assert_eq!(records.len(), 1);
+        assert_eq!(records.next_back().unwrap().pos, Some(40));
+        assert_eq!(records.len(), 0);
+        assert!(records.next().is_none());
+        assert!(records.next_back().is_none());
+        assert_eq!(records.len(), 0);
+
+        let positions: Vec<i32> = reader.records().rev().map(|rec| rec.pos.unwrap()).collect();
+        assert_eq!(positions, vec![60, 50, 40, 30, 20, 10]);
     }
 }
@@ -0,0 +1,89 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{Cursor, Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use memmap2::Mmap;
+
+/// Random access source of GBAM file bytes. Sources which keep whole file in
+/// memory return borrowed slices, others read requested bytes into new buffer.
+pub trait ByteSource: Send + Sync {
+    /// Total size of the file. Streams find it out by seeking, which may fail.
+    fn len(&self) -> Result<usize>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Returns `len` bytes starting at `offset`. Reading past the end of file
+    /// is an error.
+    fn read_at(&self, offset: usize, len: usize) -> Result<Cow<'_, [u8]>>;
+}
+
+fn slice_at(bytes: &[u8], offset: usize, len: usize) -> Result<Cow<'_, [u8]>> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .map(Cow::Borrowed)
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Read past the end of GBAM file."))
+}
+
+impl ByteSource for Mmap {
+    fn len(&self) -> Result<usize> {
+        Ok(self.as_ref().len())
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Cow<'_, [u8]>> {
+        slice_at(self, offset, len)
+    }
+}
+
+/// In-memory file.
+impl<T: AsRef<[u8]> + Send + Sync> ByteSource for Cursor<T> {
+    fn len(&self) -> Result<usize> {
+        Ok(self.get_ref().as_ref().len())
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Cow<'_, [u8]>> {
+        slice_at(self.get_ref().as_ref(), offset, len)
+    }
+}
+
+/// Any seekable stream, like file on network mount where mmap is unreliable or
+/// member of uncompressed archive. Reads are serialized by the mutex, so
+/// parallel readers should open their own streams.
+impl<R: Read + Seek + Send> ByteSource for Mutex<R> {
+    fn len(&self) -> Result<usize> {
+        let mut inner = self.lock().unwrap();
+        let len = inner.seek(SeekFrom::End(0))?;
+        usize::try_from(len).map_err(|_| Error::new(ErrorKind::InvalidData, "GBAM file does not fit into address space."))
+    }
+
+    fn read_at(&self, offset: usize, len: usize) -> Result<Cow<'_, [u8]>> {
+        let mut inner = self.lock().unwrap();
+        inner.seek(SeekFrom::Start(offset as u64))?;
+        let mut buf = vec![0; len];
+        inner.read_exact(&mut buf)?;
+        Ok(Cow::Owned(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sources_agree() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let sources: Vec<Box<dyn ByteSource>> = vec![
+            Box::new(Cursor::new(bytes.clone())),
+            Box::new(Mutex::new(Cursor::new(bytes.clone()))),
+        ];
+        for source in sources {
+            assert_eq!(source.len().unwrap(), 256);
+            assert_eq!(&*source.read_at(10, 3).unwrap(), &[10, 11, 12]);
+            assert_eq!(&*source.read_at(256, 0).unwrap(), &[] as &[u8]);
+            assert_eq!(source.read_at(250, 7).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+}
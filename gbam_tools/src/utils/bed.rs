@@ -45,6 +45,20 @@ pub fn parse_bed<R: Read>(source: &mut R) -> io::Result<HashMap::<String, Vec<(u
     Ok(res)
 }
 
+/// Sorts regions of a reference sequence and merges overlapping and adjacent
+/// ones, since BED files may list them in any order.
+pub fn merge_regions(regions: &mut Vec<(u32, u32)>) {
+    regions.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(regions.len());
+    for &(start, end) in regions.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *regions = merged;
+}
+
 fn read_lines<R>(source: &mut R) -> io::Result<io::Lines<io::BufReader<&mut R>>>
 where
     R: Read,
@@ -115,4 +129,11 @@ mod tests {
         assert_eq!(res["chrX"][0], (346798, 23689090));
         assert_eq!(res["chrX"][1], (346798, 23689090));
     }
+
+    #[test]
+    fn test_merge_regions() {
+        let mut regions = vec![(50, 60), (0, 10), (5, 20), (20, 30), (55, 58), (70, 80)];
+        merge_regions(&mut regions);
+        assert_eq!(regions, vec![(0, 30), (50, 60), (70, 80)]);
+    }
 }
@@ -0,0 +1,443 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Format description: https://genome.ucsc.edu/goldenPath/help/bigWig.html and
+// supplement of Kent et al. (2010) "BigWig and BigBed: enabling browsing of
+// large distributed datasets". Zoom levels are not written, genome browsers
+// (IGV, UCSC) read data directly in that case.
+
+const BIGWIG_MAGIC: u32 = 0x888F_FC26;
+const CHROM_TREE_MAGIC: u32 = 0x78CA_8C91;
+const R_TREE_MAGIC: u32 = 0x2468_ACE0;
+const BIGWIG_VERSION: u16 = 4;
+
+const HEADER_SIZE: u64 = 64;
+const TOTAL_SUMMARY_SIZE: u64 = 40;
+const R_TREE_HEADER_SIZE: u64 = 48;
+const NODE_HEADER_SIZE: u64 = 4;
+const R_TREE_LEAF_ITEM_SIZE: u64 = 32;
+const R_TREE_NON_LEAF_ITEM_SIZE: u64 = 24;
+
+/// Max number of bedGraph items in a single data section.
+const ITEMS_PER_SLOT: usize = 1024;
+/// Max number of children of index nodes.
+const BLOCK_SIZE: usize = 256;
+/// bedGraph section type.
+const BEDGRAPH_SECTION: u8 = 1;
+
+/// Written data section, used to build R-tree index.
+struct Section {
+    chrom_id: u32,
+    start: u32,
+    end: u32,
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Default)]
+struct TotalSummary {
+    bases_covered: u64,
+    min_val: f64,
+    max_val: f64,
+    sum_data: f64,
+    sum_squares: f64,
+}
+
+/// Writes bedGraph-like regions (chr, start, end, value) into bigWig file.
+/// Regions have to be sorted by chromosome (in order of `chroms` passed to
+/// constructor) and position and must not overlap.
+pub struct BigWigWriter<W: Write + Seek> {
+    inner: W,
+    chroms: Vec<(String, u32)>,
+    // Current section.
+    chrom_id: Option<u32>,
+    items: Vec<(u32, u32, f32)>,
+    last_end: u32,
+    sections: Vec<Section>,
+    summary: TotalSummary,
+    uncompress_buf_size: usize,
+    data_offset: u64,
+}
+
+impl BigWigWriter<BufWriter<File>> {
+    pub fn create(path: &Path, chroms: &[(String, u32)]) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Self::new(BufWriter::with_capacity(64 * 1024, file), chroms)
+    }
+}
+
+impl<W: Write + Seek> BigWigWriter<W> {
+    pub fn new(mut inner: W, chroms: &[(String, u32)]) -> io::Result<Self> {
+        // Header is rewritten in `finish` once offsets are known.
+        inner.write_all(&[0; (HEADER_SIZE + TOTAL_SUMMARY_SIZE) as usize])?;
+        write_chrom_tree(&mut inner, chroms)?;
+        let data_offset = inner.stream_position()?;
+        // Number of sections, filled in `finish`.
+        inner.write_u64::<LittleEndian>(0)?;
+
+        Ok(Self {
+            inner,
+            chroms: chroms.to_vec(),
+            chrom_id: None,
+            items: Vec::with_capacity(ITEMS_PER_SLOT),
+            last_end: 0,
+            sections: Vec::new(),
+            summary: TotalSummary {
+                min_val: f64::MAX,
+                max_val: f64::MIN,
+                ..Default::default()
+            },
+            uncompress_buf_size: 0,
+            data_offset,
+        })
+    }
+
+    /// Adds region [start, end) with value. Regions with zero value are skipped.
+    pub fn write_region(&mut self, chr: &str, start: u32, end: u32, value: f32) -> io::Result<()> {
+        if value == 0.0 || start >= end {
+            return Ok(());
+        }
+        let chrom_id = self
+            .chroms
+            .iter()
+            .position(|(name, _)| name == chr)
+            .unwrap_or_else(|| panic!("Reference sequence {} is not present in bigWig header.", chr))
+            as u32;
+        let end = std::cmp::min(end, self.chroms[chrom_id as usize].1);
+        if start >= end {
+            return Ok(());
+        }
+
+        if self.chrom_id != Some(chrom_id) {
+            self.flush_section()?;
+            if self.chrom_id.is_some_and(|id| id > chrom_id) {
+                panic!("bigWig regions have to be sorted by reference sequence.");
+            }
+            self.chrom_id = Some(chrom_id);
+            self.last_end = 0;
+        }
+        if start < self.last_end {
+            panic!("bigWig regions have to be sorted and must not overlap.");
+        }
+        if self.items.len() == ITEMS_PER_SLOT {
+            self.flush_section()?;
+        }
+
+        self.items.push((start, end, value));
+        self.last_end = end;
+
+        let len = (end - start) as u64;
+        let val = value as f64;
+        let summary = &mut self.summary;
+        summary.bases_covered += len;
+        summary.min_val = summary.min_val.min(val);
+        summary.max_val = summary.max_val.max(val);
+        summary.sum_data += val * len as f64;
+        summary.sum_squares += val * val * len as f64;
+        Ok(())
+    }
+
+    fn flush_section(&mut self) -> io::Result<()> {
+        if self.items.is_empty() {
+            return Ok(());
+        }
+        let chrom_id = self.chrom_id.unwrap();
+        let start = self.items[0].0;
+        let end = self.items.last().unwrap().1;
+
+        let mut buf = Vec::with_capacity(24 + self.items.len() * 12);
+        buf.write_u32::<LittleEndian>(chrom_id)?;
+        buf.write_u32::<LittleEndian>(start)?;
+        buf.write_u32::<LittleEndian>(end)?;
+        // Item step and span are unused for bedGraph sections.
+        buf.write_u32::<LittleEndian>(0)?;
+        buf.write_u32::<LittleEndian>(0)?;
+        buf.write_u8(BEDGRAPH_SECTION)?;
+        buf.write_u8(0)?;
+        buf.write_u16::<LittleEndian>(self.items.len() as u16)?;
+        for &(item_start, item_end, value) in &self.items {
+            buf.write_u32::<LittleEndian>(item_start)?;
+            buf.write_u32::<LittleEndian>(item_end)?;
+            buf.write_f32::<LittleEndian>(value)?;
+        }
+        self.uncompress_buf_size = std::cmp::max(self.uncompress_buf_size, buf.len());
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&buf)?;
+        let compressed = encoder.finish()?;
+
+        let offset = self.inner.stream_position()?;
+        self.inner.write_all(&compressed)?;
+        self.sections.push(Section {
+            chrom_id,
+            start,
+            end,
+            offset,
+            size: compressed.len() as u64,
+        });
+        self.items.clear();
+        Ok(())
+    }
+
+    /// Writes index and header. Always call after writing all the regions.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.flush_section()?;
+
+        let index_offset = self.inner.stream_position()?;
+        write_r_tree(&mut self.inner, &self.sections)?;
+        self.inner.write_u32::<LittleEndian>(BIGWIG_MAGIC)?;
+
+        self.inner.seek(SeekFrom::Start(self.data_offset))?;
+        self.inner.write_u64::<LittleEndian>(self.sections.len() as u64)?;
+
+        self.inner.seek(SeekFrom::Start(0))?;
+        let w = &mut self.inner;
+        w.write_u32::<LittleEndian>(BIGWIG_MAGIC)?;
+        w.write_u16::<LittleEndian>(BIGWIG_VERSION)?;
+        // Zoom levels.
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u64::<LittleEndian>(HEADER_SIZE + TOTAL_SUMMARY_SIZE)?;
+        w.write_u64::<LittleEndian>(self.data_offset)?;
+        w.write_u64::<LittleEndian>(index_offset)?;
+        // Field count and defined field count are used only in bigBed.
+        w.write_u16::<LittleEndian>(0)?;
+        w.write_u16::<LittleEndian>(0)?;
+        // AutoSql offset.
+        w.write_u64::<LittleEndian>(0)?;
+        w.write_u64::<LittleEndian>(HEADER_SIZE)?;
+        w.write_u32::<LittleEndian>(self.uncompress_buf_size as u32)?;
+        // Extension offset.
+        w.write_u64::<LittleEndian>(0)?;
+
+        let summary = &self.summary;
+        let (min_val, max_val) = if summary.bases_covered == 0 {
+            (0.0, 0.0)
+        } else {
+            (summary.min_val, summary.max_val)
+        };
+        w.write_u64::<LittleEndian>(summary.bases_covered)?;
+        w.write_f64::<LittleEndian>(min_val)?;
+        w.write_f64::<LittleEndian>(max_val)?;
+        w.write_f64::<LittleEndian>(summary.sum_data)?;
+        w.write_f64::<LittleEndian>(summary.sum_squares)?;
+        w.flush()
+    }
+}
+
+/// Amount of nodes at each level of a tree with `item_count` items, starting
+/// from leaves.
+fn nodes_per_level(item_count: usize, block_size: usize) -> Vec<usize> {
+    let mut levels = vec![std::cmp::max(1, item_count.div_ceil(block_size))];
+    while *levels.last().unwrap() > 1 {
+        let prev = *levels.last().unwrap();
+        levels.push(prev.div_ceil(block_size));
+    }
+    levels
+}
+
+/// Writes B+ tree mapping chromosome names to their ids and sizes. All nodes
+/// are padded to full block size, so offsets of children can be computed.
+fn write_chrom_tree<W: Write + Seek>(w: &mut W, chroms: &[(String, u32)]) -> io::Result<()> {
+    let mut sorted: Vec<(&[u8], u32, u32)> = chroms
+        .iter()
+        .enumerate()
+        .map(|(id, (name, len))| (name.as_bytes(), id as u32, *len))
+        .collect();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+
+    let block_size = sorted.len().clamp(1, BLOCK_SIZE);
+    let key_size = sorted.iter().map(|c| c.0.len()).max().unwrap_or(1).max(1);
+    // Leaf values (id + size) and children offsets are both 8 bytes.
+    let item_size = key_size as u64 + 8;
+    let node_size = NODE_HEADER_SIZE + block_size as u64 * item_size;
+
+    w.write_u32::<LittleEndian>(CHROM_TREE_MAGIC)?;
+    w.write_u32::<LittleEndian>(block_size as u32)?;
+    w.write_u32::<LittleEndian>(key_size as u32)?;
+    w.write_u32::<LittleEndian>(8)?;
+    w.write_u64::<LittleEndian>(sorted.len() as u64)?;
+    w.write_u64::<LittleEndian>(0)?;
+
+    let write_key = |w: &mut W, key: &[u8]| -> io::Result<()> {
+        w.write_all(key)?;
+        w.write_all(&vec![0; key_size - key.len()])
+    };
+
+    let levels = nodes_per_level(sorted.len(), block_size);
+    let mut level_offset = w.stream_position()?;
+    for level in (0..levels.len()).rev() {
+        let next_level_offset = level_offset + levels[level] as u64 * node_size;
+        // Amount of items covered by one child of node at this level.
+        let items_per_child = block_size.pow(level as u32);
+        for node in 0..levels[level] {
+            let node_start = node * block_size * items_per_child;
+            let children: Vec<usize> = (0..block_size)
+                .map(|i| node_start + i * items_per_child)
+                .take_while(|&first_item| first_item < sorted.len())
+                .collect();
+            w.write_u8((level == 0) as u8)?;
+            w.write_u8(0)?;
+            w.write_u16::<LittleEndian>(children.len() as u16)?;
+            for (i, &first_item) in children.iter().enumerate() {
+                let (key, id, len) = sorted[first_item];
+                write_key(w, key)?;
+                if level == 0 {
+                    w.write_u32::<LittleEndian>(id)?;
+                    w.write_u32::<LittleEndian>(len)?;
+                } else {
+                    let child = (node * block_size + i) as u64;
+                    w.write_u64::<LittleEndian>(next_level_offset + child * node_size)?;
+                }
+            }
+            w.write_all(&vec![0; ((block_size - children.len()) as u64 * item_size) as usize])?;
+        }
+        level_offset = next_level_offset;
+    }
+    Ok(())
+}
+
+/// Writes R-tree index over data sections. Nodes are padded to full block size.
+fn write_r_tree<W: Write + Seek>(w: &mut W, sections: &[Section]) -> io::Result<()> {
+    let (first, last) = match (sections.first(), sections.last()) {
+        (Some(first), Some(last)) => ((first.chrom_id, first.start), (last.chrom_id, last.end)),
+        _ => ((0, 0), (0, 0)),
+    };
+    let end_file_offset = w.stream_position()?;
+
+    w.write_u32::<LittleEndian>(R_TREE_MAGIC)?;
+    w.write_u32::<LittleEndian>(BLOCK_SIZE as u32)?;
+    w.write_u64::<LittleEndian>(sections.len() as u64)?;
+    w.write_u32::<LittleEndian>(first.0)?;
+    w.write_u32::<LittleEndian>(first.1)?;
+    w.write_u32::<LittleEndian>(last.0)?;
+    w.write_u32::<LittleEndian>(last.1)?;
+    w.write_u64::<LittleEndian>(end_file_offset)?;
+    w.write_u32::<LittleEndian>(ITEMS_PER_SLOT as u32)?;
+    w.write_u32::<LittleEndian>(0)?;
+
+    let levels = nodes_per_level(sections.len(), BLOCK_SIZE);
+    let leaf_node_size = NODE_HEADER_SIZE + BLOCK_SIZE as u64 * R_TREE_LEAF_ITEM_SIZE;
+    let non_leaf_node_size = NODE_HEADER_SIZE + BLOCK_SIZE as u64 * R_TREE_NON_LEAF_ITEM_SIZE;
+
+    let mut level_offset = end_file_offset + R_TREE_HEADER_SIZE;
+    for level in (0..levels.len()).rev() {
+        let node_size = if level == 0 { leaf_node_size } else { non_leaf_node_size };
+        let child_node_size = if level == 1 { leaf_node_size } else { non_leaf_node_size };
+        let next_level_offset = level_offset + levels[level] as u64 * node_size;
+        let items_per_child = BLOCK_SIZE.pow(level as u32);
+        for node in 0..levels[level] {
+            let node_start = node * BLOCK_SIZE * items_per_child;
+            let children: Vec<usize> = (0..BLOCK_SIZE)
+                .map(|i| node_start + i * items_per_child)
+                .take_while(|&first_item| first_item < sections.len())
+                .collect();
+            w.write_u8((level == 0) as u8)?;
+            w.write_u8(0)?;
+            w.write_u16::<LittleEndian>(children.len() as u16)?;
+            for (i, &first_item) in children.iter().enumerate() {
+                let last_item = std::cmp::min(first_item + items_per_child, sections.len()) - 1;
+                let (first, last) = (&sections[first_item], &sections[last_item]);
+                w.write_u32::<LittleEndian>(first.chrom_id)?;
+                w.write_u32::<LittleEndian>(first.start)?;
+                w.write_u32::<LittleEndian>(last.chrom_id)?;
+                w.write_u32::<LittleEndian>(last.end)?;
+                if level == 0 {
+                    w.write_u64::<LittleEndian>(first.offset)?;
+                    w.write_u64::<LittleEndian>(first.size)?;
+                } else {
+                    let child = (node * BLOCK_SIZE + i) as u64;
+                    w.write_u64::<LittleEndian>(next_level_offset + child * child_node_size)?;
+                }
+            }
+            let item_size = if level == 0 { R_TREE_LEAF_ITEM_SIZE } else { R_TREE_NON_LEAF_ITEM_SIZE };
+            w.write_all(&vec![0; ((BLOCK_SIZE - children.len()) as u64 * item_size) as usize])?;
+        }
+        level_offset = next_level_offset;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use flate2::read::ZlibDecoder;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_bigwig_header() {
+        let chroms = vec![(String::from("chr2"), 1000), (String::from("chr1"), 500)];
+        let mut writer = BigWigWriter::new(Cursor::new(Vec::new()), &chroms).unwrap();
+        writer.write_region("chr2", 0, 10, 1.0).unwrap();
+        writer.write_region("chr2", 10, 20, 0.0).unwrap();
+        writer.write_region("chr2", 20, 30, 3.0).unwrap();
+        writer.write_region("chr1", 100, 600, 2.0).unwrap();
+        writer.finish().unwrap();
+        let bytes = writer.inner.into_inner();
+
+        let mut header = &bytes[..];
+        assert_eq!(header.read_u32::<LittleEndian>().unwrap(), BIGWIG_MAGIC);
+        assert_eq!(header.read_u16::<LittleEndian>().unwrap(), BIGWIG_VERSION);
+        assert_eq!(header.read_u16::<LittleEndian>().unwrap(), 0);
+        let chrom_tree_offset = header.read_u64::<LittleEndian>().unwrap();
+        let data_offset = header.read_u64::<LittleEndian>().unwrap();
+        let index_offset = header.read_u64::<LittleEndian>().unwrap();
+
+        let mut tree = &bytes[chrom_tree_offset as usize..];
+        assert_eq!(tree.read_u32::<LittleEndian>().unwrap(), CHROM_TREE_MAGIC);
+        let mut data = &bytes[data_offset as usize..];
+        // One section per chromosome.
+        assert_eq!(data.read_u64::<LittleEndian>().unwrap(), 2);
+        let mut index = &bytes[index_offset as usize..];
+        assert_eq!(index.read_u32::<LittleEndian>().unwrap(), R_TREE_MAGIC);
+
+        let mut summary = &bytes[HEADER_SIZE as usize..];
+        // Zero regions are skipped and regions are clamped to chromosome size.
+        assert_eq!(summary.read_u64::<LittleEndian>().unwrap(), 420);
+        assert_eq!(summary.read_f64::<LittleEndian>().unwrap(), 1.0);
+        assert_eq!(summary.read_f64::<LittleEndian>().unwrap(), 3.0);
+    }
+
+    #[test]
+    fn test_bigwig_data() {
+        let chroms = vec![(String::from("chr1"), 1000)];
+        let mut writer = BigWigWriter::new(Cursor::new(Vec::new()), &chroms).unwrap();
+        writer.write_region("chr1", 0, 10, 1.0).unwrap();
+        writer.write_region("chr1", 10, 25, 2.0).unwrap();
+        writer.write_region("chr1", 40, 50, 1.0).unwrap();
+        writer.finish().unwrap();
+        let bytes = writer.inner.into_inner();
+
+        let data_offset = (&bytes[16..]).read_u64::<LittleEndian>().unwrap() as usize;
+        let index_offset = (&bytes[24..]).read_u64::<LittleEndian>().unwrap() as usize;
+        let mut section = Vec::new();
+        ZlibDecoder::new(&bytes[data_offset + 8..index_offset]).read_to_end(&mut section).unwrap();
+
+        let mut section = &section[..];
+        let header: Vec<u32> = (0..5).map(|_| section.read_u32::<LittleEndian>().unwrap()).collect();
+        assert_eq!(header, vec![0, 0, 50, 0, 0]);
+        assert_eq!(section.read_u8().unwrap(), BEDGRAPH_SECTION);
+        section.read_u8().unwrap();
+        assert_eq!(section.read_u16::<LittleEndian>().unwrap(), 3);
+        let mut items = Vec::new();
+        while !section.is_empty() {
+            let start = section.read_u32::<LittleEndian>().unwrap();
+            let end = section.read_u32::<LittleEndian>().unwrap();
+            items.push((start, end, section.read_f32::<LittleEndian>().unwrap()));
+        }
+        assert_eq!(items, vec![(0, 10, 1.0), (10, 25, 2.0), (40, 50, 1.0)]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_bigwig_overlapping_regions() {
+        let chroms = vec![(String::from("chr1"), 1000)];
+        let mut writer = BigWigWriter::new(Cursor::new(Vec::new()), &chroms).unwrap();
+        writer.write_region("chr1", 0, 10, 1.0).unwrap();
+        writer.write_region("chr1", 5, 15, 1.0).unwrap();
+    }
+}
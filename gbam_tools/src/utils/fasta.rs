@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Reads sequence of reference `name` from FASTA file. Returns None if there is
+/// no such sequence in the file.
+pub fn read_ref_seq_from_file(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(path)?;
+    read_ref_seq(&mut file, name)
+}
+
+/// Scans FASTA source for sequence `name` (first word of the header line).
+pub fn read_ref_seq<R: Read>(source: &mut R, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut seq: Option<Vec<u8>> = None;
+
+    for line in BufReader::new(source).lines() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('>') {
+            if seq.is_some() {
+                break;
+            }
+            if header.split_whitespace().next() == Some(name) {
+                seq = Some(Vec::new());
+            }
+        } else if let Some(ref mut seq) = seq {
+            seq.extend_from_slice(line.trim_end().as_bytes());
+        }
+    }
+
+    Ok(seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_fasta_reader() {
+        let source = ">chr1 first\n\
+        ACGT\n\
+        NNac\n\
+        >chr2\n\
+        GGGG\n";
+        let mut reader = Cursor::new(source);
+        assert_eq!(read_ref_seq(&mut reader, "chr1").unwrap().unwrap(), b"ACGTNNac");
+        let mut reader = Cursor::new(source);
+        assert_eq!(read_ref_seq(&mut reader, "chr2").unwrap().unwrap(), b"GGGG");
+        let mut reader = Cursor::new(source);
+        assert!(read_ref_seq(&mut reader, "chr3").unwrap().is_none());
+    }
+}
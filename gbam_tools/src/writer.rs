@@ -1,5 +1,7 @@
-use super::meta::{BlockMeta, Codecs, FileInfo, FileMeta, FILE_INFO_SIZE, Stat};
+use super::meta::{BlockMeta, Codecs, FileInfo, FileMeta, SortOrder, FILE_INFO_SIZE, Stat};
 use crate::compressor::{CompressTask, Compressor, OrderingKey};
+use crate::name_filter::{self, NameFilterMeta};
+use crate::query::cigar::Op;
 use crate::{SIZE_LIMIT, U32_SIZE};
 use bam_tools::record::bamrawrecord::BAMRawRecord;
 use bam_tools::record::fields::{
@@ -18,6 +20,8 @@ pub(crate) struct BlockInfo {
     pub field: Fields,
     // Interpretation is up to the reader.
     pub stats: Option<Stat>,
+    /// Read name filter bits, written right after the block.
+    pub name_filter: Option<Vec<u8>>,
 }
 
 impl Default for BlockInfo {
@@ -27,6 +31,7 @@ impl Default for BlockInfo {
             uncompr_size: 0,
             field: Fields::RefID,
             stats: None,
+            name_filter: None,
         }
     }
 }
@@ -64,7 +69,7 @@ where
         ref_seqs: Vec<(String, u32)>,
         sam_header: Vec<u8>,
         full_command: String,
-        is_sorted: bool,
+        sort_order: SortOrder,
     ) -> Self {
         inner
             .seek(SeekFrom::Start((FILE_INFO_SIZE) as u64))
@@ -74,7 +79,7 @@ where
 
         let mut count = 0;
         for field in Fields::iterator().filter(|f| is_data_field(f)) {
-            let stat_collector = collect_stats_for.iter().find(|f| *f == field).and(Some(Stat::default()));
+            let stat_collector = collect_stats_for.iter().find(|f| *f == field).map(Stat::new_for);
             let col = match field_type(field) {
                 FieldType::FixedSized => {
                     Box::new(FixedColumn::new(*field, stat_collector)) as Box<dyn Column>
@@ -96,7 +101,7 @@ where
             inner,
             compressor: Compressor::new(thread_num),
             columns,
-            file_info: FileInfo::new([1, 0], 0, 0, full_command, is_sorted),
+            file_info: FileInfo::new([1, 0], 0, 0, full_command, sort_order),
         }
     }
 
@@ -107,7 +112,7 @@ where
         ref_seqs: Vec<(String, u32)>,
         sam_header: Vec<u8>,
         full_command: String,
-        is_sorted: bool,
+        sort_order: SortOrder,
     ) -> Self {
         Self::new(
             inner,
@@ -117,10 +122,27 @@ where
             ref_seqs,
             sam_header,
             full_command,
-            is_sorted
+            sort_order
         )
     }
 
+    /// Marks file as index sorted: coordinate order is kept in index file,
+    /// records themselves are unsorted.
+    pub fn set_index_sorted(&mut self) {
+        self.file_info.index_sorted = Some(true);
+    }
+
+    /// Enables writing read name filter for every ReadName block, which allows
+    /// `Reader::find_by_name` to skip blocks. Call before pushing records.
+    pub fn enable_name_filter(&mut self) {
+        for col in self.columns.iter_mut() {
+            let (inner, _) = col.get_inners();
+            if inner.field == Fields::ReadName {
+                inner.name_hashes = Some(Vec::new());
+            }
+        }
+    }
+
     /// Push BAM record into this writer
     pub fn push_record(&mut self, record: &BAMRawRecord) {
         // Index fields are not written on their own. They hold index data for variable sized fields.
@@ -163,27 +185,36 @@ where
             }
         }
 
-        let meta_start_pos = self.inner.stream_position()?;
-        // Write meta
-        let main_meta = serde_json::to_string(&self.file_meta).unwrap();
-        let main_meta_bytes = main_meta.as_bytes();
-        let crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
-        self.inner.write_all(main_meta_bytes)?;
-
-        let total_bytes_written = self.inner.stream_position()?;
-        // Revert back to the beginning of the file
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        self.inner.write_all(&[0;FILE_INFO_SIZE]).unwrap();
-        self.inner.seek(SeekFrom::Start(0)).unwrap();
-        let file_info = & mut self.file_info;
-        file_info.seekpos = meta_start_pos;
-        file_info.crc32 = crc32;
-        let file_info_bytes = serde_json::to_string(&file_info).unwrap();
-        self.inner.write_all(file_info_bytes.as_bytes())?;
-        Ok(total_bytes_written)
+        write_meta(&mut self.inner, &self.file_meta, &mut self.file_info)
     }
 }
 
+/// Writes file meta at current position and file info at the beginning of the
+/// file. Returns total amount of bytes written.
+pub(crate) fn write_meta<WS: Write + Seek>(
+    inner: &mut WS,
+    file_meta: &FileMeta,
+    file_info: &mut FileInfo,
+) -> std::io::Result<u64> {
+    let meta_start_pos = inner.stream_position()?;
+    // Write meta
+    let main_meta = serde_json::to_string(file_meta).unwrap();
+    let main_meta_bytes = main_meta.as_bytes();
+    let crc32 = calc_crc_for_meta_bytes(main_meta_bytes);
+    inner.write_all(main_meta_bytes)?;
+
+    let total_bytes_written = inner.stream_position()?;
+    // Revert back to the beginning of the file
+    inner.seek(SeekFrom::Start(0)).unwrap();
+    inner.write_all(&[0;FILE_INFO_SIZE]).unwrap();
+    inner.seek(SeekFrom::Start(0)).unwrap();
+    file_info.seekpos = meta_start_pos;
+    file_info.crc32 = crc32;
+    let file_info_bytes = serde_json::to_string(&file_info).unwrap();
+    inner.write_all(file_info_bytes.as_bytes())?;
+    Ok(total_bytes_written)
+}
+
 fn flush_field_buffer<WS: Write + Seek>(
     writer: &mut WS,
     file_meta: &mut FileMeta,
@@ -220,7 +251,7 @@ fn write_data_and_update_meta<WS: Write + Seek>(
     task: &mut CompressTask,
 ) {
     let compressed_size = task.buf.len();
-    let meta = generate_meta(
+    let mut meta = generate_meta(
         writer,
         &mut task.block_info,
         compressed_size.try_into().unwrap(),
@@ -228,6 +259,15 @@ fn write_data_and_update_meta<WS: Write + Seek>(
 
     writer.write_all(&task.buf).unwrap();
 
+    if let Some(filter) = task.block_info.name_filter.take() {
+        meta.name_filter = Some(NameFilterMeta {
+            seekpos: writer.stream_position().unwrap(),
+            size: filter.len().try_into().unwrap(),
+            num_hashes: name_filter::NUM_HASHES,
+        });
+        writer.write_all(&filter).unwrap();
+    }
+
     let field_meta = file_meta.get_blocks(&task.block_info.field);
     if field_meta.len() <= key as usize {
         field_meta.resize(key as usize + 1, BlockMeta::default());
@@ -249,6 +289,7 @@ fn generate_meta<S: Seek>(
         block_size,
         uncompressed_size: block_info.uncompr_size as u64,
         stats: block_info.stats.take(),
+        name_filter: None,
     }
 }
 
@@ -260,6 +301,8 @@ enum WriteStatus<'a> {
 
 struct Inner {
     stats_collector: Option<Stat>,
+    /// Hashes of read names in block, if read name filter is enabled.
+    name_hashes: Option<Vec<u64>>,
     buffer: Vec<u8>,
     offset: usize,
     field: Fields,
@@ -271,6 +314,7 @@ impl Inner {
     pub fn new(field: Fields, stats_collector: Option<Stat>) -> Self {
         Self {
             stats_collector,
+            name_hashes: None,
             buffer: Vec::new(),
             offset: 0,
             field,
@@ -308,16 +352,22 @@ impl Inner {
 
     pub fn generate_block_info(&mut self) -> BlockInfo {
         let stat = if self.stats_collector.is_some(){
-            self.stats_collector.replace(Stat::default())
+            self.stats_collector.replace(Stat::new_for(&self.field))
         }
         else{
             None
         };
+        let name_filter = self.name_hashes.as_mut().map(|hashes| {
+            let filter = name_filter::build_filter(hashes);
+            hashes.clear();
+            filter
+        });
         BlockInfo {
             numitems: self.rec_count,
             uncompr_size: self.offset,
             field: self.field,
             stats: stat,
+            name_filter,
         }
     }
 }
@@ -334,13 +384,33 @@ struct FixedColumn(Inner);
 
 impl FixedColumn {
     pub fn new(field: Fields, comparator: Option<Stat>) -> Self {
-        if comparator.is_some() && field != Fields::RefID && field != Fields::Pos {
-            panic!("Stats collection is only supported for RefID and POS fields.");
-        }
         Self(Inner::new(field, comparator))
     }
 }
 
+/// Reads fixed sized field value for stats. One and two byte fields (Mapq,
+/// Flags and the like) are unsigned, the rest are i32.
+pub(crate) fn fixed_field_value(data: &[u8]) -> i32 {
+    match data.len() {
+        1 => data[0] as i32,
+        2 => (&data[..]).read_u16::<LittleEndian>().unwrap() as i32,
+        _ => (&data[..]).read_i32::<LittleEndian>().unwrap(),
+    }
+}
+
+/// End (exclusive) of alignment of raw record, records without CIGAR cover
+/// a single base.
+fn alignment_end(rec: &BAMRawRecord, pos: i32) -> i32 {
+    let span: u32 = rec
+        .get_bytes(&Fields::RawCigar)
+        .chunks_exact(U32_SIZE)
+        .map(|op| Op::new(u32::from_le_bytes([op[0], op[1], op[2], op[3]])))
+        .filter(|op| op.is_consuming_reference())
+        .map(|op| op.length())
+        .sum();
+    pos + std::cmp::max(span, 1) as i32
+}
+
 impl Column for FixedColumn {
     fn write_record_field(&mut self, rec: &BAMRawRecord) -> WriteStatus {
         let inner = &mut self.0;
@@ -351,7 +421,13 @@ impl Column for FixedColumn {
         }
 
         if let Some(ref mut stats) = inner.stats_collector {
-            stats.update((&data[..]).read_i32::<LittleEndian>().unwrap());
+            stats.update(fixed_field_value(data));
+            if inner.field == Fields::Pos {
+                let ref_id = fixed_field_value(rec.get_bytes(&Fields::RefID));
+                let pos = fixed_field_value(data);
+                stats.update_key(ref_id, pos);
+                stats.update_end_key(ref_id, alignment_end(rec, pos));
+            }
         }
 
         inner.write_data(data)
@@ -369,9 +445,6 @@ struct VariableColumn {
 
 impl VariableColumn {
     pub fn new(field: Fields, comparator: Option<Stat>) -> Self {
-        if comparator.is_some() {
-            panic!("Stats collection is not supported for variable length fields.");
-        }
         Self {
             inner: Inner::new(field, comparator),
             index: FixedColumn::new(var_size_field_to_index(&field), None),
@@ -395,7 +468,12 @@ impl Column for VariableColumn {
             return WriteStatus::Full(inner);
         }
 
-        assert!(inner.stats_collector.is_none());
+        if let Some(ref mut stats) = inner.stats_collector {
+            stats.update(data.len() as i32);
+        }
+        if let Some(ref mut hashes) = inner.name_hashes {
+            hashes.push(name_filter::hash_name(name_filter::strip_nul(data)));
+        }
 
         inner.write_data(data);
         (&mut idx_buf[..])
@@ -6,6 +6,9 @@ import gzip
 import shutil
 import os
 import io
+import json
+import ctypes
+import struct
 
 with_depth = pytest.mark.skipif("not config.getoption('with_depth')")
 
@@ -13,6 +16,7 @@ cur_file_path = Path(__file__).parent.absolute()
 
 test_data_folder = cur_file_path.parent/"test_data"
 binary_path = cur_file_path.parent/"target"/"release"/"gbam_binary"
+library_path = cur_file_path.parent/"target"/"release"/"libgbam_tools.so"
 
 bam_file_path = test_data_folder/"little.bam"
 
@@ -102,33 +106,109 @@ def test_sort(request):
     compare_bam_files(samtools_sorted_results.name, gbam_sorted_results.name)
     
 # Testing against mosdepth.
-@with_depth
-def test_depth():    
-    temp_dir = TemporaryDirectory()
+def run_mosdepth(temp_dir, bam_path=None, args=[]):
     dir_path = Path(temp_dir.name)
+    bam_path = bam_path or bam_file_sorted_path.name
 
     # Mosdepth won't work without index and won't accept absolute paths. Copy BAM file and index file into mosdepth temp directory.
-    shutil.copy(bam_file_sorted_path.name, dir_path)
-    bam_file_name = bam_file_sorted_path.name.split("/")[-1]
+    shutil.copy(bam_path, dir_path)
+    bam_file_name = bam_path.split("/")[-1]
     bam_file_path = dir_path/bam_file_name
     index_file_path = dir_path/(bam_file_name+".bai")
-    subprocess.check_call(['samtools', 'index', bam_file_sorted_path.name, '-o', index_file_path.as_posix()])
+    subprocess.check_call(['samtools', 'index', bam_path, '-o', index_file_path.as_posix()])
     
     mosdepth_prefix = "testing_depth_pytest"
     mosdepth_suffix = ".per-base.bed.gz"
     mosdepth_file = (dir_path/(mosdepth_prefix+mosdepth_suffix)).as_posix()
 
-    subprocess.check_call(['mosdepth', '-x', mosdepth_prefix, bam_file_path.name], cwd=temp_dir.name)
+    subprocess.check_call(['mosdepth', '-x', *args, mosdepth_prefix, bam_file_path.name], cwd=temp_dir.name)
+    return mosdepth_file
+
+def compare_depth(mosdepth_file, gbam_args):
     bed_gz = NamedTemporaryFile()
-    subprocess.check_call([binary_path, gbam_file_sorted.name, '-d', '-o', bed_gz.name, '--index-file', gbam_file_sorted.name + '.gbai'])
-    
+    subprocess.check_call([binary_path, gbam_file_sorted.name, '-d', '-o', bed_gz.name, '--index-file', gbam_file_sorted.name + '.gbai', *gbam_args])
     with gzip.open(bed_gz, "rb") as gbam_res, gzip.open(mosdepth_file, "rb") as mosdepth_res:
-        # Read the decompressed data from both files
         decompressed_gbam_res = gbam_res.read()
-        decompressed_mosdepth_res= mosdepth_res.read()
+        assert(len(decompressed_gbam_res) > 0)
+        assert(decompressed_gbam_res == mosdepth_res.read())
+
+@with_depth
+def test_depth():    
+    temp_dir = TemporaryDirectory()
+    compare_depth(run_mosdepth(temp_dir), [])
+
+def test_depth_bigwig_unsorted_bed():
+    subprocess.check_call(["samtools", "index", bam_file_sorted_path.name])
+    chr, coords = first_reference_region(bam_file_sorted_path.name, 1_000_000).split(":")
+    end = int(coords.split("-")[1])
+    # Overlapping regions listed out of order.
+    bed = NamedTemporaryFile(mode="w", suffix=".bed")
+    bed.write(f"{chr}\t{end // 2}\t{end}\n{chr}\t0\t{end // 2 + 10}\n{chr}\t10\t20\n")
+    bed.flush()
+    bigwig = NamedTemporaryFile(suffix=".bw")
+    subprocess.check_call([binary_path, gbam_file_sorted.name, "-d", "-b", bed.name, "-o", bigwig.name, "--index-file", gbam_file_sorted.name + ".gbai"])
+
+    data = Path(bigwig.name).read_bytes()
+    magic, data_offset = struct.unpack_from("<I12xQ", data)
+    assert(magic == 0x888FFC26)
+    (section_count,) = struct.unpack_from("<Q", data, data_offset)
+    assert(section_count > 0)
+
+@with_depth
+def test_depth_mapq():
+    temp_dir = TemporaryDirectory()
+    compare_depth(run_mosdepth(temp_dir, args=["-Q", "30"]), ["--mapq", "30"])
 
-    assert(len(decompressed_gbam_res) > 0)
-    assert(decompressed_gbam_res == decompressed_mosdepth_res)
+@with_depth
+def test_depth_forward():
+    temp_dir = TemporaryDirectory()
+    # Mosdepth has no strand specific mode, so it gets forward reads only.
+    forward_bam = Path(temp_dir.name)/"forward.bam"
+    subprocess.check_call(["samtools", "view", "-b", "-F", "16", bam_file_sorted_path.name, "-o", forward_bam.as_posix()])
+    mosdepth_dir = TemporaryDirectory()
+    compare_depth(run_mosdepth(mosdepth_dir, forward_bam.as_posix()), ["--depth-mode", "forward"])
+
+@with_depth
+def test_depth_fragment():
+    temp_dir = TemporaryDirectory()
+    compare_depth(run_mosdepth(temp_dir, args=["--fragment-mode"]), ["--depth-mode", "fragment"])
+
+def gbam_depth_positions(gbam_path, args):
+    bed_gz = NamedTemporaryFile()
+    subprocess.check_call([binary_path, gbam_path, "-d", "-o", bed_gz.name, "--index-file", gbam_path + ".gbai", *args])
+    depth = {}
+    with gzip.open(bed_gz.name, "rt") as res:
+        for line in res:
+            chr, start, end, value = line.split("\t")
+            if int(value) > 0:
+                for pos in range(int(start), int(end)):
+                    depth[(chr, pos + 1)] = int(value)
+    return depth
+
+def samtools_depth_positions(bam_path, args):
+    depth = {}
+    for line in subprocess.check_output(["samtools", "depth", *args, bam_path]).decode().splitlines():
+        chr, pos, value = line.split("\t")
+        if int(value) > 0:
+            depth[(chr, int(pos))] = int(value)
+    return depth
+
+# samtools depth never counts splices and counts deletions only with -J.
+@pytest.mark.parametrize("gbam_args, samtools_args", [(["--skip-splices"], ["-J"]), (["--skip-deletions"], [])])
+def test_depth_skip(gbam_args, samtools_args):
+    # Reads which do not pass filter are counted differently, so both tools get only passing ones.
+    filtered_bam = NamedTemporaryFile(suffix=".bam")
+    subprocess.check_call(["samtools", "view", "-b", "-F", "1796", bam_file_sorted_path.name, "-o", filtered_bam.name])
+    filtered_gbam = NamedTemporaryFile()
+    subprocess.check_call([binary_path, filtered_bam.name, "-c", "-s", "-o", filtered_gbam.name, "--index-sort"])
+    depth = gbam_depth_positions(filtered_gbam.name, gbam_args)
+    assert(len(depth) > 0)
+    assert(depth == samtools_depth_positions(filtered_bam.name, samtools_args))
+
+def test_depth_fragment_skip_rejected():
+    res = subprocess.run([binary_path, gbam_file_sorted.name, "-d", "--depth-mode", "fragment", "--skip-splices", "--index-file", gbam_file_sorted.name + ".gbai"], capture_output=True)
+    assert(res.returncode != 0)
+    assert(b"--depth-mode fragment" in res.stderr)
 
 def generate_views_for_gbam_and_bam_files(gbam_input, gbam_index, bam_input):
     gbam_results = NamedTemporaryFile()
@@ -146,3 +226,246 @@ def generate_views_for_gbam_and_bam_files(gbam_input, gbam_index, bam_input):
 def test_view(request):
     gbam_results, samtools_results = generate_views_for_gbam_and_bam_files(gbam_file, None, bam_file_path)
     byte_file_comparison(samtools_results.name, gbam_results.name)
+
+def first_reference_region(bam_path, max_len):
+    header = subprocess.check_output(["samtools", "view", "-H", bam_path]).decode()
+    sq_line = next(line for line in header.splitlines() if line.startswith("@SQ"))
+    tags = dict(tag.split(":", 1) for tag in sq_line.split("\t")[1:])
+    return f"{tags['SN']}:1-{min(int(tags['LN']), max_len)}"
+
+@pytest.mark.parametrize("args", [[], ["--reverse-del"]])
+def test_mpileup(args):
+    subprocess.check_call(["samtools", "index", bam_file_sorted_path.name])
+    region = first_reference_region(bam_file_sorted_path.name, 1_000_000)
+    # GBAM mpileup does not implement BAQ and overlapping mates detection.
+    view_of_original = subprocess.check_output(["samtools", "mpileup", "-B", "-x", *args, "-r", region, bam_file_sorted_path.name], stderr=subprocess.DEVNULL)
+    view_of_result = subprocess.check_output([binary_path, "--mpileup", gbam_file_sorted.name, "-q", region, "--index-file", gbam_file_sorted.name + ".gbai", *args])
+
+    assert(view_of_original == view_of_result)
+
+def test_find_name():
+    gbam_with_filter = NamedTemporaryFile()
+    subprocess.check_call([binary_path, bam_file_path, "-c", "-o", gbam_with_filter.name, "--name-filter"])
+    # Take read name from the middle of the file.
+    names = subprocess.check_output([f"samtools view {bam_file_path} | cut -f 1"], shell=True).split()
+    name = names[len(names) // 2].decode()
+
+    expected = subprocess.check_output([f"samtools view {bam_file_path} | awk -F '\\t' '$1 == \"{name}\"'"], shell=True)
+    for gbam_input in [gbam_file.name, gbam_with_filter.name]:
+        result = subprocess.check_output([f"{binary_path} --find-name {name} {gbam_input} | samtools view"], shell=True)
+        assert(len(result) > 0)
+        assert(result == expected)
+
+@pytest.mark.parametrize("sort_order,header_tags", [("name", "SO:queryname"), ("collate", "SO:unsorted\tGO:query")])
+def test_name_sort(sort_order, header_tags):
+    gbam_name_sorted = NamedTemporaryFile()
+    subprocess.check_call([binary_path, bam_file_path, "-c", "-s", "-o", gbam_name_sorted.name, "--sort-order", sort_order])
+    header = subprocess.check_output([binary_path, "--header", gbam_name_sorted.name]).decode()
+    assert(header.startswith("@HD") and header_tags in header.splitlines()[0])
+
+    names = subprocess.check_output([f"{binary_path} -v {gbam_name_sorted.name} | samtools view | cut -f 1"], shell=True).split()
+    original_names = subprocess.check_output([f"samtools view {bam_file_path} | cut -f 1"], shell=True).split()
+    assert(sorted(names) == sorted(original_names))
+    # All alignments of a read are adjacent.
+    runs = [name for i, name in enumerate(names) if i == 0 or names[i - 1] != name]
+    assert(len(runs) == len(set(names)))
+
+def test_gbam_sort():
+    gbam_resorted = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "-s", gbam_file.name, "-o", gbam_resorted.name])
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_resorted, None, bam_file_sorted_path.name)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def test_materialize_index_sort():
+    gbam_materialized = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "-s", gbam_file_sorted.name, "--index-file", gbam_file_sorted.name + ".gbai", "-o", gbam_materialized.name])
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_materialized, None, bam_file_sorted_path.name)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def test_merge():
+    gbam_resorted = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "-s", gbam_file.name, "-o", gbam_resorted.name])
+    gbam_merged = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "--merge", gbam_resorted.name, gbam_resorted.name, "-o", gbam_merged.name])
+    bam_merged = NamedTemporaryFile(suffix=".bam")
+    subprocess.check_call(["samtools", "merge", "-f", bam_merged.name, bam_file_sorted_path.name, bam_file_sorted_path.name])
+
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_merged, None, bam_merged.name)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def bam_with_references(bam_path, refs):
+    """Writes reads of `refs` into new BAM file, whose dictionary has only these references."""
+    header = subprocess.check_output(["samtools", "view", "-H", bam_path]).decode().splitlines()
+    lines = [line for line in header if not line.startswith("@SQ") or line.split("\t")[1][3:] in refs]
+    for line in subprocess.check_output(["samtools", "view", bam_path]).decode().splitlines():
+        fields = line.split("\t")
+        if fields[2] not in refs:
+            continue
+        if fields[6] not in ("=", "*") and fields[6] not in refs:
+            fields[6], fields[7] = "*", "0"
+        lines.append("\t".join(fields))
+    sam = NamedTemporaryFile(suffix=".sam", mode="w")
+    sam.write("\n".join(lines) + "\n")
+    sam.flush()
+    bam = NamedTemporaryFile(suffix=".bam")
+    subprocess.check_call(["samtools", "view", "-b", "-o", bam.name, sam.name])
+    return bam
+
+def test_merge_different_dictionaries():
+    refs = []
+    for line in subprocess.check_output(["samtools", "view", bam_file_sorted_path.name]).decode().splitlines():
+        ref = line.split("\t")[2]
+        if ref != "*" and ref not in refs:
+            refs.append(ref)
+    if len(refs) < 2:
+        pytest.skip("Test file has reads on less than two references.")
+    # The second file has only the second reference, so its RefIDs are shifted.
+    bams = [bam_with_references(bam_file_sorted_path.name, refs[:2]), bam_with_references(bam_file_sorted_path.name, refs[1:2])]
+    gbams = [NamedTemporaryFile() for _ in bams]
+    for bam, gbam in zip(bams, gbams):
+        subprocess.check_call([binary_path, bam.name, "-c", "-s", "-o", gbam.name])
+    gbam_merged = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "--merge", *[gbam.name for gbam in gbams], "-o", gbam_merged.name])
+    bam_merged = NamedTemporaryFile(suffix=".bam")
+    subprocess.check_call(["samtools", "merge", "-f", bam_merged.name, *[bam.name for bam in bams]])
+
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_merged, None, bam_merged.name)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def test_cat():
+    gbam_concatenated = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "--cat", gbam_file.name, gbam_file.name, "-o", gbam_concatenated.name])
+    bam_concatenated = NamedTemporaryFile(suffix=".bam")
+    subprocess.check_call(["samtools", "cat", "-o", bam_concatenated.name, bam_file_path, bam_file_path])
+
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_concatenated, None, bam_concatenated.name)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def test_split():
+    with TemporaryDirectory() as out_dir:
+        subprocess.check_call([binary_path, "--split", "1000", gbam_file.name, "-o", f"{out_dir}/part"])
+        parts = sorted(Path(out_dir).glob("part.*.gbam"), key=lambda path: int(path.suffixes[0][1:]))
+        gbam_concatenated = NamedTemporaryFile()
+        subprocess.check_call([binary_path, "--cat", *parts, "-o", gbam_concatenated.name])
+        gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_concatenated, None, bam_file_path)
+        byte_file_comparison(samtools_res.name, gbam_res.name)
+
+    gbam_resorted = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "-s", gbam_file.name, "-o", gbam_resorted.name])
+    subprocess.check_call(["samtools", "index", bam_file_sorted_path.name])
+    with TemporaryDirectory() as out_dir:
+        subprocess.check_call([binary_path, "--split", "reference", gbam_resorted.name, "-o", f"{out_dir}/ref"])
+        for part in Path(out_dir).glob("ref.*.gbam"):
+            ref_name = part.name[len("ref."):-len(".gbam")]
+            region = "*" if ref_name == "unmapped" else ref_name
+            expected = subprocess.check_output([f"samtools view {bam_file_sorted_path.name} '{region}' | wc -l"], shell=True)
+            result = subprocess.check_output([f"{binary_path} -v {part} | samtools view | wc -l"], shell=True)
+            assert(result == expected)
+
+def test_subsample():
+    gbam_subsampled = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "--subsample", "0.25", gbam_file.name, "-o", gbam_subsampled.name])
+    bam_subsampled = NamedTemporaryFile(suffix=".bam")
+    subprocess.check_call(["samtools", "view", "-b", "-s", "0.25", "-o", bam_subsampled.name, bam_file_path])
+
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_subsampled, None, bam_subsampled.name)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def test_parquet_round_trip():
+    help_text = subprocess.check_output([binary_path, "--help"]).decode()
+    if "--to-parquet" not in help_text:
+        pytest.skip("gbam_binary is built without parquet feature")
+    parquet_file = NamedTemporaryFile(suffix=".parquet")
+    subprocess.check_call([binary_path, "--to-parquet", gbam_file.name, "-o", parquet_file.name])
+    gbam_imported = NamedTemporaryFile()
+    subprocess.check_call([binary_path, "--from-parquet", parquet_file.name, "-o", gbam_imported.name])
+
+    gbam_res, samtools_res = generate_views_for_gbam_and_bam_files(gbam_imported, None, bam_file_path)
+    byte_file_comparison(samtools_res.name, gbam_res.name)
+
+def import_python_bindings():
+    gbam_tools = pytest.importorskip("gbam_tools")
+    if not hasattr(gbam_tools, "Reader"):
+        pytest.skip("gbam_tools Python module is not built (maturin develop in gbam_tools)")
+    return gbam_tools
+
+def test_python_reader():
+    gbam_tools = import_python_bindings()
+    reader = gbam_tools.Reader(gbam_file.name, fields=["read_name", "pos", "cigar"])
+    names = subprocess.check_output([f"samtools view {bam_file_path} | cut -f 1"], shell=True).decode().split()
+    assert(len(reader) == len(names))
+    assert([rec.read_name for rec in reader.records(0, 100)] == names[:100])
+    assert(reader[-1].read_name == names[-1])
+    assert(reader[0].seq is None)
+
+    stats = json.loads(subprocess.check_output(["samtools", "flagstat", "-O", "json", str(bam_file_path)]))
+    for key, (passed, failed) in reader.flagstat().items():
+        assert(stats["QC-passed reads"][key] == passed)
+        assert(stats["QC-failed reads"][key] == failed)
+
+def test_python_columns():
+    gbam_tools = import_python_bindings()
+    np = pytest.importorskip("numpy")
+    reader = gbam_tools.Reader(gbam_file.name, fields=["read_name"])
+    view = [line.split("\t") for line in subprocess.check_output(["samtools", "view", str(bam_file_path)]).decode().splitlines()]
+    pos, flag, mapq, tlen = (reader.column(name) for name in ["pos", "flag", "mapq", "tlen"])
+    assert((pos.dtype, flag.dtype, mapq.dtype, tlen.dtype) == (np.int32, np.uint16, np.uint8, np.int32))
+    assert(pos.tolist() == [int(line[3]) - 1 for line in view])
+    assert(flag.tolist() == [int(line[1]) for line in view])
+    assert(mapq.tolist() == [int(line[4]) for line in view])
+    assert(tlen.tolist() == [int(line[8]) for line in view])
+    with pytest.raises(ValueError):
+        reader.column("seq")
+
+    # Columns of index sorted file are in sorted order.
+    sorted_reader = gbam_tools.Reader(gbam_file_sorted.name, fields=["pos"], index_file=gbam_file_sorted.name + ".gbai")
+    assert(sorted_reader.column("pos").tolist() == [rec.pos for rec in sorted_reader])
+
+def test_python_query():
+    gbam_tools = import_python_bindings()
+    subprocess.check_call(["samtools", "index", bam_file_sorted_path.name])
+    region = first_reference_region(bam_file_sorted_path.name, 1_000_000)
+    reader = gbam_tools.Reader(gbam_file_sorted.name, fields=["read_name"], index_file=gbam_file_sorted.name + ".gbai")
+    expected = subprocess.check_output(["samtools", "view", bam_file_sorted_path.name, region]).decode().splitlines()
+    assert(sorted(rec.read_name for rec in reader.query(region)) == sorted(line.split("\t")[0] for line in expected))
+    with pytest.raises(ValueError):
+        gbam_tools.Reader(gbam_file.name).query(region)
+
+@with_depth
+def test_python_depth():
+    gbam_tools = import_python_bindings()
+    temp_dir = TemporaryDirectory()
+    mosdepth_file = run_mosdepth(temp_dir)
+    region = first_reference_region(bam_file_sorted_path.name, 1_000_000)
+    chrom, interval = region.split(":")
+    end = int(interval.split("-")[1])
+    expected = []
+    with gzip.open(mosdepth_file, "rt") as mosdepth_res:
+        for line in mosdepth_res:
+            line_chr, start, line_end, depth = line.split()
+            if line_chr == chrom and int(start) < end:
+                expected.extend([int(depth)] * (min(int(line_end), end) - int(start)))
+
+    reader = gbam_tools.Reader(gbam_file_sorted.name, index_file=gbam_file_sorted.name + ".gbai")
+    assert(reader.depth(region) == expected)
+
+def test_c_api():
+    if shutil.which("cc") is None or not library_path.exists() or not hasattr(ctypes.CDLL(str(library_path)), "gbam_reader_open"):
+        pytest.skip("gbam_tools library is built without c-api feature")
+    crate_path = cur_file_path.parent/"gbam_tools"
+    example = NamedTemporaryFile()
+    subprocess.check_call(["cc", crate_path/"examples"/"c"/"gbam_view.c", "-I", crate_path/"include", "-L", library_path.parent, "-lgbam_tools", "-o", example.name])
+    env = dict(os.environ, LD_LIBRARY_PATH=str(library_path.parent))
+
+    def view(args):
+        return subprocess.check_output(args, env=env).decode().splitlines()
+
+    # Read name, flag, reference, position and CIGAR columns.
+    expected = ["\t".join(line.split("\t")[:4] + [line.split("\t")[5]]) for line in view(["samtools", "view", str(bam_file_path)])]
+    example.file.close()
+    assert(view([example.name, gbam_file.name]) == expected)
+
+    subprocess.check_call(["samtools", "index", bam_file_sorted_path.name])
+    region = first_reference_region(bam_file_sorted_path.name, 1_000_000)
+    expected = ["\t".join(line.split("\t")[:4] + [line.split("\t")[5]]) for line in view(["samtools", "view", bam_file_sorted_path.name, region])]
+    assert(sorted(view([example.name, gbam_file_sorted.name, region, gbam_file_sorted.name + ".gbai"])) == sorted(expected))